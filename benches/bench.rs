@@ -1,4 +1,6 @@
+// Indexing in the loops is what is being measured
 #[test]
+#[allow(clippy::needless_range_loop)]
 fn f() {
 	use std::time::Instant;
 
@@ -20,13 +22,14 @@ fn f() {
 	for i in 0..5000usize {
 		let _ = unsafe {
 			let x = v.as_ptr();
-			*x.offset(i as isize) as i32
+			*x.add(i) as i32
 		};
 	}
 	println!("get: {:.2?}", now.elapsed());
 }
 
 #[test]
+#[allow(clippy::needless_range_loop)]
 fn fd() {
 	use std::time::Instant;
 
@@ -307,3 +310,146 @@ fn fd() {
 	}
 	println!("get: {:.2?}", now.elapsed());
 }
+
+/// Times the dispatch loop, which must be run without tracing e.g. `cargo test --release --no-default-features --features repl,regex,gc --bench bench dispatch_loop -- --ignored --nocapture`
+#[test]
+#[ignore = "takes a long time unless built in release mode without tracing"]
+fn dispatch_loop() {
+	use std::time::Instant;
+
+	use interpreter::{interpret, Chunk, Runtime};
+
+	let iterations = 20_000_000;
+	let mut runtime = Runtime::new(Chunk::new());
+	let now = Instant::now();
+	assert!(interpret(&format!("{{ let i = 0; while i < {iterations} {{ i = i + 1; }} }}"), &mut runtime).is_ok());
+	println!("Loop of {iterations}: {:.2?}", now.elapsed());
+}
+
+/// Times a loop of float arithmetic, for comparing the value representations with and without the `nan_boxing` feature
+#[test]
+#[ignore = "takes a long time unless built in release mode without tracing"]
+fn arithmetic_loop() {
+	use std::time::Instant;
+
+	use interpreter::{interpret, Chunk, Runtime};
+
+	let iterations = 10_000_000;
+	let mut runtime = Runtime::new(Chunk::new());
+	let now = Instant::now();
+	let source = format!("{{ let i = 0; let x = 0.5; while i < {iterations} {{ x = x * 0.999 + 0.25 - x / 8; i = i + 1; }} }}");
+	assert!(interpret(&source, &mut runtime).is_ok());
+	println!("Arithmetic loop of {iterations}: {:.2?}", now.elapsed());
+}
+
+/// Times a loop of 10M iterations with a global counter, where the control flow of the loop is a large part of each iteration
+#[test]
+#[ignore = "takes a long time unless built in release mode without tracing"]
+fn while_loop() {
+	use std::time::Instant;
+
+	use interpreter::{interpret, Chunk, Runtime};
+
+	let iterations = 10_000_000;
+	let mut runtime = Runtime::new(Chunk::new());
+	let now = Instant::now();
+	assert!(interpret(&format!("let i = 0; while i < {iterations} {{ i = i + 1; }}"), &mut runtime).is_ok());
+	println!("While loop of {iterations}: {:.2?}", now.elapsed());
+}
+
+/// Counts the allocations made by the current thread, so a benchmark can check the interpreter does not allocate in a loop
+struct CountingAllocator;
+
+thread_local! {
+	static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+	unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+		ALLOCATIONS.with(|count| count.set(count.get() + 1));
+		unsafe { std::alloc::System.alloc(layout) }
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+		unsafe { std::alloc::System.dealloc(ptr, layout) }
+	}
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Times 10M assignments to a global, checking that accessing globals does not allocate by comparing against a single assignment
+#[test]
+#[ignore = "takes a long time unless built in release mode without tracing"]
+fn global_assignment() {
+	use std::time::Instant;
+
+	use interpreter::{interpret, Chunk, Runtime};
+
+	let allocations = |iterations: usize| {
+		let mut runtime = Runtime::new(Chunk::new());
+		let before = ALLOCATIONS.with(|count| count.get());
+		let now = Instant::now();
+		assert!(interpret(&format!("let x = 0; {{ let i = 0; while i < {iterations} {{ x = x + 1; i = i + 1; }} }}"), &mut runtime).is_ok());
+		let elapsed = now.elapsed();
+		(ALLOCATIONS.with(|count| count.get()) - before, elapsed)
+	};
+	let iterations = 10_000_000;
+	let (baseline, _) = allocations(1);
+	let (allocated, elapsed) = allocations(iterations);
+	println!("Global assignments of {iterations}: {elapsed:.2?}, {allocated} allocations");
+	assert_eq!(allocated, baseline);
+}
+
+/// Times interning 100k distinct strings and then 100k duplicates of them, which finds each one in the runtime's table of strings
+#[test]
+#[ignore = "takes a long time unless built in release mode without tracing"]
+fn string_interning() {
+	use std::time::Instant;
+
+	use interpreter::{Chunk, Runtime};
+
+	let count = 100_000;
+	let mut runtime = Runtime::new(Chunk::new());
+	let now = Instant::now();
+	let distinct = (0..count).map(|index| runtime.new_string(format!("string {index}"))).collect::<Vec<_>>();
+	println!("Interning {count} distinct strings: {:.2?}", now.elapsed());
+
+	let now = Instant::now();
+	for (index, string) in distinct.iter().enumerate() {
+		assert_eq!(runtime.new_string(format!("string {index}")), *string);
+	}
+	println!("Interning {count} duplicates: {:.2?}", now.elapsed());
+}
+
+/// Times a loop of 1M string concatenations, for comparing against the numeric loops
+#[test]
+#[ignore = "takes a long time unless built in release mode without tracing"]
+fn string_building() {
+	use std::time::Instant;
+
+	use interpreter::{interpret, Chunk, Runtime};
+
+	let iterations = 1_000_000;
+	let mut runtime = Runtime::new(Chunk::new());
+	let now = Instant::now();
+	let source = format!(r#"{{ let i = 0; let s = "ab"; while i < {iterations} {{ let t = s + "c" + s; i = i + 1; }} }}"#);
+	assert!(interpret(&source, &mut runtime).is_ok());
+	println!("String building loop of {iterations}: {:.2?}", now.elapsed());
+}
+
+/// Times a loop of 10M negations and nots of locals, which replace the value on top of the stack
+#[test]
+#[ignore = "takes a long time unless built in release mode without tracing"]
+fn unary_loop() {
+	use std::time::Instant;
+
+	use interpreter::{interpret, Chunk, Runtime};
+
+	let iterations = 10_000_000;
+	let mut runtime = Runtime::new(Chunk::new());
+	let now = Instant::now();
+	let source = format!("{{ let i = 0; let x = 1.5; let b = true; while i < {iterations} {{ x = -x; b = !b; i = i + 1; }} }}");
+	assert!(interpret(&source, &mut runtime).is_ok());
+	println!("Unary loop of {iterations}: {:.2?}", now.elapsed());
+}
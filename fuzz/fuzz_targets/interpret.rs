@@ -0,0 +1,13 @@
+#![no_main]
+
+use std::time::Duration;
+
+use interpreter::{interpret, Chunk, Runtime};
+use libfuzzer_sys::fuzz_target;
+
+// Run with `cargo fuzz run interpret`. Any panic or crash is a bug, since `interpret` must turn every problem into an error.
+fuzz_target!(|source: &str| {
+	// Loops and sleeps can legitimately run for as long as they like, which the fuzzer would report as a timeout, so they are cut short with a runtime error
+	let mut runtime = Runtime::new(Chunk::new()).with_output(std::io::sink()).with_instruction_limit(100_000).with_max_sleep(Duration::ZERO);
+	let _ = interpret(source, &mut runtime);
+});
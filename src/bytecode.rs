@@ -3,8 +3,8 @@ pub(crate) mod prelude {
 	pub use super::compiler::{scanner::*, *};
 	pub use super::heap::*;
 	pub use super::logger::init_logger;
-	pub use super::vm::Runtime;
-	pub use super::{chunk::*, errors::*, line::Line, opcode::*};
+	pub use super::vm::{arithmetic, natives::*, MemStats, Runtime};
+	pub use super::{chunk::*, errors::*, line::Line, opcode::*, value::*};
 }
 #[macro_use]
 mod chunk;
@@ -14,6 +14,7 @@ mod heap;
 mod line;
 mod logger;
 mod opcode;
+mod value;
 mod vm;
 use std::{
 	cell::{Ref, RefCell},
@@ -22,19 +23,29 @@ use std::{
 };
 
 use prelude::*;
+pub use prelude::{init_logger, Chunk, InterpretError, Runtime};
 
-pub fn interpret<'source>(source: &'source str, runtime: &mut Runtime) -> Result<(), InterpretError> {
+/// Compiles and runs the source in the runtime
+///
+/// The compiled chunk copies everything it needs from the source, so the source can be dropped afterwards while the runtime (and its globals) are kept.
+///
+/// Any source is accepted without panicking, with problems reported as a [`InterpretError::CompileError`] or a runtime error. This is checked by the fuzz target in `fuzz/`.
+pub fn interpret(source: &str, runtime: &mut Runtime) -> Result<(), InterpretError> {
+	interpret_file(source, "<repl>", runtime)
+}
+
+/// Interprets the source of the file at the specified path, which is what `__file__` evaluates to
+pub fn interpret_file(source: &str, file: &str, runtime: &mut Runtime) -> Result<(), InterpretError> {
 	trace!("Starting bytecode {source}");
 	let mut chunk = Chunk::new();
-	if !Parser::compile(source, &mut chunk) {
+	if !Parser::compile_file(source, file, &mut chunk) {
 		trace!("Compile error");
 		return Err(InterpretError::CompileError);
 	}
 	trace!("Starting runtime chunk {:?}", chunk);
-	runtime.reset(&chunk);
+	runtime.reset(chunk);
 	runtime.interpret()?;
 	trace!("Runtime ok");
-	runtime.chunk = &Chunk::EMPTY;
 
 	Ok(())
 }
@@ -57,12 +68,14 @@ fn read_line() -> String {
 }
 
 /// Starts the REPL - the read evaluate print loop - for interactive testing
-pub fn repl() {
+///
+/// `fs_access` allows the script to use file system natives such as `read_file`.
+pub fn repl(fs_access: bool) {
 	let mut editor = rustyline::Editor::<()>::new();
 	editor.add_history_entry(r#"print("hello" + " " + "world");"#);
 	editor.add_history_entry(r#"if false{print("hi");}print("world");"#);
-	let mut runtime = Runtime::new(&Chunk::EMPTY);
-	let mut lines = Vec::new();
+	// Redefining a global is how code is iterated on interactively
+	let mut runtime = Runtime::new(Chunk::new()).with_fs_access(fs_access).with_global_redefinition(true);
 	loop {
 		let command = match editor.readline("📡 ") {
 			Ok(line) => line,
@@ -76,16 +89,30 @@ pub fn repl() {
 			}
 		};
 		editor.add_history_entry(command.clone());
-		if command.is_empty() {
-			break;
+		if !repl_line(&command, &mut runtime) {
+			return;
 		}
-		lines.push(command);
-		let _ = interpret(unsafe { &*(lines.as_ptr().add(lines.len() - 1)) }, &mut runtime);
 	}
 }
 
+/// Runs a line entered into the REPL, returning false if the session should end
+fn repl_line(line: &str, runtime: &mut Runtime) -> bool {
+	// A blank line does nothing, so the user is prompted again
+	if line.trim().is_empty() {
+		return true;
+	}
+	// Exiting the REPL ends the session rather than killing the process mid-readline
+	if let Err(InterpretError::Exit(code)) = interpret(line, runtime) {
+		info!("Goodbye (exit code {code})");
+		return false;
+	}
+	true
+}
+
 /// Loads a file by path and runs it
-pub fn run_file(path: &str) {
+///
+/// `fs_access` allows the script to use file system natives such as `read_file`.
+pub fn run_file(path: &str, fs_access: bool) {
 	let file = match std::fs::read_to_string(path) {
 		Ok(file) => file,
 		Err(e) => {
@@ -93,10 +120,11 @@ pub fn run_file(path: &str) {
 			std::process::exit(74);
 		}
 	};
-	if let Err(e) = interpret(&file, &mut Runtime::new(&Chunk::EMPTY)) {
+	if let Err(e) = interpret_file(&file, path, &mut Runtime::new(Chunk::new()).with_fs_access(fs_access)) {
 		match e {
 			InterpretError::CompileError => std::process::exit(65),
 			InterpretError::InterpretError => std::process::exit(70),
+			InterpretError::Exit(code) => std::process::exit(code),
 		}
 	}
 }
@@ -118,7 +146,1003 @@ fn dyns() {
 	println!("{:?}", y.0);
 }
 
+#[test]
+fn exit() {
+	init_logger();
+	assert!(matches!(interpret("exit(3); print(1);", &mut Runtime::new(Chunk::new())), Err(InterpretError::Exit(3))));
+	assert!(matches!(interpret("let a = 1; exit();", &mut Runtime::new(Chunk::new())), Err(InterpretError::Exit(0))));
+	assert!(matches!(interpret("exit(true);", &mut Runtime::new(Chunk::new())), Err(InterpretError::InterpretError)));
+}
+
+#[test]
+fn panic() {
+	init_logger();
+	assert!(matches!(
+		interpret(r#"error("bad state"); exit(0);"#, &mut Runtime::new(Chunk::new())),
+		Err(InterpretError::InterpretError)
+	));
+	assert!(matches!(interpret("panic(5); exit(0);", &mut Runtime::new(Chunk::new())), Err(InterpretError::InterpretError)));
+	assert!(matches!(interpret("if false { panic(null); } exit(0);", &mut Runtime::new(Chunk::new())), Err(InterpretError::Exit(0))));
+}
+
+#[test]
+fn contains() {
+	init_logger();
+	let mut chunk = Chunk::new();
+	assert!(Parser::compile(r#""ell" in "hello";"#, &mut chunk));
+	assert_eq!(
+		chunk.code,
+		[
+			Opcode::Constant.into(),
+			0,
+			Opcode::Constant.into(),
+			1,
+			Opcode::Contains.into(),
+			Opcode::Pop.into(),
+			Opcode::Return.into()
+		]
+	);
+}
+
+#[test]
+fn labeled_break() {
+	use Opcode::*;
+	init_logger();
+	let mut chunk = Chunk::new();
+	assert!(Parser::compile("'outer: while true { let a = 1; while true { let b = 2; break 'outer; } }", &mut chunk));
+	// The loops always run, so they have no conditions to check
+	#[rustfmt::skip]
+	let expected: [u8; 19] = [
+		LoadOne.into(),
+		LoadSmallInt.into(), 2,
+		// Both locals are popped before jumping past the end of the outer loop
+		Pop.into(), Pop.into(), LongJump.into(), 0, 0, 0, 8,
+		// The jumps back to the start of each loop have negative offsets
+		Pop.into(), Jump.into(), 255, 243,
+		Pop.into(), Jump.into(), 255, 238,
+		Return.into(),
+	];
+	assert_eq!(chunk.code, expected);
+
+	let mut chunk = Chunk::new();
+	assert!(Parser::compile("while true { let a = 1; continue; }", &mut chunk));
+
+	assert!(!Parser::compile("'outer: while true { break 'inner; }", &mut Chunk::new()));
+	assert!(!Parser::compile("break;", &mut Chunk::new()));
+	assert!(!Parser::compile("{ continue; }", &mut Chunk::new()));
+}
+
+#[test]
+fn loop_inversion() {
+	use Opcode::*;
+	init_logger();
+	let mut chunk = Chunk::new();
+	assert!(Parser::compile("let i = 0; while i < 10 { i = i + 1; }", &mut chunk));
+	// Each iteration runs the body and then the condition, ending in a single jump back (9 instructions rather than 11)
+	#[rustfmt::skip]
+	let expected: [u8; 24] = [
+		LoadZero.into(), DefineGlobalVariable.into(), 0,
+		LongJump.into(), 0, 0, 0, 7,
+		GetGlobalVariable.into(), 0, LoadOne.into(), Add.into(), SetGlobal.into(), 0, Pop.into(),
+		GetGlobalVariable.into(), 0, LoadSmallInt.into(), 10, Less.into(), PopJumpIfTrue.into(), 255, 241,
+		Return.into(),
+	];
+	assert_eq!(chunk.code, expected);
+
+	let mut runtime = Runtime::new(Chunk::new());
+	// `continue` jumps forward to the condition, including that of an outer loop, popping the locals of the loops it leaves
+	let source = "let i = 0; let s = 0; while i < 10 { i = i + 1; if i % 2 == 0 { continue; } s = s + i; } print(s);
+		let n = 0; 'outer: while n < 3 { n = n + 1; let j = 0; while true { let k = j; j = j + 1; if k > 2 { continue 'outer; } } } print(n);";
+	assert_eq!(run_in(&mut runtime, source), (Ok(()), "25\n3\n".into()));
+	assert_eq!(runtime.mem_stats().stack, 0);
+	// A break in the condition of an inner loop exits the outer loop, even though the condition is moved after the body
+	let source = "let k = 0; while k < 5 { k = k + 1; while if k == 3 { break; true } else { false } { print(k); } } print(k);";
+	assert_eq!(run_in(&mut runtime, source), (Ok(()), "3\n".into()));
+
+	// The condition is checked before the first iteration and after each one
+	assert_eq!(run_in(&mut runtime, "while 1 { print(1); }"), (Err(InterpretError::InterpretError), " [line 1:7] in script\n".into()));
+	assert_eq!(runtime.last_error(), Some("Condition must be a bool, got number"));
+	assert_eq!(
+		run_in(&mut runtime, "let y = 0; while y < 2 or y { y = y + 1; }"),
+		(Err(InterpretError::InterpretError), " [line 1:27] in script\n".into())
+	);
+	assert_eq!(runtime.last_error(), Some("Condition must be a bool, got number"));
+}
+
+#[test]
+fn long_jumps() {
+	use Opcode::*;
+	init_logger();
+	// Each statement is 7 bytes, so the branches are over 64KB
+	let statements = "x = x + 1;\n".repeat(10000);
+	let source = format!(
+		"let x = 0; let c = true; if c {{ {statements} }} else {{ x = x - 1; }} print(x);
+		let i = 0; while i < 2 {{ {statements} i = i + 1; }} print(x); print(c and x);"
+	);
+	assert_eq!(run(&source), (Ok(()), "10000\n30000\n30000\n".into()));
+
+	// The jump back of a loop only uses the long form when it has to
+	let mut chunk = Chunk::new();
+	assert!(Parser::compile(&format!("let x = 0; let c = true; while c {{ {statements} c = false; }}"), &mut chunk));
+	assert!(chunk.len() > u16::MAX as usize);
+	assert_eq!(chunk.code[chunk.len() - 6], LongPopJumpIfTrue.into());
+	let mut chunk = Chunk::new();
+	assert!(Parser::compile("let c = true; while c { c = false; }", &mut chunk));
+	assert_eq!(chunk.code[chunk.len() - 4], PopJumpIfTrue.into());
+}
+
+#[test]
+fn loop_with_forward_jumps() {
+	use Opcode::*;
+	init_logger();
+	// The body has forward jumps for the ifs, `continue` and `break`, while the loop itself ends in a backward jump
+	let source = "let i = 0; let s = 0; while i < 10 { i = i + 1; if i % 2 == 0 { continue; } if i > 7 { break; } s = s + i; } print(s);";
+	let mut chunk = Chunk::new();
+	assert!(Parser::compile(source, &mut chunk));
+	let jump = chunk.code.iter().rposition(|&byte| byte == PopJumpIfTrue.into()).unwrap();
+	let offset = i16::from_be_bytes([chunk.code[jump + 1], chunk.code[jump + 2]]);
+	assert!(offset < 0);
+	// It lands on the start of the body, `i = i + 1`
+	let target = (jump + 3).checked_add_signed(offset as isize).unwrap();
+	assert_eq!(chunk.code[target..target + 4], [GetGlobalVariable.into(), 0, LoadOne.into(), Add.into()]);
+
+	let mut runtime = Runtime::new(Chunk::new());
+	assert_eq!(run_in(&mut runtime, source), (Ok(()), "16\n".into()));
+	assert_eq!(runtime.mem_stats().stack, 0);
+}
+
+#[test]
+fn if_expression() {
+	use Opcode::*;
+	init_logger();
+	assert!(matches!(
+		interpret("let x = if false { 1 } else { 2 }; exit(x);", &mut Runtime::new(Chunk::new())),
+		Err(InterpretError::Exit(2))
+	));
+	assert!(matches!(
+		interpret("let x = if true { print(1); 3 } else if false { 4 } else { 5 }; exit(x);", &mut Runtime::new(Chunk::new())),
+		Err(InterpretError::Exit(3))
+	));
+
+	let mut chunk = Chunk::new();
+	assert!(Parser::compile("let c = true; let y = 10 * if c { let a = 2; a } else { 3 };", &mut chunk));
+	#[rustfmt::skip]
+	let expected: [u8; 31] = [
+		True.into(), DefineGlobalVariable.into(), 0,
+		LoadSmallInt.into(), 10,
+		GetGlobalVariable.into(), 0, LongJumpIfFalse.into(), 0, 0, 0, 13, Pop.into(),
+		// The local `a` is above the `10` on the stack, and the block's result replaces it
+		LoadSmallInt.into(), 2, GetLocal.into(), 1, SetLocal.into(), 1, Pop.into(),
+		LongJump.into(), 0, 0, 0, 3, Pop.into(),
+		LoadSmallInt.into(), 3,
+		Multiply.into(), DefineGlobalVariable.into(), 1,
+	];
+	assert_eq!(chunk.code[..31], expected);
+
+	assert!(!Parser::compile("let x = if true { 1 };", &mut Chunk::new()));
+	assert!(Parser::compile("if true { print(1); }", &mut Chunk::new()));
+
+	// An if that ends a branch is that branch's value, and one without an else elsewhere in a block is null
+	let source = "let c = true; let d = false; let a = if c { if d { 1 } else { 2 } } else { 3 }; print(a);
+		print(if d { 1 } else { if c { print(4); if d { 5 } 6 } else if d { 7 } else { 8 } });
+		print(if c { let e = 9; if d { e } else if c { if c { e + 1 } else { e } } else { 0 } } else { 0 });
+		print(if false { 1 } else { if true { 2 } else { 3 } }); print(if c { if d { 1 } } else { 0 });";
+	assert_eq!(run(source), (Ok(()), "2\n4\n6\n10\n2\nnull\n".into()));
+	assert!(!Parser::compile("let x = if true { 1 } else if false { 2 };", &mut Chunk::new()));
+}
+
+#[test]
+fn dead_branches() {
+	use Opcode::*;
+	init_logger();
+	let compile = |source: &str| {
+		let mut chunk = Chunk::new();
+		assert!(Parser::compile(source, &mut chunk), "{source}");
+		chunk
+	};
+	// Only the branch that runs is emitted, without the condition or any jumps
+	let chunk = compile("if true { print(1); } else { print(2); }");
+	assert_eq!((chunk.code.as_slice(), chunk.constant_count()), ([LoadOne.into(), Print.into(), Return.into()].as_slice(), 0));
+	assert_eq!(compile("if 1 > 2 { print(1); }").code, [Return.into()]);
+	assert_eq!(compile("while false { print(1); }").code, [Return.into()]);
+	let chunk = compile("let x = if false { 1 } else if true { 2 } else { 3 };");
+	assert_eq!(chunk.code, [LoadSmallInt.into(), 2, DefineGlobalVariable.into(), 0, Return.into()]);
+
+	// Locals declared in a removed branch don't take up slots
+	let chunk = compile("if false { let a = 1; let b = 2; } { let c = 3; print(c); }");
+	assert_eq!(chunk.code, [LoadSmallInt.into(), 3, GetLocal.into(), 0, Print.into(), Pop.into(), Return.into()]);
+	// Removed branches are still checked for errors
+	assert!(!Parser::compile("if false { let; }", &mut Chunk::new()));
+	assert!(!Parser::compile("while false { break 'missing; }", &mut Chunk::new()));
+	// As are structs declared in them, along with the constants for their fields
+	let chunk = compile("if false { struct S { a } } while false { struct T { b } } struct U { c }");
+	assert_eq!((chunk.structs.len(), chunk.constant_count()), (1, 1));
+	assert!(!Parser::compile("if false { struct S { a } } let s = S { a: 1 };", &mut Chunk::new()));
+
+	let source = "let i = 0; while i < 3 { i = i + 1; if false { break; } } while true { if !false { break; } } print(i);";
+	assert_eq!(run(source), (Ok(()), "3\n".into()));
+}
+
+#[test]
+fn is_type() {
+	init_logger();
+	assert!(matches!(interpret(r#"if "a" is String { exit(1); }"#, &mut Runtime::new(Chunk::new())), Err(InterpretError::Exit(1))));
+	assert!(matches!(interpret("if null is Null { exit(1); }", &mut Runtime::new(Chunk::new())), Err(InterpretError::Exit(1))));
+	assert!(matches!(interpret("if 5 is Bool { exit(1); }", &mut Runtime::new(Chunk::new())), Ok(())));
+	assert!(matches!(interpret("if true is Number { exit(1); }", &mut Runtime::new(Chunk::new())), Ok(())));
+	assert!(matches!(interpret("let x = 4 is 5;", &mut Runtime::new(Chunk::new())), Err(InterpretError::CompileError)));
+
+	// Checking against a name that is not a type is a runtime error naming it
+	let mut runtime = Runtime::new(Chunk::new());
+	assert!(matches!(interpret("let x = 4 is Point;", &mut runtime), Err(InterpretError::InterpretError)));
+	assert_eq!(runtime.last_error(), Some("Cannot check if 4 is Point, which is not a type"));
+
+	// Instances are only of their own struct
+	let source = "struct P { x } struct Q { x } let p = P { x: 1 }; print(p is P); print(p is Q); print(1 is P); print(p is Number);";
+	assert_eq!(run(source), (Ok(()), "true\nfalse\nfalse\nfalse\n".into()));
+}
+
+/// A writer that can be shared with a [Runtime] to capture the output in tests
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+#[cfg(test)]
+impl std::io::Write for SharedOutput {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		self.0.borrow_mut().write(buf)
+	}
+	fn flush(&mut self) -> std::io::Result<()> {
+		Ok(())
+	}
+}
+
+/// Interprets the source in a new runtime, returning the result along with what it printed
+#[cfg(test)]
+fn run(source: &str) -> (Result<(), InterpretError>, String) {
+	run_in(&mut Runtime::new(Chunk::new()), source)
+}
+
+/// Interprets the source in the runtime, returning the result along with what it printed
+#[cfg(test)]
+fn run_in(runtime: &mut Runtime, source: &str) -> (Result<(), InterpretError>, String) {
+	let output = SharedOutput::default();
+	runtime.set_output(output.clone());
+	let result = interpret(source, runtime);
+	(result, String::from_utf8(output.0.take()).unwrap())
+}
+
+#[test]
+fn print_output() {
+	init_logger();
+	assert_eq!(run(r#"prints("a"); prints(1); print(true); print("b"); prints(null);"#), (Ok(()), "a1true\nb\nnull".into()));
+}
+
 #[test]
 fn div_zero() {
 	println!("{}", 4. / 0.)
 }
+
+#[test]
+fn eval_reentrant() {
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new());
+	// The outer chunk continues after the nested interpret returns
+	assert_eq!(run_in(&mut runtime, r#"let x = eval("print(2); 3"); print(x); print(eval("x"));"#), (Ok(()), "2\n3\n3\n".into()));
+	assert_eq!(run_in(&mut runtime, r#"let s = "4"; print(eval("eval(s)"));"#), (Ok(()), "4\n".into()));
+	assert!(matches!(interpret(r#"eval("let;");"#, &mut runtime), Err(InterpretError::InterpretError)));
+
+	// The locals of the nested chunk are counted from where it starts on the stack, so they don't alias those of the caller
+	let source = r#"{ let y = 5; eval("{ let x = 1; print(x); x = 2; print(x); }"); print(y); let z = eval("{ let w = 3; } 4"); print(z); }"#;
+	assert_eq!(run_in(&mut runtime, source), (Ok(()), "1\n2\n5\n4\n".into()));
+	assert_eq!(runtime.mem_stats().stack, 0);
+
+	// Exiting inside eval exits the script that called it, however deeply nested
+	assert_eq!(run_in(&mut runtime, r#"print(1); eval("exit(3);"); print(2);"#), (Err(InterpretError::Exit(3)), "1\n".into()));
+	assert_eq!(
+		run_in(&mut runtime, r#"let inner = "exit(4);"; eval("eval(inner); print(5);"); print(6);"#),
+		(Err(InterpretError::Exit(4)), String::new())
+	);
+	// The exit doesn't linger to end a later script
+	assert_eq!(run_in(&mut runtime, r#"print(eval("7"));"#), (Ok(()), "7\n".into()));
+}
+
+#[test]
+fn json_values() {
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new());
+	// The parsed strings and arrays stay rooted while the rest are allocated, even in stress mode
+	let source = r#"let a = json_parse(json_stringify([1, ["x", "y"], [[]], null])); print(a); print(json_stringify(a));
+		struct P { name, tags } print(json_stringify([P { name: "p", tags: a }, true]));"#;
+	assert_eq!(
+		run_in(&mut runtime, source),
+		(
+			Ok(()),
+			"[1, [x, y], [[]], null]\n[1,[\"x\",\"y\"],[[]],null]\n[{\"name\":\"p\",\"tags\":[1,[\"x\",\"y\"],[[]],null]},true]\n".into()
+		)
+	);
+
+	assert!(matches!(interpret(r#"print(json_parse("{}"));"#, &mut runtime), Err(InterpretError::InterpretError)));
+	assert_eq!(runtime.last_error(), Some("Invalid JSON at byte 0: Objects are not supported until the language has maps"));
+}
+
+#[test]
+fn debug_expression() {
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new());
+	assert_eq!(run_in(&mut runtime, "let x = 4;\nlet y = debug( -x );\nprint(y);"), (Ok(()), "[line 2] -x = -4\n-4\n".into()));
+	assert_eq!(run_in(&mut runtime, "let z = 4; debug(-debug(z));"), (Ok(()), "[line 1] z = 4\n[line 1] -debug(z) = -4\n".into()));
+	assert!(matches!(interpret("debug(1;", &mut runtime), Err(InterpretError::CompileError)));
+}
+
+#[test]
+fn source_location() {
+	init_logger();
+	let output = SharedOutput::default();
+	let mut runtime = Runtime::new(Chunk::new()).with_output(output.clone());
+	assert!(matches!(interpret_file("print(__file__);\n\nprint(__line__);", "scripts/test.lox", &mut runtime), Ok(())));
+	assert_eq!(String::from_utf8(output.0.take()).unwrap(), "scripts/test.lox\n3\n");
+	assert_eq!(run_in(&mut runtime, "print(__file__); print(__line__);"), (Ok(()), "<repl>\n1\n".into()));
+	// Other identifiers can contain underscores
+	assert_eq!(run_in(&mut runtime, "let __lines = 2; print(__lines);"), (Ok(()), "2\n".into()));
+}
+
+#[test]
+fn math_constants() {
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new());
+	assert_eq!(
+		run_in(&mut runtime, "print(PI); print(E); print(INF); print(-INF); print(NAN);"),
+		(Ok(()), "3.141592653589793\n2.718281828459045\ninf\n-inf\nNaN\n".into())
+	);
+	assert_eq!(run_in(&mut runtime, "print(NAN == NAN); print(NAN == 1);"), (Ok(()), "false\nfalse\n".into()));
+	// Constants can be shadowed by globals
+	assert_eq!(run_in(&mut runtime, "let PI = 3; print(PI); print(E);"), (Ok(()), "3\n2.718281828459045\n".into()));
+}
+
+#[test]
+fn structs() {
+	use Opcode::*;
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new());
+	let source = "struct Wrapper { value } let w = Wrapper { value: chr(65) }; print(w.value); print(w); Wrapper { value: chr(66) }; print(gc()); print(w);";
+	// The string in the field of the global is kept alive, but the unused struct and its string are freed
+	let freed = if cfg!(feature = "gc") { 2 } else { 0 };
+	assert_eq!(run_in(&mut runtime, source), (Ok(()), format!("A\nWrapper {{ value: A }}\n{freed}\nWrapper {{ value: A }}\n")));
+	assert!(matches!(
+		interpret("struct Wrapper { value } print(Wrapper { value: 1 }.other);", &mut Runtime::new(Chunk::new())),
+		Err(InterpretError::InterpretError)
+	));
+	assert!(matches!(interpret("let x = 1; print(x.value);", &mut Runtime::new(Chunk::new())), Err(InterpretError::InterpretError)));
+
+	// Field names are interned, so properties are found from other chunks, and instances keep them alive after the chunk that declared the struct is dropped
+	let source = r#"let p = eval("struct Pair { left, right } Pair { left: 1, right: 2 }"); gc(); print(p.right); print(eval("p.left")); print(p);"#;
+	assert_eq!(run_in(&mut runtime, source), (Ok(()), "2\n1\nPair { left: 1, right: 2 }\n".into()));
+
+	// Fields can be written in any order, with the offsets as operands. The field names are the first constants, so the property name `x` reuses one, as does the second use of `p`
+	let mut chunk = Chunk::new();
+	assert!(Parser::compile("struct Point { x, y } let p = Point { y: 1, x: 2 }; p.x = 3;", &mut chunk));
+	#[rustfmt::skip]
+	let expected: [u8; 17] = [
+		LoadOne.into(), LoadSmallInt.into(), 2,
+		MakeStruct.into(), 0, 1, 0,
+		DefineGlobalVariable.into(), 2,
+		GetGlobalVariable.into(), 2, LoadSmallInt.into(), 3, SetProperty.into(), 0, Pop.into(),
+		Return.into(),
+	];
+	assert_eq!(chunk.code, expected);
+
+	assert!(!Parser::compile("struct Point { x, y } let p = Point { x: 1 };", &mut Chunk::new()));
+	assert!(!Parser::compile("struct Point { x, y } let p = Point { x: 1, y: 2, z: 3 };", &mut Chunk::new()));
+	assert!(!Parser::compile("struct Point { x, y } let p = Point { x: 1, x: 2 };", &mut Chunk::new()));
+	assert!(!Parser::compile("struct Point { x, x }", &mut Chunk::new()));
+	assert!(!Parser::compile("struct Point { x } struct Point { y }", &mut Chunk::new()));
+}
+
+#[test]
+fn arrays() {
+	use Opcode::*;
+	init_logger();
+	let source = "let a = [1, chr(65), [true, null],]; print(a); print([]); print(a == a); print(a == [1]); print(a is Array); [chr(66)]; print(gc()); print(a);";
+	// The nested array and string are kept alive through the global, but the temporary arrays and the string in one are freed
+	// (in stress mode the earlier temporaries were already freed by the allocations that followed them)
+	let freed = if cfg!(feature = "gc_stress") {
+		2
+	} else if cfg!(feature = "gc") {
+		4
+	} else {
+		0
+	};
+	assert_eq!(run(source), (Ok(()), format!("[1, A, [true, null]]\n[]\ntrue\nfalse\ntrue\n{freed}\n[1, A, [true, null]]\n")));
+
+	let mut chunk = Chunk::new();
+	assert!(Parser::compile("[1, 2];", &mut chunk));
+	assert_eq!(chunk.code, [LoadOne.into(), LoadSmallInt.into(), 2, MakeArray.into(), 2, Pop.into(), Return.into()]);
+
+	assert!(!Parser::compile("let a = [1, 2;", &mut Chunk::new()));
+	assert!(!Parser::compile("let a = [,];", &mut Chunk::new()));
+	let elements = vec!["1"; 256].join(", ");
+	assert!(!Parser::compile(&format!("let a = [{elements}];"), &mut Chunk::new()));
+}
+
+#[test]
+fn temporaries_rooted() {
+	init_logger();
+	// Each allocation happens while the results of earlier ones are only held on the stack, which the `gc_stress` feature checks are kept alive
+	let source = r#"struct Pair { left, right }
+let p = Pair { right: [chr(65) + chr(66), to_fixed(1, 2)], left: format("{} {}", chr(67), [chr(68)]) };
+print(p); print(gc()); print("AB" + p.left);"#;
+	// The single character strings and the array passed to format are temporaries, which stress mode has already freed
+	let freed = if cfg!(feature = "gc") && !cfg!(feature = "gc_stress") { 5 } else { 0 };
+	assert_eq!(run(source), (Ok(()), format!("Pair {{ left: C [D], right: [AB, 1.00] }}\n{freed}\nABC [D]\n")));
+}
+
+#[test]
+fn constant_folding() {
+	use Opcode::*;
+	init_logger();
+	let compile = |source: &str| {
+		let mut chunk = Chunk::new();
+		assert!(Parser::compile(source, &mut chunk), "{source}");
+		chunk
+	};
+	// Only the global's name and the result are left in the constant pool
+	let chunk = compile("let seconds_per_day = 60 * 60 * 24;");
+	assert_eq!(chunk.code, [Constant.into(), 1, DefineGlobalVariable.into(), 0, Return.into()]);
+	assert_eq!((chunk.constant_count(), *chunk.constant(1)), (2, Value::int(86400)));
+	let chunk = compile(r#"print("a" + "b" + "c");"#);
+	assert_eq!(chunk.code, [Constant.into(), 0, Print.into(), Return.into()]);
+	assert_eq!((chunk.constant_count(), chunk.objects.len(), chunk.constant(0).to_string()), (1, 1, "abc".to_string()));
+	for (source, opcode) in [("1 < 2", True), ("2 >= 3", False), ("!(1 == 1.0)", False), ("null == null", True)] {
+		assert_eq!(compile(&format!("print({source});")).code, [opcode.into(), Print.into(), Return.into()], "{source}");
+	}
+	// Literals are folded up to the first operand that isn't one
+	let chunk = compile("let x = 1; print(-200 * 3 + x + 400);");
+	assert_eq!(
+		chunk.code[3..],
+		[Constant.into(), 1, GetGlobalVariable.into(), 0, Add.into(), Constant.into(), 2, Add.into(), Print.into(), Return.into()]
+	);
+	assert_eq!((chunk.constant_count(), *chunk.constant(1)), (3, Value::int(-600)));
+
+	// Operations that are runtime errors are left for the runtime to raise, as is division by zero which may be allowed
+	for (source, opcode) in [("1 + true", Add), ("-\"a\"", Negate), ("!1", Not), ("1 / 0", Divide), ("1 % 0.0", Modulo), ("1 < \"a\"", Less)] {
+		let chunk = compile(&format!("print({source});"));
+		assert_eq!(chunk.code[chunk.len() - 3], opcode.into(), "{source}");
+	}
+	let chunk = compile(&format!("print({} + 1);", Value::MAX_INT));
+	assert_eq!(chunk.code[3], Add.into());
+
+	// Folded expressions give the same value as the operations at runtime
+	let mut runtime = Runtime::new(Chunk::new()).with_global_redefinition(true);
+	let mut printed = |source: String| {
+		let (result, output) = run_in(&mut runtime, &source);
+		assert_eq!(result, Ok(()), "{source}");
+		output
+	};
+	for (left, operator, right) in [
+		("60", "*", "24"),
+		("7", "/", "2"),
+		("-7", "%", "3"),
+		("7.5", "%", "2"),
+		("1", "-", "2.5"),
+		("0.1", "+", "0.2"),
+		(r#""a""#, "+", r#""b""#),
+		("1", "==", "1.0"),
+		(r#""a""#, "==", r#""a""#),
+		("true", "==", "false"),
+		("2", ">=", "3"),
+		("3", "<=", "3"),
+		("1", "!=", "1.0"),
+		(r#""a""#, "!=", r#""b""#),
+		("3", ">", "2.5"),
+	] {
+		let folded = printed(format!("print({left} {operator} {right});"));
+		assert_eq!(folded, printed(format!("let l = {left}; let r = {right}; print(l {operator} r);")), "{left} {operator} {right}");
+	}
+	for (operator, operand) in [("-", "5"), ("-", "0.0"), ("-", "-1.5"), ("!", "true")] {
+		let folded = printed(format!("print({operator}{operand});"));
+		assert_eq!(folded, printed(format!("let v = {operand}; print({operator}v);")), "{operator}{operand}");
+	}
+}
+
+#[test]
+fn comparison_operators() {
+	use Opcode::*;
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new()).with_global_redefinition(true);
+	// Each comparison is a single instruction
+	for (operator, opcode) in [("<", Less), (">", Greater), ("<=", LessEqual), (">=", GreaterEqual), ("==", Equal), ("!=", NotEqual)] {
+		let mut chunk = Chunk::new();
+		assert!(Parser::compile(&format!("let a = 1; print(a {operator} a);"), &mut chunk));
+		assert_eq!(
+			chunk.code[3..],
+			[GetGlobalVariable.into(), 0, GetGlobalVariable.into(), 0, opcode.into(), Print.into(), Return.into()],
+			"{operator}"
+		);
+	}
+	for (left, right, expected) in [
+		("1", "2", "true false true false false true"),
+		("2", "2", "false false true true true false"),
+		("3", "2", "false true false true false true"),
+		("1.5", "2", "true false true false false true"),
+		("2.0", "2", "false false true true true false"),
+		("2", "1.5", "false true false true false true"),
+	] {
+		let source = format!("let l = {left}; let r = {right}; print(l < r); print(l > r); print(l <= r); print(l >= r); print(l == r); print(l != r);");
+		let (result, output) = run_in(&mut runtime, &source);
+		assert_eq!(result, Ok(()), "{source}");
+		assert_eq!(output.split_whitespace().collect::<Vec<_>>().join(" "), expected, "{left} {right}");
+	}
+}
+
+#[test]
+fn chained_comparison() {
+	init_logger();
+	assert!(!Parser::compile("let x = 5; if 1 < x < 10 { print(x); }", &mut Chunk::new()));
+	assert!(!Parser::compile("let x = 1 <= 2 >= 3;", &mut Chunk::new()));
+	assert!(!Parser::compile("let x = 1 < 2 == true;", &mut Chunk::new()));
+	assert!(!Parser::compile("let x = 1 == 2 == false;", &mut Chunk::new()));
+	assert!(!Parser::compile("let x = 1 != 2 != false;", &mut Chunk::new()));
+	assert!(!Parser::compile(r#"let x = "a" in "abc" == true;"#, &mut Chunk::new()));
+
+	assert!(Parser::compile("let x = 5; if 1 < x and x < 10 { print(x); }", &mut Chunk::new()));
+	assert!(Parser::compile("let x = (1 < 2) == true;", &mut Chunk::new()));
+	assert!(Parser::compile("let x = 1 < 2; let y = 2 < 3;", &mut Chunk::new()));
+	assert!(Parser::compile("let x = 1 + 2 < 3 * 4;", &mut Chunk::new()));
+	// A comparison in a removed branch doesn't make the next operator look chained
+	let source = "let x=1; let y=2; let a=1; let b=2; let c=3; if false { print(x < y); } print(a + b == c);";
+	assert_eq!(run(source), (Ok(()), "true\n".into()));
+	// Nor does one in a loop's condition, which is moved after the body
+	let source = "let x=0; let y=3; let z=1; let w=1; while x + 1 < y { print(z == w); x = x + 1; }";
+	assert_eq!(run(source), (Ok(()), "true\ntrue\n".into()));
+}
+
+#[test]
+fn logical_operator_values() {
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new());
+	// The result is the operand that decided it, and zero is truthy
+	let source = r#"print(null or 5); print(0 or 5); print(false or null); print("a" and "b"); print(null and "b"); print(0 and "b");"#;
+	assert_eq!(run_in(&mut runtime, source), (Ok(()), "5\n0\nnull\nb\nnull\nb\n".into()));
+	// The right operand is only evaluated if the left doesn't decide the result
+	let source = "print(1 or debug(2)); print(null and debug(3)); print(null or debug(4)); print(1 and debug(5));";
+	assert_eq!(run_in(&mut runtime, source), (Ok(()), "1\nnull\n[line 1] 4 = 4\n4\n[line 1] 5 = 5\n5\n".into()));
+
+	// Each `or` is a single jump over the right operand, which is skipped as soon as an operand is truthy
+	let mut chunk = Chunk::new();
+	assert!(Parser::compile("let a = null; print(a or a);", &mut chunk));
+	let (jump, get) = (Opcode::LongJumpIfTruthy.into(), Opcode::GetGlobalVariable.into());
+	assert_eq!(chunk.code[3..], [get, 0, jump, 0, 0, 0, 3, Opcode::Pop.into(), get, 0, Opcode::Print.into(), Opcode::Return.into()]);
+	assert_eq!(run_in(&mut runtime, "print(null or false or null or 7 or debug(8) or debug(9));"), (Ok(()), "7\n".into()));
+}
+
+#[test]
+fn int_literals() {
+	init_logger();
+	let mut chunk = Chunk::new();
+	// The largest int is past the precision of a float (unless ints are limited by `nan_boxing`), but is still exact
+	assert!(Parser::compile(&format!("{};", Value::MAX_INT), &mut chunk));
+	assert_eq!(chunk.constant(0).as_int(), Some(Value::MAX_INT));
+	assert!(!Parser::compile(&format!("let x = {}0;", Value::MAX_INT), &mut Chunk::new()));
+	assert!(!Parser::compile("let x = 10000000000000000000;", &mut Chunk::new()));
+	assert_eq!(parse_number("4_000"), Some(Value::int(4000)));
+	assert_eq!(parse_number("4.5").and_then(|number| number.as_number()), Some(4.5));
+
+	let source = format!("print(-{}); print(3 is Int); print(3 is Number); print(PI is Int);", Value::MAX_INT);
+	assert_eq!(run(&source), (Ok(()), format!("-{}\ntrue\ntrue\nfalse\n", Value::MAX_INT)));
+	assert!(matches!(interpret("exit(7);", &mut Runtime::new(Chunk::new())), Err(InterpretError::Exit(7))));
+
+	// Decimal literals are numbers, and a trailing dot is not part of the literal
+	assert_eq!(run("print(1.5 + 1); print(2.0 is Int);"), (Ok(()), "2.5\nfalse\n".into()));
+	assert!(!Parser::compile("print(3.);", &mut Chunk::new()));
+}
+
+#[test]
+fn deep_stack() {
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new());
+	// Each bracket keeps its left operand on the stack, so this needs a dozen slots
+	let source = "print(1 + (2 + (3 + (4 + (5 + (6 + (7 + (8 + (9 + (10 + (11 + 12)))))))))));";
+	assert_eq!(run_in(&mut runtime, source), (Ok(()), "78\n".into()));
+	let source = "{ let a = 1; let b = 2; let c = 3; let d = 4; let e = 5; let f = 6; let g = 7; print(a + b * (c + d * (e + f * g))); }";
+	assert_eq!(run_in(&mut runtime, source), (Ok(()), "383\n".into()));
+	assert_eq!(run_in(&mut runtime, "print(-7 % 3); print(mod(-7, 3));"), (Ok(()), "-1\n2\n".into()));
+}
+
+#[test]
+fn stack_overflow() {
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new()).with_max_stack(50);
+	// The operands are variables so that the additions aren't folded into a constant
+	let source = format!("let x = 1; print({}x{});", "x + (".repeat(100), ")".repeat(100));
+	assert_eq!(run_in(&mut runtime, &source), (Err(InterpretError::InterpretError), " [line 1:268] in script\n".into()));
+	// The runtime can still be used after the error
+	assert_eq!(run_in(&mut runtime, "print(1 + (2 + 3));"), (Ok(()), "6\n".into()));
+}
+
+#[test]
+fn instruction_limit() {
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new()).with_instruction_limit(1000);
+	assert_eq!(
+		run_in(&mut runtime, "print(1); while true { }"),
+		(Err(InterpretError::InterpretError), "1\n [line 1:24] in script\n".into())
+	);
+	assert_eq!(runtime.last_error(), Some("Instruction limit of 1000 reached"));
+	// Code run by `eval` counts towards the limit of the script that called it
+	assert_eq!(
+		run_in(&mut runtime, r#"let x = 0; while true { x = eval("x + 1"); }"#),
+		(Err(InterpretError::InterpretError), " [line 1:42] in script\n".into())
+	);
+	// Each interpret starts with the full limit
+	let source = "{ let i = 0; while i < 50 { i = i + 1; } print(i); }";
+	assert_eq!(run_in(&mut runtime, source), (Ok(()), "50\n".into()));
+	assert_eq!(run_in(&mut runtime, source), (Ok(()), "50\n".into()));
+}
+
+#[test]
+fn runtime_errors_abort() {
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new());
+	// Only the location of the error is written, not the output of the rest of the script
+	for (source, column) in [
+		("1 + \"x\"; print(\"unreachable\");", 5),
+		("-true; print(\"unreachable\");", 2),
+		("print(1 < \"x\"); print(\"unreachable\");", 11),
+		("if 5 { print(\"then\"); } print(\"unreachable\");", 4),
+		("print(undefined); print(\"unreachable\");", 7),
+	] {
+		let output = format!(" [line 1:{column}] in script\n");
+		assert_eq!(run_in(&mut runtime, source), (Err(InterpretError::InterpretError), output), "{source}");
+	}
+}
+
+#[test]
+fn type_error_messages() {
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new()).with_output(std::io::sink());
+	for (source, message) in [
+		("1 + \"x\";", "Cannot add number and string"),
+		("\"x\" + null;", "Cannot add string and null"),
+		("2.5 - true;", "Cannot subtract number and bool"),
+		("null * 2;", "Cannot multiply null and number"),
+		("[1] / 2;", "Cannot divide array and number"),
+		("1 % \"x\";", "Cannot take the remainder of number and string"),
+		("print(1 < \"x\");", "Cannot compare number and string"),
+		("print(\"a\" >= 1);", "Cannot compare string and number"),
+		("print(null <= 1.5);", "Cannot compare null and number"),
+		("-true;", "Operand to '-' must be a number, got bool"),
+		("!1;", "Operand to '!' must be a bool, got number"),
+		("if \"x\" { }", "Condition must be a bool, got string"),
+		("1 in 2;", "Cannot use 'in' on a number"),
+	] {
+		assert!(matches!(interpret(source, &mut runtime), Err(InterpretError::InterpretError)), "{source}");
+		assert_eq!(runtime.last_error(), Some(message), "{source}");
+	}
+	// Overflow is reported as such rather than as a type error
+	assert!(matches!(interpret(&format!("{} + 1;", Value::MAX_INT), &mut runtime), Err(InterpretError::InterpretError)));
+	assert_eq!(runtime.last_error(), Some(format!("Integer overflow in {} + 1", Value::MAX_INT).as_str()));
+}
+
+#[test]
+fn non_boolean_condition() {
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new());
+	assert_eq!(run_in(&mut runtime, "if 5 { print(\"no\"); }"), (Err(InterpretError::InterpretError), " [line 1:4] in script\n".into()));
+	assert_eq!(
+		run_in(&mut runtime, "while null { print(\"no\"); }"),
+		(Err(InterpretError::InterpretError), " [line 1:7] in script\n".into())
+	);
+	// The failed condition doesn't leave anything behind for the next script
+	assert_eq!(run_in(&mut runtime, "let x = 1; print(x);"), (Ok(()), "1\n".into()));
+}
+
+#[test]
+fn runtime_error_line() {
+	init_logger();
+	fn error_line(source: &str) -> u16 {
+		let mut chunk = Chunk::new();
+		assert!(Parser::compile(source, &mut chunk));
+		let mut runtime = Runtime::new(chunk);
+		assert!(matches!(runtime.interpret(), Err(InterpretError::InterpretError)));
+		runtime.current_line().line
+	}
+	assert_eq!(error_line("1 + true;"), 1);
+	// The line is of the faulting instruction rather than the next one, which starts after the operand
+	assert_eq!(error_line("print(\nmissing\n);"), 2);
+	// A while condition is checked after the body, but is still reported on its own line
+	assert_eq!(error_line("let x = 1;\nwhile x {\n\tprint(x);\n}"), 2);
+}
+
+#[test]
+fn division_by_zero() {
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new());
+	for (source, column) in [("print(1 / 0);", 11), ("print(0 / 0);", 11), ("print(1 % 0);", 11), ("print(mod(1, 0));", 15)] {
+		let output = format!(" [line 1:{column}] in script\n");
+		assert_eq!(run_in(&mut runtime, source), (Err(InterpretError::InterpretError), output), "{source}");
+	}
+
+	let mut runtime = Runtime::new(Chunk::new()).with_ieee_division(true);
+	assert_eq!(
+		run_in(&mut runtime, "print(1 / 0); print(0 / 0); print(1 % 0); print(-1 / 0);"),
+		(Ok(()), "inf\nNaN\nNaN\n-inf\n".into())
+	);
+}
+
+#[test]
+fn consecutive_interprets() {
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new());
+	assert_eq!(run_in(&mut runtime, "print(1 + 2);"), (Ok(()), "3\n".into()));
+	assert_eq!(
+		run_in(&mut runtime, "print(3 * 4);\nprint(-true);"),
+		(Err(InterpretError::InterpretError), "12\n [line 2:8] in script\n".into())
+	);
+	// The runtime keeps the chunk it last ran, so the error can still be located afterwards
+	assert_eq!(runtime.current_line().line, 2);
+	assert_eq!(run_in(&mut runtime, "print(5 - 6);"), (Ok(()), "-1\n".into()));
+}
+
+#[test]
+fn globals_outlive_chunk() {
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new());
+	assert!(matches!(interpret("let s = \"hello\";", &mut runtime), Ok(())));
+	assert_eq!(run_in(&mut runtime, "print(s);"), (Ok(()), "hello\n".into()));
+	// The string from the first chunk is still reachable from the global, so is not collected
+	runtime.collect_garbage();
+	assert_eq!(run_in(&mut runtime, "print(s);"), (Ok(()), "hello\n".into()));
+}
+
+#[test]
+fn repl_lines() {
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new());
+	// Each line is dropped once it has been interpreted, like in the REPL
+	for index in 0..100 {
+		let line = format!("let value_{index} = \"line {index}\";");
+		assert!(matches!(interpret(&line, &mut runtime), Ok(())));
+	}
+	for index in [0, 50, 99] {
+		assert_eq!(run_in(&mut runtime, &format!("print(value_{index});")), (Ok(()), format!("line {index}\n")));
+	}
+}
+
+#[test]
+fn string_interning() {
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new());
+	let source = "print(\"hi\" == \"hi\"); print(\"ab\" == \"a\" + \"b\"); print(\"a\" + \"b\" == \"ab\"); print(\"hi\" == \"ho\");";
+	assert_eq!(run_in(&mut runtime, source), (Ok(()), "true\ntrue\ntrue\nfalse\n".into()));
+	// Strings from earlier chunks and the names of globals are shared with later chunks
+	assert!(matches!(interpret("let greeting = \"hi\"; let joined = \"a\" + \"b\";", &mut runtime), Ok(())));
+	let source = "print(greeting == \"hi\"); print(joined == \"ab\"); print(id(greeting) == id(\"hi\"));";
+	assert_eq!(run_in(&mut runtime, source), (Ok(()), "true\ntrue\ntrue\n".into()));
+}
+
+#[test]
+fn small_ints() {
+	use Opcode::*;
+	init_logger();
+	// Only the global's name is in the constant pool
+	let mut chunk = Chunk::new();
+	assert!(Parser::compile("let i = 0;", &mut chunk));
+	assert_eq!(
+		(chunk.code.as_slice(), chunk.constant_count()),
+		([LoadZero.into(), DefineGlobalVariable.into(), 0, Return.into()].as_slice(), 1)
+	);
+	let mut chunk = Chunk::new();
+	assert!(Parser::compile("print(1); print(-1); print(127); print(128); print(1.0);", &mut chunk));
+	#[rustfmt::skip]
+	let expected = [
+		LoadOne.into(), Print.into(),
+		LoadSmallInt.into(), 255, Print.into(),
+		LoadSmallInt.into(), 127, Print.into(),
+		Constant.into(), 0, Print.into(),
+		Constant.into(), 1, Print.into(),
+		Return.into(),
+	];
+	assert_eq!((chunk.code.as_slice(), chunk.constant_count()), (expected.as_slice(), 2));
+
+	// The values are still ints, including the negative ones
+	let mut runtime = Runtime::new(Chunk::new());
+	let values = ["-129", "-128", "-5", "-1", "0", "1", "2", "127", "128"];
+	for value in values {
+		assert_eq!(run_in(&mut runtime, &format!("print({value}); print({value} is Int);")), (Ok(()), format!("{value}\ntrue\n")));
+	}
+	assert_eq!(run_in(&mut runtime, "let x = 0; print(x - 1 == -1); print(-128 - 1);"), (Ok(()), "true\n-129\n".into()));
+}
+
+#[test]
+fn string_concat() {
+	init_logger();
+	let opcodes = |source: &str| {
+		let mut chunk = Chunk::new();
+		assert!(Parser::compile(source, &mut chunk), "{source}");
+		let (concat, add) = (Opcode::Concat.into(), Opcode::Add.into());
+		(chunk.code.iter().filter(|&&byte| byte == concat).count(), chunk.code.iter().filter(|&&byte| byte == add).count())
+	};
+	// A string literal operand, or the result of another concat, makes `+` a concat
+	assert_eq!(opcodes("let x = 1; print(\"a\" + x);"), (1, 0));
+	assert_eq!(opcodes("let x = 1; print(x + \"a\");"), (1, 0));
+	assert_eq!(opcodes("let x = 1; print(x + \"a\" + x + x);"), (3, 0));
+	assert_eq!(opcodes("let x = 1; print(x + x + \"a\");"), (1, 1));
+	assert_eq!(opcodes("let x = 1; print(x + x);"), (0, 1));
+
+	// Either opcode gives the same result, so a concat that turns out to have numbers still adds them
+	let mut runtime = Runtime::new(Chunk::new());
+	let source = r#"let s = "b"; let n = 2; print("a" + s + "c"); print(s + s); print((n or "a" + s) + 1); print(n + n);"#;
+	assert_eq!(run_in(&mut runtime, source), (Ok(()), "abc\nbb\n3\n4\n".into()));
+	assert!(matches!(interpret("print(\"a\" + n);", &mut runtime), Err(InterpretError::InterpretError)));
+	assert_eq!(runtime.last_error(), Some("Cannot add string and number"));
+}
+
+#[test]
+fn local_own_initializer() {
+	init_logger();
+	assert!(!Parser::compile("{ let a = a; }", &mut Chunk::new()));
+	assert!(!Parser::compile("{ let a = 1; { let b = 2 + b; } }", &mut Chunk::new()));
+
+	// A shadowing initializer reads the outer variable, and globals are unaffected
+	let source = "{ let a = 1; { let a = a + 1; print(a); } { let a = a; print(a); } print(a); } let g = 5; let g2 = g; print(g2);";
+	assert_eq!(run(source), (Ok(()), "2\n1\n1\n5\n".into()));
+}
+
+#[test]
+fn duplicate_local() {
+	init_logger();
+	assert!(!Parser::compile("{ let x = 1; let x = 2; }", &mut Chunk::new()));
+	assert!(!Parser::compile("{ let x = 1; let y = 2; let x = 3; }", &mut Chunk::new()));
+
+	let source = "{ let x = 1; { let x = 2; print(x); } { let x = 3; print(x); } print(x); }";
+	assert_eq!(run(source), (Ok(()), "2\n3\n1\n".into()));
+}
+
+#[test]
+fn local_slots() {
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new());
+	let source = "{ let a = 1; { let b = 2; { let c = a * 10 + b; print(c); } } }";
+	assert_eq!(run_in(&mut runtime, source), (Ok(()), "12\n".into()));
+	// Locals in block expressions are above the values of the expressions they are in
+	let source = "{ let x = 1; { let y = 2; let z = x + y * if true { let w = 3; x + y + w } else { 0 }; print(z); print(x - if true { let v = 5; v } else { 0 }); } }";
+	assert_eq!(run_in(&mut runtime, source), (Ok(()), "13\n-4\n".into()));
+	// Breaking out of a block expression also pops the values below its locals
+	let source = "{ let i = 0; while true { let n = 100 + if i == 3 { break; } else { i }; i = n - 99; } print(i); }";
+	assert_eq!(run_in(&mut runtime, source), (Ok(()), "3\n".into()));
+}
+
+#[test]
+fn many_locals() {
+	init_logger();
+	// Slots past 255 use the long local opcodes with 3 byte operands
+	let declarations = (0..300).map(|index| format!("let v{index} = {index};")).collect::<String>();
+	let source = format!("{{ {declarations} v256 = v256 * 10; print(v256); print(v255); print(v299); v0 = v299; print(v0); }}");
+	let mut chunk = Chunk::new();
+	assert!(Parser::compile(&source, &mut chunk));
+	assert!(chunk.code.windows(4).any(|window| window == [Opcode::SetLongLocal.into(), 0, 1, 0]));
+	assert!(chunk.code.windows(4).any(|window| window == [Opcode::GetLongLocal.into(), 0, 1, 43]));
+
+	assert_eq!(run(&source), (Ok(()), "2560\n255\n299\n299\n".into()));
+}
+
+#[test]
+fn byte_order_mark() {
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new());
+	assert_eq!(run_in(&mut runtime, "\u{FEFF}print(\"hi\");"), (Ok(()), "hi\n".into()));
+	assert!(matches!(interpret("print(\"hi\");\u{FEFF}", &mut runtime), Err(InterpretError::CompileError)));
+}
+
+#[test]
+fn deep_nesting() {
+	init_logger();
+	let nested = |depth: usize| format!("print({}1{});", "(".repeat(depth), ")".repeat(depth));
+	assert!(Parser::compile(&nested(200), &mut Chunk::new()));
+	assert!(!Parser::compile(&nested(10_000), &mut Chunk::new()));
+	assert!(!Parser::compile(&format!("print({}1", "-(".repeat(10_000)), &mut Chunk::new()));
+	let blocks = |depth: usize| format!("{}print(1);{}", "{".repeat(depth), "}".repeat(depth));
+	assert!(Parser::compile(&blocks(200), &mut Chunk::new()));
+	assert!(!Parser::compile(&blocks(10_000), &mut Chunk::new()));
+
+	let mut runtime = Runtime::new(Chunk::new());
+	assert!(matches!(interpret(&nested(10_000), &mut runtime), Err(InterpretError::CompileError)));
+}
+
+#[test]
+#[ignore = "fills the constant pool, which uses a lot of memory"]
+fn constant_pool_overflow() {
+	init_logger();
+	let mut chunk = Chunk::new();
+	// Equal constants share an index, so each one must be different to fill the pool
+	for index in 0..=Chunk::MAX_LONG_INDEX {
+		chunk.make_constant(Value::int(index as i64));
+	}
+	assert!(!Parser::compile("print(1);", &mut chunk));
+}
+
+#[test]
+fn empty_source() {
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new());
+	assert!(matches!(runtime.interpret(), Ok(())));
+	assert!(matches!(interpret("", &mut runtime), Ok(())));
+	assert!(matches!(interpret("// just a comment", &mut runtime), Ok(())));
+
+	// A blank line in the REPL is skipped, and only `exit` ends the session
+	let output = SharedOutput::default();
+	let mut runtime = Runtime::new(Chunk::new()).with_output(output.clone());
+	let session = ["let x = 1;", "", "   ", "print(x);", "exit(0);", "print(2);"];
+	let ran = session.iter().take_while(|line| repl_line(line, &mut runtime)).count();
+	assert_eq!(ran, 4);
+	assert_eq!(String::from_utf8(output.0.take()).unwrap(), "1\n");
+}
+
+#[test]
+fn incomplete_expressions() {
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new());
+	for source in ["1 +", "(", "let x =", "!b ==", "1 + 2 *", "print(", "{ 1 +", "struct", "if", "while 1 <"] {
+		assert!(matches!(interpret(source, &mut runtime), Err(InterpretError::CompileError)), "{source}");
+	}
+}
+
+#[test]
+fn stack_values_outlive_slot_reuse() {
+	init_logger();
+	let source = "{ let a = \"x\"; let b = a + \"y\"; { let c = b + a; print(c + b); } let d = 1 + 2; print(a + b); print(d); }";
+	assert_eq!(run(source), (Ok(()), "xyxxy\nxxy\n3\n".into()));
+}
+
+#[test]
+fn first_emission() {
+	use Opcode::*;
+	init_logger();
+	let mut chunk = Chunk::new();
+	assert!(Parser::compile("null;", &mut chunk));
+	assert_eq!(chunk.code, [Null.into(), Pop.into(), Return.into()]);
+	assert_eq!(chunk.line_at(chunk.len() - 1), Line::new(1, 5));
+
+	// Nothing has been consumed before the return of an empty source
+	let mut chunk = Chunk::new();
+	assert!(Parser::compile("", &mut chunk));
+	assert_eq!(chunk.code, [Return.into()]);
+	let mut chunk = Chunk::new();
+	assert!(Parser::compile_eval("", &mut chunk));
+	assert_eq!(chunk.code, [Null.into(), Return.into()]);
+}
+
+#[test]
+fn global_redefinition() {
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new()).with_global_redefinition(true);
+	assert!(matches!(interpret("let x = 1;", &mut runtime), Ok(())));
+	assert_eq!(run_in(&mut runtime, "let x = \"two\"; print(x);"), (Ok(()), "two\n".into()));
+
+	let mut runtime = Runtime::new(Chunk::new());
+	assert!(matches!(interpret("let x = 1;", &mut runtime), Ok(())));
+	assert!(matches!(interpret("let x = 2;", &mut runtime), Err(InterpretError::InterpretError)));
+}
+
+#[test]
+fn fuzz_crashers() {
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new());
+	// A struct that contains itself is shown without recursing forever
+	assert_eq!(
+		run_in(&mut runtime, "struct P { x, y } let p = P { x: 1, y: 2 }; p.y = p; print(p);"),
+		(Ok(()), "P { x: 1, y: ... }\n".into())
+	);
+	// As is a long chain of structs
+	let chain = "struct Q { next } let q = null; let i = 0; while i < 1000 { q = Q { next: q }; i = i + 1; } print(q);";
+	let (result, printed) = run_in(&mut runtime, chain);
+	assert_eq!(result, Ok(()));
+	assert_eq!(printed.matches("Q {").count(), 64);
+	assert!(printed.contains("Q { next: ... }"));
+	// Durations too long for the clock are an error
+	assert!(matches!(
+		interpret("sleep(99999999999999999999.0 * 99999999999999999999.0);", &mut runtime),
+		Err(InterpretError::InterpretError)
+	));
+}
@@ -1,43 +1,39 @@
 use core::ops::Index;
-use std::{cell::RefCell, mem::size_of, sync::Arc};
+use std::{cell::RefCell, collections::BTreeMap, mem::size_of, rc::Rc, sync::Arc};
 
 use crate::bytecode::prelude::*;
 
-#[derive(Clone, Copy)]
-pub enum Value {
-	Number(f64),
+/// Identifies a constant by its contents, so equal constants can share an index in the pool.
+///
+/// Numbers are compared by their bits, which keeps `0` and `-0.0` apart, and strings by their text rather than their object.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum ConstantKey {
+	Number(u64),
+	Int(i64),
 	Bool(bool),
 	Null,
-	Obj(ObjRef),
+	Str(String),
 }
 
-impl core::fmt::Debug for Value {
-	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-		match self {
-			Value::Number(n) => write!(f, "{}", n),
-			Value::Bool(v) => write!(f, "{}", v),
-			Value::Null => write!(f, "null"),
-			Value::Obj(s) => write!(f, "{:?}", s),
-		}
+impl ConstantKey {
+	/// The key of the value, or `None` for objects other than strings which are never shared
+	fn of(value: &Value) -> Option<Self> {
+		Some(match value.kind() {
+			ValueKind::Number(number) => Self::Number(number.to_bits()),
+			ValueKind::Int(int) => Self::Int(int),
+			ValueKind::Bool(value) => Self::Bool(value),
+			ValueKind::Null => Self::Null,
+			ValueKind::Obj(obj) => Self::Str(obj.as_str()?.to_string()),
+		})
 	}
 }
 
-impl PartialEq for Value {
-	fn eq(&self, other: &Self) -> bool {
-		match (self, other) {
-			(Self::Number(l0), Self::Number(r0)) => l0 == r0,
-			(Self::Bool(l0), Self::Bool(r0)) => l0 == r0,
-			(Self::Obj(l0), Self::Obj(r0)) => {
-				l0.object_ty() == r0.object_ty()
-					&& match l0.object_ty() {
-						ObjTy::Str => l0 == r0,
-						ObjTy::Other => unimplemented!(),
-					}
-			}
-			(Self::Null, Self::Null) => true,
-			_ => false,
-		}
-	}
+/// A run of consecutive bytes of bytecode that come from the same [Line]
+#[derive(Debug, Clone, Copy)]
+struct LineRun {
+	/// The offset just past the last byte of the run, which is the total length of this run and all those before it
+	end: usize,
+	line: Line,
 }
 
 /// Contains a seiries of bytecode instructions along with associated constants and [Line] numbers.
@@ -45,19 +41,25 @@ impl PartialEq for Value {
 pub struct Chunk {
 	pub code: Vec<u8>,
 	constants: Vec<Value>,
+	/// The index of each constant by its contents, so that equal constants are only stored once
+	constant_indices: BTreeMap<ConstantKey, usize>,
 	pub strings: Vec<ObjRef>,
-	pub objects: Vec<Box<ObjTy>>,
+	pub objects: Vec<OwnedObj>,
+	/// The struct declarations, indexed by the operand of [`Opcode::MakeStruct`]
+	pub structs: Vec<Rc<StructLayout>>,
 
-	/// The line numbers, one for each line of bytecode.
-	pub lines: Vec<Line>,
+	/// The line numbers of the bytecode, with a run for each sequence of bytes from the same line (see [`Chunk::line_at`])
+	lines: Vec<LineRun>,
 }
 
 impl Chunk {
 	pub const EMPTY: Self = Self {
 		code: Vec::new(),
 		constants: Vec::new(),
+		constant_indices: BTreeMap::new(),
 		strings: Vec::new(),
 		objects: Vec::new(),
+		structs: Vec::new(),
 		lines: Vec::new(),
 	};
 
@@ -69,32 +71,135 @@ impl Chunk {
 	#[inline]
 	pub fn push(&mut self, code: impl Into<u8>, line: Line) {
 		self.code.push(code.into());
-		self.lines.push(line);
+		let end = self.code.len();
+		match self.lines.last_mut() {
+			Some(run) if run.line == line => run.end = end,
+			_ => self.lines.push(LineRun { end, line }),
+		}
+	}
+	/// The line that the byte of bytecode at the offset comes from, found by binary searching the runs of lines
+	pub fn line_at(&self, offset: usize) -> Line {
+		let index = self.lines.partition_point(|run| run.end <= offset);
+		match self.lines.get(index) {
+			Some(run) => run.line,
+			None => panic!("Bytecode index {offset} is out of bounds for a chunk of length {}", self.len()),
+		}
 	}
 	/// Length of bytecode
 	#[inline]
 	pub fn len(&self) -> usize {
 		self.code.len()
 	}
+	/// Whether there is no bytecode
+	#[inline]
+	pub fn is_empty(&self) -> bool {
+		self.code.is_empty()
+	}
 
-	/// Makes a constant in the chunk's storage, returning the index of the constant
+	/// Makes a constant in the chunk's storage, returning the index of the constant. An equal constant that already exists is reused.
 	pub fn make_constant(&mut self, constant: Value) -> usize {
+		let key = ConstantKey::of(&constant);
+		if let Some(&index) = key.as_ref().and_then(|key| self.constant_indices.get(key)) {
+			return index;
+		}
 		self.constants.push(constant);
-		self.constants.len() - 1
+		let index = self.constants.len() - 1;
+		if let Some(key) = key {
+			self.constant_indices.insert(key, index);
+		}
+		index
 	}
 
+	/// Makes a string constant, reusing the constant of an equal string so each string in the chunk has one object
 	pub fn make_string(&mut self, val: String) -> usize {
-		let (reference, obj) = ObjRef::new(val);
+		let key = ConstantKey::Str(val);
+		if let Some(&index) = self.constant_indices.get(&key) {
+			return index;
+		}
+		let ConstantKey::Str(val) = &key else { unreachable!() };
+		let (reference, obj) = ObjRef::new(val.clone());
 		self.objects.push(obj);
 		self.strings.push(reference);
-		self.make_constant(Value::Obj(reference))
+		self.constants.push(Value::obj(reference));
+		self.constant_indices.insert(key, self.constants.len() - 1);
+		self.constants.len() - 1
+	}
+
+	/// The number of constants in the chunk's storage
+	#[inline]
+	pub fn constant_count(&self) -> usize {
+		self.constants.len()
 	}
 
+	/// Removes the bytecode from `len` onwards and the constants from `constant_count` onwards, such as to replace instructions that the compiler has folded.
+	///
+	/// The removed constants must only be used by the removed bytecode.
+	pub fn truncate(&mut self, len: usize, constant_count: usize) {
+		debug_assert!(len <= self.len() && constant_count <= self.constants.len());
+		self.code.truncate(len);
+		// The runs that start before the new end are kept, with the last one cut short
+		let kept = if len == 0 {
+			0
+		} else {
+			(self.lines.partition_point(|run| run.end < len) + 1).min(self.lines.len())
+		};
+		self.lines.truncate(kept);
+		if let Some(run) = self.lines.last_mut() {
+			run.end = len;
+		}
+
+		for constant in self.constants.drain(constant_count..).rev() {
+			// Strings are created along with their constant, so a removed string constant owns the last of the chunk's objects
+			if let Some(string) = constant.as_obj()
+				&& self.strings.last() == Some(&string)
+			{
+				self.strings.pop();
+				self.objects.pop();
+			}
+		}
+		self.constant_indices.retain(|_, &mut index| index < constant_count);
+	}
+
+	/// Removes the bytecode from `start` onwards, returning each byte with its line so that the code can be pushed again later.
+	///
+	/// Only code where every jump lands inside of it can be moved, since jumps are relative.
+	pub fn take_code(&mut self, start: usize) -> Vec<(u8, Line)> {
+		let code = (start..self.len()).map(|offset| (self.code[offset], self.line_at(offset))).collect();
+		self.truncate(start, self.constants.len());
+		code
+	}
+
+	/// Replaces each string constant with the object returned by `intern`, so that equal strings from different chunks can share one object
+	pub fn intern_strings(&mut self, mut intern: impl FnMut(ObjRef) -> ObjRef) {
+		let interned = self.strings.iter().map(|&string| (string, intern(string))).collect::<ahash::AHashMap<_, _>>();
+		for constant in &mut self.constants {
+			if let Some(obj) = constant.as_obj()
+				&& let Some(&string) = interned.get(&obj)
+			{
+				*constant = Value::obj(string);
+			}
+		}
+		for string in &mut self.strings {
+			*string = interned[string];
+		}
+		// The field names of the structs are string constants too
+		for layout in &mut self.structs {
+			for field in &mut Rc::make_mut(layout).fields {
+				*field = interned[field];
+			}
+		}
+	}
+
+	/// The largest index that fits in the three byte operand of a long instruction
+	pub const MAX_LONG_INDEX: usize = (1 << 24) - 1;
+
 	/// Push a constant.
 	///
 	/// First inserts either a the `short_op` or `long_op` depending on the current number of constants,
 	/// then it inserts the constant index, a single byte for normal constants and three bytes for long constants.
+	/// The index must be at most [`Chunk::MAX_LONG_INDEX`].
 	pub fn push_constant(&mut self, id: usize, line: Line, short_op: Opcode, long_op: Opcode) {
+		debug_assert!(id <= Self::MAX_LONG_INDEX, "Index {id} does not fit in a long operand");
 		if id <= u8::MAX as usize {
 			self.push(short_op, line);
 			self.push(id as u8, line);
@@ -105,26 +210,28 @@ impl Chunk {
 			self.push(id as u8, line);
 		}
 	}
-	/// Retrieves a constant by index (unchecked).
+	/// Retrieves a constant by index, panicking if there is no constant at the index.
 	#[inline]
 	pub fn constant(&self, idx: usize) -> &Value {
 		&self.constants[idx]
 	}
 
-	/// Gets a raw pointer to the start of the bytecode.
-	#[inline]
-	pub fn as_ptr(&self) -> *const u8 {
-		self.code.as_ptr()
+	/// Reads the big endian operand of `length` bytes following the instruction at `offset`, or `None` if the chunk ends first
+	pub fn operand(&self, offset: usize, length: usize) -> Option<usize> {
+		let bytes = self.code.get(offset + 1..offset + 1 + length)?;
+		Some(bytes.iter().fold(0, |value, &byte| (value << 8) | byte as usize))
 	}
 }
 
-impl<'source> Index<usize> for Chunk {
+impl Index<usize> for Chunk {
 	type Output = u8;
 
-	/// Fast indexing for the Chunk's bytecode.
+	/// Indexes the Chunk's bytecode, panicking if the index is past the end. The VM reads through its own instruction pointer instead.
 	#[inline]
 	fn index(&self, index: usize) -> &Self::Output {
-		unsafe { &*self.code.as_ptr().offset(index as isize) }
+		self.code
+			.get(index)
+			.unwrap_or_else(|| panic!("Bytecode index {index} is out of bounds for a chunk of length {}", self.len()))
 	}
 }
 
@@ -140,3 +247,108 @@ macro_rules! disassemble {
 		}
 	};
 }
+
+#[test]
+fn constant_operands() {
+	let line = Line::new(1, 1);
+	let mut chunk = Chunk::new();
+	chunk.push_constant(255, line, Opcode::Constant, Opcode::LongConstant);
+	assert_eq!(chunk.code, [Opcode::Constant.into(), 255]);
+	let mut chunk = Chunk::new();
+	chunk.push_constant(256, line, Opcode::Constant, Opcode::LongConstant);
+	assert_eq!(chunk.code, [Opcode::LongConstant.into(), 0, 1, 0]);
+	let mut chunk = Chunk::new();
+	chunk.push_constant(Chunk::MAX_LONG_INDEX, line, Opcode::Constant, Opcode::LongConstant);
+	assert_eq!(chunk.code, [Opcode::LongConstant.into(), 255, 255, 255]);
+}
+
+#[test]
+fn operand_reads() {
+	let line = Line::new(1, 1);
+	let mut chunk = Chunk::new();
+	chunk.push_constant(256, line, Opcode::Constant, Opcode::LongConstant);
+	assert_eq!(chunk.operand(0, 3), Some(256));
+	assert_eq!(chunk.operand(0, 4), None);
+	assert_eq!(chunk.operand(3, 1), None);
+}
+
+#[test]
+#[should_panic(expected = "Bytecode index 1 is out of bounds for a chunk of length 1")]
+fn index_out_of_bounds() {
+	let mut chunk = Chunk::new();
+	chunk.push(Opcode::Constant, Line::new(1, 1));
+	let _ = chunk[1];
+}
+
+#[test]
+fn shared_constants() {
+	let mut chunk = Chunk::new();
+	assert_eq!(chunk.make_constant(Value::int(1)), chunk.make_constant(Value::int(1)));
+	assert_eq!(chunk.make_string("i".to_string()), chunk.make_string("i".to_string()));
+	assert_eq!(chunk.make_constant(Value::NULL), chunk.make_constant(Value::NULL));
+	assert_eq!(chunk.constants.len(), 3);
+	assert_eq!(chunk.objects.len(), 1);
+
+	// Constants that compare equal as values but behave differently are kept apart
+	let int = chunk.make_constant(Value::int(0));
+	let number = chunk.make_constant(Value::number(0.));
+	let negative = chunk.make_constant(Value::number(-0.));
+	assert!(int != number && number != negative && int != negative);
+
+	// The literals in a loop body share the constants of their first use
+	let mut chunk = Chunk::new();
+	assert!(Parser::compile("let i = 1000; while i < 2000 { i = i + 500; i = i + 500; i = i + 500; }", &mut chunk));
+	assert_eq!(chunk.constants.len(), 4);
+}
+
+#[test]
+fn line_runs() {
+	// Instructions of one to four bytes with a few on each line, recording lines the old way alongside for comparison
+	let mut chunk = Chunk::new();
+	let mut lines = Vec::new();
+	for instruction in 0..100_000 {
+		let line = Line::new((instruction / 3) as u16, (instruction % 3 * 4 + 1) as u16);
+		for _ in 0..=instruction % 4 {
+			chunk.push(Opcode::Pop, line);
+			lines.push(line);
+		}
+	}
+	for offset in [0, 1, 2, 3, 4, 1000, 12_345, 99_999, lines.len() / 2, lines.len() - 2, lines.len() - 1] {
+		assert_eq!(chunk.line_at(offset), lines[offset], "at offset {offset}");
+	}
+	assert!(lines.iter().enumerate().all(|(offset, &line)| chunk.line_at(offset) == line));
+
+	// There is one run per line rather than a line for every byte of bytecode
+	assert_eq!((chunk.len(), chunk.lines.len()), (250_000, 100_000));
+}
+
+#[test]
+#[should_panic = "out of bounds"]
+fn line_out_of_bounds() {
+	let mut chunk = Chunk::new();
+	chunk.push(Opcode::Return, Line::new(1, 1));
+	chunk.line_at(1);
+}
+
+#[test]
+fn truncate() {
+	let mut chunk = Chunk::new();
+	let name = chunk.make_string("name".to_string());
+	chunk.push_constant(name, Line::new(1, 1), Opcode::Constant, Opcode::LongConstant);
+	let constants = chunk.constant_count();
+	let folded = chunk.make_string("folded".to_string());
+	chunk.push_constant(folded, Line::new(2, 1), Opcode::Constant, Opcode::LongConstant);
+	let int = chunk.make_constant(Value::int(1));
+	chunk.push_constant(int, Line::new(2, 5), Opcode::Constant, Opcode::LongConstant);
+
+	chunk.truncate(3, constants);
+	assert_eq!((chunk.len(), chunk.constant_count(), chunk.strings.len(), chunk.objects.len()), (3, 1, 1, 1));
+	assert_eq!(chunk.line_at(2), Line::new(2, 1));
+	chunk.push(Opcode::Pop, Line::new(3, 1));
+	assert_eq!((chunk.line_at(2), chunk.line_at(3)), (Line::new(2, 1), Line::new(3, 1)));
+	// The removed constants can be made again
+	assert_eq!(chunk.make_constant(Value::int(1)), 1);
+
+	chunk.truncate(0, 0);
+	assert!(chunk.is_empty() && chunk.lines.is_empty() && chunk.objects.is_empty());
+}
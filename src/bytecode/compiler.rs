@@ -9,12 +9,69 @@ use parse_rules::*;
 use precedence::Precedence;
 pub struct Local<'source> {
 	ident: Token<'source>,
-	depth: usize,
+	/// The scope depth the local is declared in, or `None` while its initializer is being compiled (so it can't be read in its own initializer)
+	depth: Option<usize>,
+	/// The index of the local's value from the bottom of the stack, which is set once it is initialized
+	slot: usize,
+}
+/// A loop that is currently being compiled, used to resolve `break` and `continue`
+pub struct Loop<'source> {
+	label: Option<&'source str>,
+	/// The number of values on the stack outside of the loop body
+	height: usize,
+	/// The jumps emitted by `break` that need patching to the end of the loop
+	breaks: Vec<usize>,
+	/// The jumps emitted by `continue` that need patching to the loop condition, which comes after the body
+	continues: Vec<usize>,
 }
 #[derive(Default)]
 pub struct Compiler<'source> {
 	locals: Vec<Local<'source>>,
 	depth: usize,
+	loops: Vec<Loop<'source>>,
+	/// The number of values that the enclosing expressions have left on the stack while the current one is evaluated, such as the left operand of `+`.
+	///
+	/// Locals declared in a block expression e.g. `1 + if c { let a = 2; a } else { 3 }` are above these values on the stack.
+	temporaries: usize,
+}
+
+impl Compiler<'_> {
+	/// Local slots are encoded in at most the 3 byte operand of [`Opcode::GetLongLocal`] and [`Opcode::SetLongLocal`]
+	const MAX_LOCALS: usize = Chunk::MAX_LONG_INDEX + 1;
+
+	/// The number of values on the stack at this point in the code, which is the slot the next value pushed will be in
+	fn stack_height(&self) -> usize {
+		self.locals.iter().filter(|local| local.depth.is_some()).count() + self.temporaries
+	}
+}
+
+/// Parses a number literal with the same syntax as the scanner, which is digits (optionally separated by `_`) followed by an optional decimal part e.g. `4_000.5`
+///
+/// Literals without a decimal part are ints, and are invalid if they don't fit in an int (see [`Value::MAX_INT`]).
+pub fn parse_number(text: &str) -> Option<Value> {
+	let (integer, decimal) = match text.split_once('.') {
+		Some((integer, decimal)) => (integer, Some(decimal)),
+		None => (text, None),
+	};
+	let valid_integer = integer.starts_with(|c: char| c.is_ascii_digit()) && integer.chars().all(|c| c.is_ascii_digit() || c == '_');
+	let valid_decimal = decimal.is_none_or(|decimal| !decimal.is_empty() && decimal.chars().all(|c| c.is_ascii_digit()));
+	if !valid_integer || !valid_decimal {
+		return None;
+	}
+	let digits = text.chars().filter(|&c| c != '_').collect::<String>();
+	match decimal {
+		Some(_) => FromStr::from_str(&digits).ok().map(Value::number),
+		None => FromStr::from_str(&digits).ok().and_then(Value::try_int),
+	}
+}
+
+/// A literal that was emitted as a single instruction, which an operator applied to it can be folded into (see [`Parser::literal_at`])
+#[derive(Clone, Copy)]
+struct Literal {
+	/// The offset of the instruction
+	start: usize,
+	/// The number of constants before the literal was emitted, so that its constant can be removed when it is folded
+	constants: usize,
 }
 
 /// A simple Pratt parser that walks over the source code and output bytecode in a single pass
@@ -26,10 +83,25 @@ pub struct Parser<'a, 'source> {
 	panic: bool,
 	compiling_chunk: &'a mut Chunk,
 	compiler: Compiler<'source>,
+	/// The length of the chunk just after the last comparison was emitted, used to detect chained comparisons like `a < b < c`
+	comparison_end: Option<usize>,
+	/// The length of the chunk just after the last [`Opcode::Concat`] was emitted, so adding to its result can also be a concat
+	concat_end: Option<usize>,
+	/// The most recently emitted literal, see [`Parser::literal_at`]
+	last_literal: Option<Literal>,
+	/// The offset in the chunk where the left operand of the infix expression being parsed starts
+	operand_start: usize,
+	/// The path of the file being compiled (or `<repl>`), which `__file__` evaluates to
+	file: &'source str,
+	/// How many expressions and declarations are being parsed inside each other, limited to [`Parser::MAX_NESTING`]
+	nesting: usize,
 }
 impl<'a, 'source> Parser<'a, 'source> {
+	/// The maximum nesting of expressions and blocks, past which there is a compile error rather than a stack overflow in the parser
+	pub const MAX_NESTING: usize = 256;
+
 	/// Construct a new parser from the source and the target chunk
-	fn new(source: &'source str, chunk: &'a mut Chunk) -> Self {
+	fn new(source: &'source str, file: &'source str, chunk: &'a mut Chunk) -> Self {
 		Self {
 			scanner: Scanner::new(source),
 			current: None,
@@ -38,6 +110,12 @@ impl<'a, 'source> Parser<'a, 'source> {
 			panic: false,
 			compiling_chunk: chunk,
 			compiler: Compiler::default(),
+			comparison_end: None,
+			concat_end: None,
+			last_literal: None,
+			operand_start: 0,
+			file,
+			nesting: 0,
 		}
 	}
 	/// Does current match the token?
@@ -66,22 +144,15 @@ impl<'a, 'source> Parser<'a, 'source> {
 
 		let location = std::panic::Location::caller();
 
-		let record = log::Record::builder()
-			.args(format_args!("Line"))
-			.level(log::Level::Error)
-			.file(Some(location.file()))
-			.line(Some(location.line()))
-			.target("nonew")
-			.build();
-		log::logger().log(&record);
-
-		print!(" {}", token.line);
-		match token.token_type {
-			TokenType::Error => {}
-			TokenType::End => print!(" at end"),
-			_ => print!(" at '{}'", token.contents),
-		}
-		println!(": {}", message);
+		let description = token.describe_error(message, self.scanner.source());
+		log::logger().log(
+			&log::Record::builder()
+				.args(format_args!("{description}"))
+				.level(log::Level::Error)
+				.file(Some(location.file()))
+				.line(Some(location.line()))
+				.build(),
+		);
 	}
 	/// Create an error at the current token
 	#[track_caller]
@@ -118,40 +189,159 @@ impl<'a, 'source> Parser<'a, 'source> {
 		}
 		trace!("Current {:?}", self.current);
 	}
+	/// The line of the previous token, falling back to the current token (or the first line) before anything has been consumed so that emitting never drops bytes
+	fn previous_line(&self) -> Line {
+		self.previous.as_ref().or(self.current.as_ref()).map_or(Line::new(1, 1), |token| token.line)
+	}
 	/// Emits a byte with the line number of the previous token
 	fn emit_byte(&mut self, byte: impl Into<u8>) {
-		if let Some(token) = &self.previous {
-			self.compiling_chunk.push(byte, token.line);
-		}
+		let line = self.previous_line();
+		self.compiling_chunk.push(byte, line);
 	}
 	/// Emits 2 bytes with the line number of the previous token
 	fn emit_bytes(&mut self, byte1: impl Into<u8>, byte2: impl Into<u8>) {
-		if let Some(token) = &self.previous {
-			self.compiling_chunk.push(byte1, token.line);
-			self.compiling_chunk.push(byte2, token.line);
-		}
+		let line = self.previous_line();
+		self.compiling_chunk.push(byte1, line);
+		self.compiling_chunk.push(byte2, line);
+	}
+	/// Emits a copy of the value on top of the stack, so an expression with side effects can be used twice without evaluating it again
+	fn emit_dup(&mut self) {
+		self.emit_byte(Opcode::Dup);
+	}
+	/// Emits an exchange of the top two values on the stack
+	fn emit_swap(&mut self) {
+		self.emit_byte(Opcode::Swap);
 	}
 	/// Emits a return, tracing the chunk if debugging is enabled
 	fn emit_return(&mut self) {
-		if let Some(token) = &self.previous {
-			self.compiling_chunk.push(Opcode::Return, token.line);
-		}
+		self.emit_byte(Opcode::Return);
 		#[cfg(feature = "trace_execution")]
 		disassemble!(chunk = &self.compiling_chunk, name = "code");
 	}
-	/// Emit a constant at the last token
+	/// Emits an instruction with an index operand (in the long form if it doesn't fit in a byte), erroring if the index is too large for the long form
+	fn push_constant(&mut self, id: usize, line: Line, short_op: Opcode, long_op: Opcode) {
+		if id > Chunk::MAX_LONG_INDEX {
+			self.error_at_previous("Too many constants in one chunk");
+			return;
+		}
+		self.compiling_chunk.push_constant(id, line, short_op, long_op);
+	}
+	/// Emit a constant at the last token, where ints that fit in a signed byte are pushed by an instruction rather than stored in the constant pool
 	fn emit_constant(&mut self, value: Value) {
-		if let Some(token) = &self.previous {
-			let id = self.compiling_chunk.make_constant(value);
-			self.compiling_chunk.push_constant(id, token.line, Opcode::Constant, Opcode::LongConstant)
+		match value.kind() {
+			ValueKind::Int(0) => self.emit_byte(Opcode::LoadZero),
+			ValueKind::Int(1) => self.emit_byte(Opcode::LoadOne),
+			ValueKind::Int(int @ -128..=127) => self.emit_bytes(Opcode::LoadSmallInt, int as i8 as u8),
+			_ => {
+				let id = self.compiling_chunk.make_constant(value);
+				self.push_constant(id, self.previous_line(), Opcode::Constant, Opcode::LongConstant)
+			}
 		}
 	}
 	/// Make the identifier into a constant
 	fn emit_string(&mut self, value: String) {
-		if let Some(token) = &self.previous {
-			let id = self.compiling_chunk.make_string(value);
-			self.compiling_chunk.push_constant(id, token.line, Opcode::Constant, Opcode::LongConstant)
+		let id = self.compiling_chunk.make_string(value);
+		self.push_constant(id, self.previous_line(), Opcode::Constant, Opcode::LongConstant)
+	}
+	/// Emits a literal with `emit`, recording it so that an operator applied to it can be folded into a single literal
+	fn emit_literal(&mut self, emit: impl FnOnce(&mut Self)) {
+		let literal = Literal {
+			start: self.compiling_chunk.len(),
+			constants: self.compiling_chunk.constant_count(),
+		};
+		emit(self);
+		self.last_literal = Some(literal);
+	}
+	/// Emits a value that is not an object as a literal, using the dedicated opcodes for bools and null
+	fn emit_value(&mut self, value: Value) {
+		self.emit_literal(|parser| match value.kind() {
+			ValueKind::Bool(true) => parser.emit_byte(Opcode::True),
+			ValueKind::Bool(false) => parser.emit_byte(Opcode::False),
+			ValueKind::Null => parser.emit_byte(Opcode::Null),
+			_ => parser.emit_constant(value),
+		});
+	}
+	/// The literal starting at `start` and its value, if it is the only thing emitted since then
+	fn literal_at(&self, start: usize) -> Option<(Literal, Value)> {
+		let literal = self.last_literal.filter(|literal| literal.start == start)?;
+		let chunk = &self.compiling_chunk;
+		let (value, length) = match Opcode::from(*chunk.code.get(start)?) {
+			Opcode::True => (Value::bool(true), 1),
+			Opcode::False => (Value::bool(false), 1),
+			Opcode::Null => (Value::NULL, 1),
+			Opcode::LoadZero => (Value::int(0), 1),
+			Opcode::LoadOne => (Value::int(1), 1),
+			Opcode::LoadSmallInt => (Value::int((chunk.operand(start, 1)? as u8 as i8).into()), 2),
+			Opcode::Constant => (*chunk.constant(chunk.operand(start, 1)?), 2),
+			Opcode::LongConstant => (*chunk.constant(chunk.operand(start, 3)?), 4),
+			_ => return None,
+		};
+		(start + length == chunk.len()).then_some((literal, value))
+	}
+	/// Replaces the literal operands from `literal` onwards with the result of the operator applied to them
+	fn replace_literals(&mut self, literal: Literal, emit: impl FnOnce(&mut Self)) {
+		self.truncate(literal.start, literal.constants);
+		self.emit_literal(emit);
+	}
+	/// Removes the code from `start` onwards and the constants from `constants` onwards, forgetting where the removed comparisons and concats ended
+	///
+	/// Otherwise later code could end at the same offset and be mistaken for their result.
+	fn truncate(&mut self, start: usize, constants: usize) {
+		self.compiling_chunk.truncate(start, constants);
+		self.comparison_end = None;
+		self.concat_end = None;
+	}
+	/// Removes the code from `start` onwards so that it can be pushed again elsewhere, forgetting the literal and where the comparisons and concats in it ended
+	fn take_code(&mut self, start: usize) -> Vec<(u8, Line)> {
+		let code = self.compiling_chunk.take_code(start);
+		self.comparison_end = None;
+		self.concat_end = None;
+		self.last_literal = None;
+		code
+	}
+	/// Folds a unary operator applied to a literal at `start`, returning false if the operand is not a literal or the operation would be a runtime error
+	fn fold_unary(&mut self, opcode: Opcode, start: usize) -> bool {
+		let Some((literal, value)) = self.literal_at(start) else { return false };
+		let result = match (opcode, value.kind()) {
+			(Opcode::Negate, ValueKind::Number(number)) => Value::number(-number),
+			(Opcode::Negate, ValueKind::Int(int)) => match int.checked_neg().and_then(Value::try_int) {
+				Some(result) => result,
+				None => return false,
+			},
+			(Opcode::Not, ValueKind::Bool(value)) => Value::bool(!value),
+			_ => return false,
+		};
+		self.replace_literals(literal, |parser| parser.emit_value(result));
+		true
+	}
+	/// Folds a binary operator applied to two literals, the second starting at `right_start`.
+	///
+	/// Returns false if either operand is not a literal or the operation would be a runtime error, which is then left to be raised at runtime.
+	/// Division by zero is never folded since it depends on [`Runtime::with_ieee_division`].
+	fn fold_binary(&mut self, opcode: Opcode, left: Option<(Literal, Value)>, right_start: usize) -> bool {
+		let (Some((literal, a)), Some((_, b))) = (left, self.literal_at(right_start)) else { return false };
+		if opcode == Opcode::Add
+			&& let (Some(a), Some(b)) = (a.as_obj(), b.as_obj())
+			&& let (Some(a), Some(b)) = (a.as_str(), b.as_str())
+		{
+			let result = a.to_string() + b;
+			self.replace_literals(literal, |parser| parser.emit_string(result));
+			return true;
 		}
+		let result = match opcode {
+			Opcode::Equal => Value::bool(a == b),
+			Opcode::NotEqual => Value::bool(a != b),
+			Opcode::Divide | Opcode::Modulo if b.as_f64() == Some(0.) => return false,
+			Opcode::Add | Opcode::Subtract | Opcode::Multiply | Opcode::Divide | Opcode::Modulo | Opcode::Greater | Opcode::Less | Opcode::GreaterEqual | Opcode::LessEqual => {
+				match arithmetic(opcode, a, b) {
+					Ok(result) => result,
+					Err(_) => return false,
+				}
+			}
+			_ => return false,
+		};
+		self.replace_literals(literal, |parser| parser.emit_value(result));
+		true
 	}
 	/// Attempt to consume a token, creating an error on failiure and advancing on success
 	#[track_caller]
@@ -165,15 +355,147 @@ impl<'a, 'source> Parser<'a, 'source> {
 	/// Parses a string literal
 	fn string(&mut self, _can_assign: bool) {
 		if let Some(token) = &self.previous {
-			self.emit_string(token.contents[1..(token.contents.len() - 1)].to_string());
+			let string = token.contents[1..(token.contents.len() - 1)].to_string();
+			self.emit_literal(|parser| parser.emit_string(string));
 		}
 	}
 	/// Parses a variable identifer
 	fn variable(&mut self, can_assign: bool) {
 		if let Some(token) = self.previous.clone() {
+			if self.check(TokenType::LeftParen)
+				&& self.resolve_local(&token).is_none()
+				&& let Some(index) = find_native(token.contents)
+			{
+				self.native_call(&token, index);
+				return;
+			}
+			// Only declared structs are treated as literals, so conditions like `if x { ... }` still parse as blocks
+			if self.check(TokenType::LeftBrace)
+				&& self.resolve_local(&token).is_none()
+				&& let Some(index) = self.compiling_chunk.structs.iter().position(|layout| layout.name == token.contents)
+			{
+				self.struct_literal(&token, index);
+				return;
+			}
 			self.named_variable(&token, can_assign);
 		}
 	}
+	/// Parses the arguments to a native function like `find(line, "[0-9]+")`, checking the argument count
+	fn native_call(&mut self, name: &Token<'source>, index: usize) {
+		self.consume(TokenType::LeftParen, "Expected '(' after native function name");
+		let mut arg_count = 0;
+		if !self.check(TokenType::RightParen) {
+			loop {
+				self.expression();
+				arg_count += 1;
+				self.compiler.temporaries += 1;
+				if !self.matches(TokenType::Comma) {
+					break;
+				}
+			}
+		}
+		self.compiler.temporaries -= arg_count;
+		self.consume(TokenType::RightParen, "Expected ')' after arguments");
+
+		let native = &NATIVES[index];
+		if !native.arity.contains(&arg_count) {
+			let expected = if native.arity.start() == native.arity.end() {
+				native.arity.start().to_string()
+			} else {
+				format!("{} to {}", native.arity.start(), native.arity.end())
+			};
+			self.error_at(name, &format!("Expected {expected} arguments to '{}' but got {arg_count}", native.name));
+			self.error = true;
+			self.panic = true;
+			return;
+		}
+		self.emit_bytes(Opcode::CallNative, index as u8);
+		self.emit_byte(arg_count as u8);
+	}
+	/// Parses an array literal like `[1, 2, 3]`, allowing a trailing comma
+	pub fn array(&mut self, _can_assign: bool) {
+		let Some(open) = self.previous.clone() else { return };
+		let mut count = 0;
+		while !self.check(TokenType::RightBracket) && !self.at_end() {
+			self.expression();
+			count += 1;
+			self.compiler.temporaries += 1;
+			if !self.matches(TokenType::Comma) {
+				break;
+			}
+		}
+		self.compiler.temporaries -= count;
+		self.consume(TokenType::RightBracket, "Expected ']' after array elements");
+
+		if count > u8::MAX as usize {
+			self.error_at(&open, &format!("Too many elements in array literal ({count}), the limit is {}", u8::MAX));
+			self.error = true;
+			self.panic = true;
+			return;
+		}
+		self.emit_bytes(Opcode::MakeArray, count as u8);
+	}
+	/// Parses the fields of a struct literal like `Point { x: 1, y: 2 }`, checking that every field is specified exactly once
+	fn struct_literal(&mut self, name: &Token<'source>, index: usize) {
+		self.consume(TokenType::LeftBrace, "Expected '{' after struct name");
+		let layout = self.compiling_chunk.structs[index].clone();
+		let expected = layout.fields.iter().filter_map(ObjRef::as_str).collect::<Vec<_>>().join(", ");
+		let mut offsets = Vec::with_capacity(layout.fields.len());
+		let mut values = 0;
+		while !self.check(TokenType::RightBrace) && !self.at_end() {
+			self.consume(TokenType::Identifier, "Expected a field name");
+			let Some(field) = self.previous.clone() else { return };
+			match layout.field(field.contents) {
+				Some(offset) if offsets.contains(&offset) => self.error_at_previous(&format!("Field '{}' is specified more than once", field.contents)),
+				Some(offset) => offsets.push(offset),
+				None => self.error_at_previous(&format!("Unknown field '{}' in {}, expected fields: {expected}", field.contents, layout.name)),
+			}
+			self.consume(TokenType::Colon, "Expected ':' after field name");
+			self.expression();
+			values += 1;
+			self.compiler.temporaries += 1;
+			if !self.matches(TokenType::Comma) {
+				break;
+			}
+		}
+		self.compiler.temporaries -= values;
+		self.consume(TokenType::RightBrace, "Expected '}' after struct fields");
+
+		if self.error {
+			return;
+		}
+		if offsets.len() != layout.fields.len() {
+			let missing = layout
+				.fields
+				.iter()
+				.enumerate()
+				.filter(|(offset, _)| !offsets.contains(offset))
+				.filter_map(|(_, field)| field.as_str())
+				.collect::<Vec<_>>();
+			self.error_at(name, &format!("Missing {} in {}, expected fields: {expected}", missing.join(", "), layout.name));
+			self.error = true;
+			self.panic = true;
+			return;
+		}
+		self.emit_bytes(Opcode::MakeStruct, index as u8);
+		for offset in offsets {
+			self.emit_byte(offset as u8);
+		}
+	}
+	/// Parses a property access like `point.x` or assignment like `point.x = 3`
+	fn dot(&mut self, can_assign: bool) {
+		self.consume(TokenType::Identifier, "Expected a property name after '.'");
+		let Some(name) = self.previous.clone() else { return };
+		let index = self.compiling_chunk.make_string(name.contents.to_string());
+		if can_assign && self.matches(TokenType::Equals) {
+			self.compiler.temporaries += 1;
+			self.expression();
+			self.compiler.temporaries -= 1;
+			self.push_constant(index, name.line, Opcode::SetProperty, Opcode::SetLongProperty);
+		} else {
+			self.push_constant(index, name.line, Opcode::GetProperty, Opcode::GetLongProperty);
+		}
+	}
 	pub fn named_variable(&mut self, name: &Token<'source>, can_assign: bool) {
 		let local = self.resolve_local(name);
 		let index = local.unwrap_or_else(|| self.compiling_chunk.make_string(name.contents.to_string()));
@@ -185,47 +507,78 @@ impl<'a, 'source> Parser<'a, 'source> {
 			} else {
 				[Opcode::SetGlobal, Opcode::SetLongGlobal]
 			};
-			self.compiling_chunk.push_constant(index, name.line, short, long);
+			self.push_constant(index, name.line, short, long);
 		} else {
 			let [short, long] = if local.is_some() {
 				[Opcode::GetLocal, Opcode::GetLongLocal]
 			} else {
 				[Opcode::GetGlobalVariable, Opcode::GetLongGlobalVariable]
 			};
-			self.compiling_chunk.push_constant(index, name.line, short, long);
+			self.push_constant(index, name.line, short, long);
 		}
 	}
 
 	fn resolve_local(&mut self, name: &Token<'source>) -> Option<usize> {
-		self.compiler
-			.locals
-			.iter()
-			.enumerate()
-			.rev()
-			.find(|(_, local)| local.ident.contents == name.contents)
-			.map(|(index, _)| index)
+		let mut locals = self.compiler.locals.iter().rev().filter(|local| local.ident.contents == name.contents);
+		let mut found = locals.next();
+		// In its own initializer a local can only refer to a variable it shadows in an outer scope
+		if found.is_some_and(|local| local.depth.is_none()) {
+			found = locals.next();
+			if found.is_none() {
+				self.error_at_previous("Can't read local variable in its own initializer");
+				return None;
+			}
+		}
+		found.map(|local| local.slot)
 	}
 
 	/// Parses a number with `str::parse`
 	fn number(&mut self, _can_assign: bool) {
 		if let Some(token) = &self.previous {
-			self.emit_constant(Value::Number(FromStr::from_str(&token.contents.chars().filter(|&c| c != '_').collect::<String>()).unwrap()));
+			match parse_number(token.contents) {
+				Some(number) => self.emit_value(number),
+				None => self.error_at_previous("Invalid number literal"),
+			}
 		}
 	}
 	/// Parses a grouping `(5+5)`
 	fn grouping(&mut self, _can_assign: bool) {
 		self.expression();
 		self.consume(TokenType::RightParen, "Expected closing ')'");
+		// A comparison in brackets can be compared explicitly e.g. `(a < b) == c`
+		self.comparison_end = None;
+	}
+	/// Parses `debug(expr)`, which prints the source of the expression along with its value and then evaluates to the value
+	fn debug(&mut self, _can_assign: bool) {
+		self.consume(TokenType::LeftParen, "Expected '(' after debug");
+		let first = self.current.as_ref().map(|token| token.contents);
+		self.expression();
+		let last = self.previous.as_ref().map(|token| token.contents);
+		self.consume(TokenType::RightParen, "Expected ')' after the debugged expression");
+
+		// The tokens are slices of the source, so the expression's source spans from the start of the first to the end of the last
+		let (Some(first), Some(last)) = (first, last) else { return };
+		let source = self.scanner.source();
+		let start = first.as_ptr() as usize - source.as_ptr() as usize;
+		let end = last.as_ptr() as usize + last.len() - source.as_ptr() as usize;
+		let Some(expression) = source.get(start..end) else { return };
+		let id = self.compiling_chunk.make_string(expression.to_string());
+		let line = self.previous_line();
+		self.push_constant(id, line, Opcode::Debug, Opcode::LongDebug);
 	}
 	/// Parses a unary expression like `-5`
 	fn unary(&mut self, _can_assign: bool) {
 		if let Some(token) = &self.previous {
 			let token_type = token.token_type;
+			let start = self.compiling_chunk.len();
 			self.parse_precedence(Precedence::Unary);
-			match token_type {
-				TokenType::Minus => self.emit_byte(Opcode::Negate),
-				TokenType::Escamation => self.emit_byte(Opcode::Not),
+			let opcode = match token_type {
+				TokenType::Minus => Opcode::Negate,
+				TokenType::Escamation => Opcode::Not,
 				_ => unreachable!(),
+			};
+			if !self.fold_unary(opcode, start) {
+				self.emit_byte(opcode);
 			}
 		}
 	}
@@ -233,65 +586,118 @@ impl<'a, 'source> Parser<'a, 'source> {
 	fn binary(&mut self, _can_assign: bool) {
 		if let Some(token) = &self.previous {
 			let operator = token.token_type;
+			let comparison = matches!(
+				operator,
+				TokenType::EqualsEquals | TokenType::EscamationEquals | TokenType::Greater | TokenType::GreaterEqual | TokenType::Less | TokenType::LessEqual | TokenType::In
+			);
+			// The left operand ends where the last comparison was emitted, so it is the result of that comparison
+			if comparison && self.comparison_end == Some(self.compiling_chunk.len()) {
+				self.error_at_previous("Comparisons cannot be chained, use 'and' instead e.g. `1 < x and x < 10`");
+			}
+			let left = self.literal_at(self.operand_start);
 			let rule = get_rule(operator).precedence;
+			let right_start = self.compiling_chunk.len();
+			self.compiler.temporaries += 1;
 			self.parse_precedence(rule.next());
-			match operator {
-				TokenType::Plus => self.emit_byte(Opcode::Add),
-				TokenType::Minus => self.emit_byte(Opcode::Subtract),
-				TokenType::Star => self.emit_byte(Opcode::Multiply),
-				TokenType::Percentage => self.emit_byte(Opcode::Modolo),
-				TokenType::Slash => self.emit_byte(Opcode::Divide),
-				TokenType::EqualsEquals => self.emit_byte(Opcode::Equal),
-				TokenType::Greater => self.emit_byte(Opcode::Greater),
-				TokenType::GreaterEqual => self.emit_bytes(Opcode::Less, Opcode::Not),
-				TokenType::Less => self.emit_byte(Opcode::Less),
-				TokenType::LessEqual => self.emit_bytes(Opcode::Greater, Opcode::Not),
+			self.compiler.temporaries -= 1;
+			let opcode = match operator {
+				TokenType::Plus => Opcode::Add,
+				TokenType::Minus => Opcode::Subtract,
+				TokenType::Star => Opcode::Multiply,
+				TokenType::Percentage => Opcode::Modulo,
+				TokenType::Slash => Opcode::Divide,
+				TokenType::EqualsEquals => Opcode::Equal,
+				TokenType::EscamationEquals => Opcode::NotEqual,
+				TokenType::Greater => Opcode::Greater,
+				TokenType::GreaterEqual => Opcode::GreaterEqual,
+				TokenType::Less => Opcode::Less,
+				TokenType::LessEqual => Opcode::LessEqual,
+				TokenType::In => Opcode::Contains,
 				_ => unreachable!(),
+			};
+			if !self.fold_binary(opcode, left, right_start) {
+				// `+` joins strings if either operand is a string literal or the result of another concat
+				let string = |operand: Option<(Literal, Value)>, end: usize| operand.is_some_and(|(_, value)| value.as_obj().is_some_and(|obj| obj.as_str().is_some())) || self.concat_end == Some(end);
+				if opcode == Opcode::Add && (string(left, right_start) || string(self.literal_at(right_start), self.compiling_chunk.len())) {
+					self.emit_byte(Opcode::Concat);
+					self.concat_end = Some(self.compiling_chunk.len());
+				} else {
+					self.emit_byte(opcode);
+				}
+			}
+			if comparison {
+				self.comparison_end = Some(self.compiling_chunk.len());
 			}
 		}
 	}
 
-	/// Parses a short circuit and
+	/// Parses a type check like `x is Number` or `p is Point`, where the type is a built-in type or a declared struct
+	///
+	/// Checking against any other name is a runtime error.
+	fn is(&mut self, _can_assign: bool) {
+		self.consume(TokenType::Identifier, "Expected a type name after 'is'");
+		let Some(token) = self.previous.clone() else { return };
+		if let Some(value_type) = ValueType::from_name(token.contents) {
+			self.emit_bytes(Opcode::IsType, value_type);
+		} else if let Some(index) = self.compiling_chunk.structs.iter().position(|layout| layout.name == token.contents) {
+			self.emit_bytes(Opcode::IsStruct, index as u8);
+		} else {
+			self.emit_string(token.contents.to_string());
+			self.emit_bytes(Opcode::IsType, ValueType::Unknown);
+		}
+	}
+
+	/// Parses a short circuit and, which evaluates to the left operand if it is falsy and the right operand otherwise
 	fn and(&mut self, _can_assign: bool) {
-		let jump_start = self.emit_jump(Opcode::JumpIfFalse);
+		let jump_start = self.emit_jump(Opcode::JumpIfFalsy);
 		self.emit_byte(Opcode::Pop);
 		self.parse_precedence(Precedence::And);
-		self.patch_jump(jump_start);
+		self.patch_jump(jump_start, self.compiling_chunk.len());
 	}
-	/// Parses a short circuit or
+	/// Parses a short circuit or, which evaluates to the left operand if it is truthy and the right operand otherwise
 	fn or(&mut self, _can_assign: bool) {
-		let jump_start = self.emit_jump(Opcode::JumpIfFalse);
-		let jump_end = self.emit_jump(Opcode::Jump);
-		self.patch_jump(jump_start);
+		let jump_end = self.emit_jump(Opcode::JumpIfTruthy);
 		self.emit_byte(Opcode::Pop);
 		self.parse_precedence(Precedence::Or);
-		self.patch_jump(jump_end);
+		self.patch_jump(jump_end, self.compiling_chunk.len());
 	}
 	/// Parses literal like `true`, `false` or `null`
 	fn literal(&mut self, _can_assign: bool) {
 		if let Some(token) = &self.previous {
 			match token.token_type {
-				TokenType::True => self.emit_byte(Opcode::True),
-				TokenType::False => self.emit_byte(Opcode::False),
-				TokenType::Null => self.emit_byte(Opcode::Null),
+				TokenType::True => self.emit_value(Value::bool(true)),
+				TokenType::False => self.emit_value(Value::bool(false)),
+				TokenType::Null => self.emit_value(Value::NULL),
+				TokenType::CurrentLine => self.emit_value(Value::int(token.line.line as i64)),
+				TokenType::CurrentFile => {
+					let file = self.file.to_string();
+					self.emit_literal(|parser| parser.emit_string(file));
+				}
 				_ => unreachable!("{:?}", token.token_type),
 			}
 		}
 	}
 	/// Parses an expression using a specific [`Precedence`].
 	fn parse_precedence(&mut self, precedence: Precedence) {
+		if !self.nest("Expression too deeply nested") {
+			return;
+		}
 		self.advance();
-		let prefix = self.previous.as_ref().map_or(None, |token| get_rule(token.token_type).prefix);
+		let prefix = self.previous.as_ref().and_then(|token| get_rule(token.token_type).prefix);
 		let can_assign = precedence as u8 <= Precedence::Assignment as u8;
+		let start = self.compiling_chunk.len();
 		if let Some(prefix) = prefix {
 			prefix(self, can_assign);
 		} else {
 			self.error_at_previous("Expected expression")
 		}
 
-		while precedence as u8 <= get_rule(self.current.as_ref().unwrap().token_type).precedence as u8 {
+		// The End token (or a missing token) has no precedence, so an expression cut short at the end of input stops here
+		let current_precedence = |parser: &Self| parser.current.as_ref().map_or(Precedence::None, |token| get_rule(token.token_type).precedence);
+		while precedence as u8 <= current_precedence(self) as u8 {
 			self.advance();
-			let infix = self.previous.as_ref().map_or(None, |token| get_rule(token.token_type).infix);
+			let infix = self.previous.as_ref().and_then(|token| get_rule(token.token_type).infix);
+			self.operand_start = start;
 			if let Some(infix) = infix {
 				infix(self, can_assign);
 			} else {
@@ -303,18 +709,51 @@ impl<'a, 'source> Parser<'a, 'source> {
 			warn!("curr {:?}", self.current);
 			self.error_at_current("Invalid assignment target.");
 		}
+		self.nesting -= 1;
+	}
+	/// Enters a nested expression or declaration, returning false with an error if it is nested too deeply
+	fn nest(&mut self, message: &str) -> bool {
+		if self.nesting >= Self::MAX_NESTING {
+			self.error_at_current(message);
+			return false;
+		}
+		self.nesting += 1;
+		true
 	}
 	/// Parses with the [`Precedence::Assignment`] precedence
 	fn expression(&mut self) {
 		self.parse_precedence(Precedence::Assignment);
 	}
 
-	fn print_statement(&mut self) {
+	/// Parses `print(x);` which prints with a trailing newline or `prints(x);` which prints without one (emitting the specified opcode)
+	fn print_statement(&mut self, opcode: Opcode) {
 		self.consume(TokenType::LeftParen, "Print statements must have a '(' after the print keyword");
 		self.expression();
 		self.consume(TokenType::RightParen, "Print statements must end with a ')'");
 		self.consume(TokenType::Semicolon, "Print statements must end with a ';'");
-		self.emit_byte(Opcode::Print);
+		self.emit_byte(opcode);
+	}
+
+	/// Parses `exit(code);` or `exit();`, which halts the script with the code (defaulting to 0)
+	fn exit_statement(&mut self) {
+		self.consume(TokenType::LeftParen, "Exit statements must have a '(' after the exit keyword");
+		if self.check(TokenType::RightParen) {
+			self.emit_constant(Value::int(0));
+		} else {
+			self.expression();
+		}
+		self.consume(TokenType::RightParen, "Exit statements must end with a ')'");
+		self.consume(TokenType::Semicolon, "Exit statements must end with a ';'");
+		self.emit_byte(Opcode::Exit);
+	}
+
+	/// Parses `error(message);` (or `panic(message);`), which raises a runtime error with the message
+	fn panic_statement(&mut self) {
+		self.consume(TokenType::LeftParen, "Error statements must have a '(' after the error keyword");
+		self.expression();
+		self.consume(TokenType::RightParen, "Error statements must end with a ')'");
+		self.consume(TokenType::Semicolon, "Error statements must end with a ';'");
+		self.emit_byte(Opcode::Panic);
 	}
 
 	/// A statent that is just an expression e.g. `5+3;` or `foo(bar);`
@@ -327,11 +766,26 @@ impl<'a, 'source> Parser<'a, 'source> {
 	/// Parse a statement (expression, for, if, pring, return, while or block)
 	fn statement(&mut self) {
 		if self.matches(TokenType::Print) {
-			self.print_statement();
+			self.print_statement(Opcode::Print);
+		} else if self.matches(TokenType::Prints) {
+			self.print_statement(Opcode::PrintInline);
+		} else if self.matches(TokenType::Exit) {
+			self.exit_statement();
+		} else if self.matches(TokenType::Panic) {
+			self.panic_statement();
 		} else if self.matches(TokenType::If) {
 			self.if_statement();
 		} else if self.matches(TokenType::While) {
-			self.while_statement();
+			self.while_statement(None);
+		} else if self.matches(TokenType::Label) {
+			let label = self.previous.as_ref().map(|token| token.contents);
+			self.consume(TokenType::Colon, "Expected ':' after loop label");
+			self.consume(TokenType::While, "Labels must be followed by a loop");
+			self.while_statement(label);
+		} else if self.matches(TokenType::Break) {
+			self.break_statement();
+		} else if self.matches(TokenType::Continue) {
+			self.continue_statement();
 		} else if self.matches(TokenType::LeftBrace) {
 			self.begin_scope();
 			self.block();
@@ -349,7 +803,20 @@ impl<'a, 'source> Parser<'a, 'source> {
 	}
 
 	fn if_statement(&mut self) {
-		self.expression();
+		let branch = |parser: &mut Self| {
+			parser.consume(TokenType::LeftBrace, "If statements must contain a block");
+			parser.begin_scope();
+			parser.block();
+			parser.end_scope();
+		};
+		// Only the branch that runs is emitted when the condition is known
+		if let Some(condition) = self.constant_condition() {
+			self.live_if(condition, branch);
+			if self.matches(TokenType::Else) {
+				self.live_if(!condition, branch);
+			}
+			return;
+		}
 
 		let then_jump = self.emit_jump(Opcode::JumpIfFalse);
 		self.emit_byte(Opcode::Pop);
@@ -361,7 +828,7 @@ impl<'a, 'source> Parser<'a, 'source> {
 
 		let else_jump = self.emit_jump(Opcode::Jump);
 
-		self.patch_jump(then_jump);
+		self.patch_jump(then_jump, self.compiling_chunk.len());
 		self.emit_byte(Opcode::Pop);
 
 		if self.matches(TokenType::Else) {
@@ -371,60 +838,299 @@ impl<'a, 'source> Parser<'a, 'source> {
 			self.end_scope();
 		}
 
-		self.patch_jump(else_jump);
+		self.patch_jump(else_jump, self.compiling_chunk.len());
 	}
 
-	fn while_statement(&mut self) {
-		let loop_start = self.compiling_chunk.len();
-		self.expression();
-		let exit = self.emit_jump(Opcode::JumpIfFalse);
+	/// Parses an if in expression position e.g. `let x = if cond { 1 } else { 2 };` where each branch leaves exactly one value on the stack
+	fn if_expression(&mut self, _can_assign: bool) {
+		self.if_value(true);
+	}
+
+	/// Parses an if that leaves the value of the branch that ran on the stack, where a missing else branch gives null unless `needs_else`
+	fn if_value(&mut self, needs_else: bool) {
+		let branch = |parser: &mut Self| {
+			parser.consume(TokenType::LeftBrace, "If expressions must contain a block");
+			parser.block_expression();
+		};
+		let else_branch = |parser: &mut Self| {
+			if !parser.matches(TokenType::Else) {
+				if needs_else {
+					parser.error_at_current("If expressions must have an else branch");
+				} else {
+					parser.emit_byte(Opcode::Null);
+				}
+			} else if parser.matches(TokenType::If) {
+				parser.if_value(needs_else);
+			} else {
+				branch(parser);
+			}
+		};
+		if let Some(condition) = self.constant_condition() {
+			self.live_if(condition, branch);
+			self.live_if(!condition, else_branch);
+			return;
+		}
+
+		let then_jump = self.emit_jump(Opcode::JumpIfFalse);
 		self.emit_byte(Opcode::Pop);
+		branch(self);
+
+		let else_jump = self.emit_jump(Opcode::Jump);
+
+		self.patch_jump(then_jump, self.compiling_chunk.len());
+		self.emit_byte(Opcode::Pop);
+		else_branch(self);
+
+		self.patch_jump(else_jump, self.compiling_chunk.len());
+	}
+
+	/// Does current start a statement or declaration rather than an expression?
+	fn at_statement(&self) -> bool {
+		use TokenType::*;
+		self.current
+			.as_ref()
+			.filter(|token| matches!(token.token_type, Let | Struct | Print | Prints | Exit | Panic | If | While | Label | Break | Continue | LeftBrace))
+			.is_some()
+	}
+
+	/// Parses a block that yields the value of its final expression (or null if it ends with a statement)
+	fn block_expression(&mut self) {
+		self.begin_scope();
+		let mut has_value = false;
+		while !self.check(TokenType::RightBrace) && !self.check(TokenType::End) {
+			// An if is parsed as an expression so that it can be the block's value when it comes last, like `{ if c { 1 } else { 2 } }`
+			if self.matches(TokenType::If) {
+				self.if_value(false);
+				if self.check(TokenType::RightBrace) {
+					has_value = true;
+					break;
+				}
+			} else if self.at_statement() {
+				self.declaration();
+				continue;
+			} else {
+				self.expression();
+				if !self.matches(TokenType::Semicolon) {
+					has_value = true;
+					break;
+				}
+			}
+			self.emit_byte(Opcode::Pop);
+			if self.panic {
+				self.synchronise_error();
+			}
+		}
+		self.consume(TokenType::RightBrace, "Blocks should end with '}'.");
+		if !has_value {
+			self.emit_byte(Opcode::Null);
+		}
+
+		// The result is on top of the block's locals, so it is moved into the first local's slot before popping
+		self.compiler.depth -= 1;
+		let count = self.compiler.locals.iter().rev().take_while(|local| local.depth > Some(self.compiler.depth)).count();
+		if count > 0 {
+			let first = self.compiler.locals.len() - count;
+			let line = self.previous_line();
+			self.push_constant(self.compiler.locals[first].slot, line, Opcode::SetLocal, Opcode::SetLongLocal);
+			for _ in 0..count {
+				self.emit_byte(Opcode::Pop);
+			}
+			self.compiler.locals.truncate(first);
+		}
+	}
+
+	/// Parses a while loop, which has its condition after the body so that each iteration ends in a single conditional jump back:
+	/// `Jump condition; body: ...; condition: ...; PopJumpIfTrue body`
+	fn while_statement(&mut self, label: Option<&'source str>) {
+		let loop_start = self.compiling_chunk.len();
+		let constants = self.compiling_chunk.constant_count();
+		let structs = self.compiling_chunk.structs.len();
+		let constant = self.constant_condition();
+		// The condition's own jumps are relative so its code can be moved, but any that leave it for an enclosing loop move with it
+		let condition = self.take_code(loop_start);
+		let moved = self.take_loop_jumps(loop_start);
+
+		// A loop that always runs has no condition to jump to
+		let entry = constant.is_none().then(|| self.emit_jump(Opcode::Jump));
+		let body_start = self.compiling_chunk.len();
+		self.compiler.loops.push(Loop {
+			label,
+			height: self.compiler.stack_height(),
+			breaks: Vec::new(),
+			continues: Vec::new(),
+		});
 
 		self.consume(TokenType::LeftBrace, "While statements must contain a block");
 		self.begin_scope();
 		self.block();
 		self.end_scope();
 
-		self.jump_back(loop_start);
+		let Some(finished) = self.compiler.loops.pop() else { return };
+		for jump in finished.continues {
+			self.patch_jump(jump, self.compiling_chunk.len());
+		}
+		let condition_start = self.compiling_chunk.len();
+		match entry {
+			Some(entry) => {
+				self.patch_jump(entry, self.compiling_chunk.len());
+				let condition_line = condition.last().map(|&(_, line)| line);
+				for (byte, line) in condition {
+					self.compiling_chunk.push(byte, line);
+				}
+				let jump = self.compiling_chunk.len();
+				self.emit_jump_to(Opcode::PopJumpIfTrue, body_start);
+				// A condition that isn't a bool is reported at the end of the condition, as it was before it moved, rather than the end of the body
+				if let Some(line) = condition_line {
+					for (byte, _) in self.compiling_chunk.take_code(jump) {
+						self.compiling_chunk.push(byte, line);
+					}
+				}
+			}
+			None => self.emit_jump_to(Opcode::Jump, body_start),
+		}
+		for (target, (breaks, continues)) in self.compiler.loops.iter_mut().zip(moved) {
+			target.breaks.extend(breaks.into_iter().map(|jump| jump - loop_start + condition_start));
+			target.continues.extend(continues.into_iter().map(|jump| jump - loop_start + condition_start));
+		}
+		for jump in finished.breaks {
+			self.patch_jump(jump, self.compiling_chunk.len());
+		}
+		if constant == Some(false) {
+			self.discard(loop_start, constants, structs);
+		}
+	}
 
-		self.patch_jump(exit);
+	/// Removes the jumps of `break` and `continue` from `start` onwards from the loops they target, returning them for each loop
+	fn take_loop_jumps(&mut self, start: usize) -> Vec<(Vec<usize>, Vec<usize>)> {
+		let take = |jumps: &mut Vec<usize>| {
+			let taken = jumps.iter().copied().filter(|&jump| jump >= start).collect();
+			jumps.retain(|&jump| jump < start);
+			taken
+		};
+		self.compiler.loops.iter_mut().map(|target| (take(&mut target.breaks), take(&mut target.continues))).collect()
 	}
 
-	/// The jump location is not specified and will be added later
+	/// Parses a condition, returning its value if it is a literal bool (possibly after folding) in which case its code is removed
+	fn constant_condition(&mut self) -> Option<bool> {
+		let start = self.compiling_chunk.len();
+		self.expression();
+		let (literal, value) = self.literal_at(start)?;
+		let condition = value.as_bool()?;
+		self.discard(literal.start, literal.constants, self.compiling_chunk.structs.len());
+		Some(condition)
+	}
+
+	/// Parses a branch of an if with a constant condition, which is emitted without any jumps if it is `live` or otherwise removed after it is checked for errors
+	fn live_if(&mut self, live: bool, branch: impl FnOnce(&mut Self)) {
+		let start = self.compiling_chunk.len();
+		let constants = self.compiling_chunk.constant_count();
+		let structs = self.compiling_chunk.structs.len();
+		branch(self);
+		if !live {
+			self.discard(start, constants, structs);
+		}
+	}
+
+	/// Removes the code from `start` onwards and the constants and struct declarations from `constants` and `structs` onwards, such as for a branch that can never run
+	fn discard(&mut self, start: usize, constants: usize, structs: usize) {
+		self.truncate(start, constants);
+		self.compiling_chunk.structs.truncate(structs);
+		// Any breaks or continues in the removed code no longer need to be patched
+		self.take_loop_jumps(start);
+		self.last_literal = None;
+	}
+
+	/// Finds the index of the loop targeted by a `break` or `continue`, consuming an optional label
+	fn resolve_loop(&mut self, keyword: &str) -> Option<usize> {
+		if self.matches(TokenType::Label) {
+			let label = self.previous.as_ref().map(|token| token.contents);
+			let index = self.compiler.loops.iter().rposition(|target| target.label == label);
+			if index.is_none() {
+				self.error_at_previous("No enclosing loop with this label");
+			}
+			index
+		} else {
+			let index = self.compiler.loops.len().checked_sub(1);
+			if index.is_none() {
+				self.error_at_previous(&format!("Cannot '{keyword}' outside of a loop"));
+			}
+			index
+		}
+	}
+
+	/// Emits pops for all the locals declared inside the loop (and any temporaries of an expression the `break` is nested in), without removing them from the compiler (the scope continues after the jump)
+	fn pop_loop_locals(&mut self, index: usize) {
+		let count = self.compiler.stack_height() - self.compiler.loops[index].height;
+		for _ in 0..count {
+			self.emit_byte(Opcode::Pop);
+		}
+	}
+
+	/// Parses `break;` or `break 'label;`, jumping to the end of the target loop
+	fn break_statement(&mut self) {
+		if let Some(index) = self.resolve_loop("break") {
+			self.pop_loop_locals(index);
+			let jump = self.emit_jump(Opcode::Jump);
+			self.compiler.loops[index].breaks.push(jump);
+		}
+		self.consume(TokenType::Semicolon, "Break statements must end with a ';'");
+	}
+
+	/// Parses `continue;` or `continue 'label;`, jumping to the condition of the target loop
+	fn continue_statement(&mut self) {
+		if let Some(index) = self.resolve_loop("continue") {
+			self.pop_loop_locals(index);
+			let jump = self.emit_jump(Opcode::Jump);
+			self.compiler.loops[index].continues.push(jump);
+		}
+		self.consume(TokenType::Semicolon, "Continue statements must end with a ';'");
+	}
+
+	/// Emits a forward jump with a placeholder offset, returning the offset of the operand for [`Parser::patch_jump`]
+	///
+	/// The length of the code being jumped over isn't known yet, so the long form of the jump (with a four byte offset) is always used.
 	fn emit_jump(&mut self, opcode: Opcode) -> usize {
-		self.emit_byte(opcode);
+		self.emit_byte(opcode.long_jump());
 		self.emit_bytes(u8::MAX, u8::MAX);
-		self.compiling_chunk.len() - 2
+		self.emit_bytes(u8::MAX, u8::MAX);
+		self.compiling_chunk.len() - 4
 	}
 
-	fn patch_jump(&mut self, start: usize) {
-		let jump = self.compiling_chunk.len() - start - 2;
-		if jump > u16::MAX as usize {
-			self.error_at_current("Jump too big");
-			return;
+	/// Emits a jump to an offset that is already known such as the start of a loop, using the short form if the offset fits in two bytes
+	fn emit_jump_to(&mut self, opcode: Opcode, target: usize) {
+		// The offset is from the end of the short instruction
+		let short = i16::try_from(target as isize - (self.compiling_chunk.len() + 3) as isize).is_ok();
+		if short {
+			self.emit_byte(opcode);
+			self.emit_bytes(u8::MAX, u8::MAX);
+			self.patch_jump(self.compiling_chunk.len() - 2, target);
+		} else {
+			let start = self.emit_jump(opcode);
+			self.patch_jump(start, target);
 		}
-		self.compiling_chunk.code[start] = (jump >> 8) as u8;
-		self.compiling_chunk.code[start + 1] = jump as u8;
 	}
 
-	fn jump_back(&mut self, to: usize) {
-		let jump = self.compiling_chunk.len() + 3 - to;
-		if jump > u16::MAX as usize {
+	/// Sets the offset of the jump whose operand is at `start` so that it lands on `target`, which can be before or after it
+	fn patch_jump(&mut self, start: usize, target: usize) {
+		let length = Opcode::from(self.compiling_chunk.code[start - 1]).jump_length().expect("only jumps are patched");
+		let offset = target as isize - (start + length) as isize;
+		let bytes = match length {
+			2 => i16::try_from(offset).map(|offset| offset.to_be_bytes().to_vec()),
+			_ => i32::try_from(offset).map(|offset| offset.to_be_bytes().to_vec()),
+		};
+		let Ok(bytes) = bytes else {
 			self.error_at_current("Jump too big");
 			return;
-		}
-		self.emit_byte(Opcode::JumpBack);
-		self.emit_bytes((jump >> 8) as u8, jump as u8);
+		};
+		self.compiling_chunk.code[start..start + length].copy_from_slice(&bytes);
 	}
 
-	/// Add the jump length to a previous jump instruction
-
 	fn begin_scope(&mut self) {
 		self.compiler.depth += 1;
 	}
 	fn end_scope(&mut self) {
 		self.compiler.depth -= 1;
-		while let Some(last) = self.compiler.locals.last().filter(|last| last.depth > self.compiler.depth) {
+		while let Some(last) = self.compiler.locals.last().filter(|last| last.depth > Some(self.compiler.depth)) {
 			self.emit_byte(Opcode::Pop);
 			self.compiler.locals.pop();
 		}
@@ -440,7 +1146,13 @@ impl<'a, 'source> Parser<'a, 'source> {
 			if matches!(
 				self.current,
 				Some(Token {
-					token_type: TokenType::Fn | TokenType::Let | TokenType::For | TokenType::If | TokenType::Print | TokenType::Return, // | TokenType::While
+					token_type: TokenType::Fn
+						| TokenType::Let | TokenType::Struct
+						| TokenType::For | TokenType::If
+						| TokenType::Print | TokenType::Prints
+						| TokenType::Exit | TokenType::Panic
+						| TokenType::Break | TokenType::Continue
+						| TokenType::Return, // | TokenType::While
 					..
 				})
 			) {
@@ -450,14 +1162,31 @@ impl<'a, 'source> Parser<'a, 'source> {
 		}
 	}
 
+	/// Declares a local variable, which can't be used until it is marked as initialized
 	fn declare_variable(&mut self, token: Token<'source>) {
 		if self.compiler.depth == 0 {
 			return;
 		}
-		self.compiler.locals.push(Local {
-			ident: token,
-			depth: self.compiler.depth,
-		})
+		// Shadowing is only allowed in a nested scope
+		let mut in_scope = self.compiler.locals.iter().rev().take_while(|local| local.depth == Some(self.compiler.depth));
+		if in_scope.any(|local| local.ident.contents == token.contents) {
+			self.error_at_previous("Variable with this name already declared in this scope");
+		}
+		// The local's slot is at least the current stack height, so it must still fit in an operand
+		if self.compiler.stack_height() >= Compiler::MAX_LOCALS {
+			self.error_at_previous(&format!("Too many local variables, the limit is {}", Compiler::MAX_LOCALS));
+			return;
+		}
+		self.compiler.locals.push(Local { ident: token, depth: None, slot: 0 })
+	}
+
+	/// Marks the most recently declared local as initialized, after its initializer has been compiled and left its value on top of the stack
+	fn mark_initialized(&mut self) {
+		let slot = self.compiler.stack_height();
+		if let Some(local) = self.compiler.locals.last_mut() {
+			local.depth = Some(self.compiler.depth);
+			local.slot = slot;
+		}
 	}
 
 	fn parse_variable(&mut self, message: &'static str) -> Option<(usize, Line)> {
@@ -481,12 +1210,16 @@ impl<'a, 'source> Parser<'a, 'source> {
 			return;
 		}
 		info!("Defining variable {index} {line}");
-		self.compiling_chunk.push_constant(index, line, Opcode::DefineGlobalVariable, Opcode::DefineLongGlobalVariable)
+		self.push_constant(index, line, Opcode::DefineGlobalVariable, Opcode::DefineLongGlobalVariable)
 	}
 
 	fn variable_declaration(&mut self) {
 		let global = self.parse_variable("Expected variable name.");
-		let token = self.previous.clone();
+		if global.is_none()
+			&& let Some(token) = self.previous.clone()
+		{
+			self.declare_variable(token);
+		}
 
 		if self.matches(TokenType::Equals) {
 			self.expression();
@@ -498,15 +1231,65 @@ impl<'a, 'source> Parser<'a, 'source> {
 
 		if let Some((index, line)) = global {
 			self.define_variable(index, line);
-		} else if let Some(token) = token {
-			self.declare_variable(token);
+		} else {
+			self.mark_initialized();
 		}
 	}
 
+	/// Parses a struct declaration like `struct Point { x, y }`, which only exists at compile time
+	fn struct_declaration(&mut self) {
+		self.consume(TokenType::Identifier, "Expected struct name");
+		let Some(name) = self.previous.clone() else { return };
+		if self.compiling_chunk.structs.iter().any(|layout| layout.name == name.contents) {
+			self.error_at_previous(&format!("Struct {} is already declared", name.contents));
+		}
+		self.consume(TokenType::LeftBrace, "Expected '{' after struct name");
+		let mut fields = Vec::new();
+		while !self.check(TokenType::RightBrace) && !self.at_end() {
+			self.consume(TokenType::Identifier, "Expected a field name");
+			if let Some(field) = &self.previous {
+				let field = field.contents.to_string();
+				if fields.contains(&field) {
+					self.error_at_previous(&format!("Field '{field}' is declared more than once"));
+				}
+				fields.push(field);
+			}
+			if !self.matches(TokenType::Comma) {
+				break;
+			}
+		}
+		self.consume(TokenType::RightBrace, "Expected '}' after struct fields");
+
+		// The struct index and field offsets are single byte operands
+		if fields.len() > u8::MAX as usize + 1 {
+			self.error_at(&name, "Structs can have at most 256 fields");
+			self.error = true;
+		} else if self.compiling_chunk.structs.len() > u8::MAX as usize {
+			self.error_at(&name, "At most 256 structs can be declared");
+			self.error = true;
+		}
+		let name = name.contents.to_string();
+		let fields = fields
+			.into_iter()
+			.map(|field| {
+				let index = self.compiling_chunk.make_string(field);
+				self.compiling_chunk.constant(index).as_obj().expect("strings are objects")
+			})
+			.collect();
+		self.compiling_chunk.structs.push(std::rc::Rc::new(StructLayout { name, fields }));
+	}
+
 	/// Parse a declaration (class, function, variable or statement)
 	fn declaration(&mut self) {
+		// Skipping a token each time means the enclosing block still reaches its end
+		if !self.nest("Blocks too deeply nested") {
+			self.advance();
+			return;
+		}
 		if self.matches(TokenType::Let) {
 			self.variable_declaration();
+		} else if self.matches(TokenType::Struct) {
+			self.struct_declaration();
 		} else {
 			self.statement();
 		}
@@ -514,11 +1297,17 @@ impl<'a, 'source> Parser<'a, 'source> {
 		if self.panic {
 			self.synchronise_error();
 		}
+		self.nesting -= 1;
 	}
 
 	/// Compiles the source into the specified chunk, returing true if successful
 	pub fn compile(source: &'source str, chunk: &'a mut Chunk) -> bool {
-		let mut parser = Parser::new(source, chunk);
+		Self::compile_file(source, "<repl>", chunk)
+	}
+
+	/// Compiles the source of the file at the specified path into the chunk, returing true if successful
+	pub fn compile_file(source: &'source str, file: &'source str, chunk: &'a mut Chunk) -> bool {
+		let mut parser = Parser::new(source, file, chunk);
 		parser.advance();
 		while parser.current.as_ref().filter(|token| token.token_type != TokenType::End).is_some() {
 			parser.declaration();
@@ -527,4 +1316,52 @@ impl<'a, 'source> Parser<'a, 'source> {
 		parser.emit_return();
 		!parser.error
 	}
+
+	/// Compiles the source for `eval`, which leaves the value of the final expression (or null if it ends with a statement) on the stack when it returns
+	pub fn compile_eval(source: &'source str, chunk: &'a mut Chunk) -> bool {
+		let mut parser = Parser::new(source, "<eval>", chunk);
+		parser.advance();
+		let mut has_value = false;
+		while !parser.at_end() {
+			if parser.at_statement() {
+				parser.declaration();
+				continue;
+			}
+			parser.expression();
+			if !parser.matches(TokenType::Semicolon) {
+				has_value = true;
+				parser.consume(TokenType::End, "Expected the final expression to be at the end of the source");
+				break;
+			}
+			parser.emit_byte(Opcode::Pop);
+			if parser.panic {
+				parser.synchronise_error();
+			}
+		}
+		if !has_value {
+			parser.emit_byte(Opcode::Null);
+		}
+
+		parser.emit_return();
+		!parser.error
+	}
+}
+
+#[test]
+fn too_many_locals() {
+	init_logger();
+	let mut chunk = Chunk::new();
+	let mut parser = Parser::new("{ let x = 1; }", "<test>", &mut chunk);
+	// Declaring millions of locals in source would be slow, so start with the stack nearly full
+	parser.compiler.temporaries = Compiler::MAX_LOCALS - 1;
+	parser.advance();
+	parser.declaration();
+	assert!(!parser.error);
+
+	let mut chunk = Chunk::new();
+	let mut parser = Parser::new("{ let x = 1; }", "<test>", &mut chunk);
+	parser.compiler.temporaries = Compiler::MAX_LOCALS;
+	parser.advance();
+	parser.declaration();
+	assert!(parser.error);
 }
@@ -26,8 +26,11 @@ pub fn get_rule<'r,'source>(token_type: TokenType) -> ParseRule<'r,'source> {
 		RightParen       => new(None,                   None,                    Precedence::None      ),
 		LeftBrace        => new(None,                   None,                    Precedence::None      ),
 		RightBrace       => new(None,                   None,                    Precedence::None      ),
+		LeftBracket      => new(Some(Parser::array),    None,                    Precedence::None      ),
+		RightBracket     => new(None,                   None,                    Precedence::None      ),
 		Comma            => new(None,                   None,                    Precedence::None      ),
-		Dot              => new(None,                   None,                    Precedence::None      ),
+		Colon            => new(None,                   None,                    Precedence::None      ),
+		Dot              => new(None,                   Some(Parser::dot),       Precedence::Call      ),
 		Minus            => new(Some(Parser::unary),    Some(Parser::binary),    Precedence::Term      ),
 		Plus             => new(None,                   Some(Parser::binary),    Precedence::Term      ),
 		Semicolon        => new(None,                   None,                    Precedence::None      ),
@@ -35,7 +38,7 @@ pub fn get_rule<'r,'source>(token_type: TokenType) -> ParseRule<'r,'source> {
 		Star             => new(None,                   Some(Parser::binary),    Precedence::Factor    ),
 		Percentage       => new(None,                   Some(Parser::binary),    Precedence::Factor    ),
 		Escamation       => new(Some(Parser::unary),    None,                    Precedence::None      ),
-		EscamationEquals => new(None,                   None,                    Precedence::None      ),
+		EscamationEquals => new(None,                   Some(Parser::binary),    Precedence::Comparison),
 		Equals           => new(None,                   None,                    Precedence::None      ),
 		EqualsEquals     => new(None,                   Some(Parser::binary),    Precedence::Comparison),
 		Greater          => new(None,                   Some(Parser::binary),    Precedence::Comparison),
@@ -45,16 +48,28 @@ pub fn get_rule<'r,'source>(token_type: TokenType) -> ParseRule<'r,'source> {
 		Identifier       => new(Some(Parser::variable), None,                    Precedence::None      ),
 		StringLiteral    => new(Some(Parser::string),   None,                    Precedence::None      ),
 		NumberLiteral    => new(Some(Parser::number),   None,                    Precedence::None      ),
+		Label            => new(None,                   None,                    Precedence::None      ),
 		And              => new(None,                   Some(Parser::and),                    Precedence::And      ),
 		Or               => new(None,                   Some(Parser::or),                    Precedence::Or      ),
-		If               => new(None,                   None,                    Precedence::None      ),
+		If               => new(Some(Parser::if_expression), None,               Precedence::None      ),
+		In               => new(None,                   Some(Parser::binary),    Precedence::Comparison),
+		Is               => new(None,                   Some(Parser::is),        Precedence::Comparison),
 		Else             => new(None,                   None,                    Precedence::None      ),
 		True             => new(Some(Parser::literal),  None,                    Precedence::None      ),
 		False            => new(Some(Parser::literal),  None,                    Precedence::None      ),
 		For              => new(None,                   None,                    Precedence::None      ),
 		While            => new(None,                   None,                    Precedence::None      ),
+		Break            => new(None,                   None,                    Precedence::None      ),
+		Continue         => new(None,                   None,                    Precedence::None      ),
 		Fn               => new(None,                   None,                    Precedence::None      ),
 		Print            => new(None,                   None,                    Precedence::None      ),
+		Prints           => new(None,                   None,                    Precedence::None      ),
+		Exit             => new(None,                   None,                    Precedence::None      ),
+		Panic            => new(None,                   None,                    Precedence::None      ),
+		Debug            => new(Some(Parser::debug),    None,                    Precedence::None      ),
+		Struct           => new(None,                   None,                    Precedence::None      ),
+		CurrentLine      => new(Some(Parser::literal),  None,                    Precedence::None      ),
+		CurrentFile      => new(Some(Parser::literal),  None,                    Precedence::None      ),
 		Return           => new(None,                   None,                    Precedence::None      ),
 		Let              => new(None,                   None,                    Precedence::None      ),
 		Null             => new(Some(Parser::literal),  None,                    Precedence::None      ),
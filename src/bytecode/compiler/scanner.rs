@@ -1,4 +1,4 @@
-use core::str::Chars;
+use core::{ops::Range, str::Chars};
 use std::cell::{Ref, RefCell};
 
 use crate::bytecode::prelude::*;
@@ -15,8 +15,14 @@ pub enum TokenType {
 	LeftBrace,
 	/// }
 	RightBrace,
+	/// [
+	LeftBracket,
+	/// ]
+	RightBracket,
 	/// ,
 	Comma,
+	/// :
+	Colon,
 	/// .
 	Dot,
 	/// -
@@ -57,21 +63,40 @@ pub enum TokenType {
 	StringLiteral,
 	/// 3.14
 	NumberLiteral,
+	/// 'outer
+	Label,
 
 	// Keywords
 	And,
 	Or,
 	If,
+	In,
+	Is,
 	Else,
 	True,
 	False,
 	For,
 	While,
+	Break,
+	Continue,
 	Fn,
 	Return,
 	Let,
 	Null,
 	Print,
+	/// `prints`, which prints without a trailing newline
+	Prints,
+	Exit,
+	/// `panic` or `error`
+	Panic,
+	/// `debug`, which prints an expression's source and value
+	Debug,
+	/// `struct`, which declares a struct type
+	Struct,
+	/// `__line__`, the line number it is written on
+	CurrentLine,
+	/// `__file__`, the path of the file being run
+	CurrentFile,
 
 	Error,
 	End,
@@ -81,8 +106,22 @@ pub enum TokenType {
 #[derive(Debug, Clone)]
 pub struct Token<'a> {
 	pub token_type: TokenType,
+	/// The source of the token, or the message for an error token
 	pub contents: &'a str,
 	pub line: Line,
+	/// The byte range of the token in the scanned source, which for an error token covers the offending characters
+	pub span: Range<usize>,
+}
+
+impl Token<'_> {
+	/// Describes an error at this token for the user, where `source` is the scanned source that the span refers to
+	pub fn describe_error(&self, message: &str, source: &str) -> String {
+		match self.token_type {
+			TokenType::Error => format!("{message} '{}' at {}", source.get(self.span.clone()).unwrap_or_default(), self.line),
+			TokenType::End => format!("Line {} at end: {message}", self.line),
+			_ => format!("Line {} at '{}': {message}", self.line, self.contents),
+		}
+	}
 }
 
 /// An iter that can be peeked 2 items in advance
@@ -143,6 +182,37 @@ fn peekable() {
 	assert_eq!(peek.next(), Some(5));
 }
 
+/// Finds the type of an identifier, which is either a keyword or a user identifier
+fn keyword(ident: &str) -> TokenType {
+	match ident {
+		"and" => TokenType::And,
+		"or" => TokenType::Or,
+		"if" => TokenType::If,
+		"in" => TokenType::In,
+		"is" => TokenType::Is,
+		"else" => TokenType::Else,
+		"exit" => TokenType::Exit,
+		"error" | "panic" => TokenType::Panic,
+		"true" => TokenType::True,
+		"false" => TokenType::False,
+		"for" => TokenType::For,
+		"fn" => TokenType::Fn,
+		"return" => TokenType::Return,
+		"break" => TokenType::Break,
+		"continue" => TokenType::Continue,
+		"debug" => TokenType::Debug,
+		"let" => TokenType::Let,
+		"null" => TokenType::Null,
+		"print" => TokenType::Print,
+		"prints" => TokenType::Prints,
+		"while" => TokenType::While,
+		"struct" => TokenType::Struct,
+		"__line__" => TokenType::CurrentLine,
+		"__file__" => TokenType::CurrentFile,
+		_ => TokenType::Identifier,
+	}
+}
+
 /// The scanner which looks through the source code and generates tokens
 pub struct Scanner<'a> {
 	source: &'a str,
@@ -152,11 +222,14 @@ pub struct Scanner<'a> {
 	current: usize,
 	line: Line,
 	string_nesting: usize,
+	/// The number of columns between tab stops, used for the column of each token
+	tab_width: u16,
 }
 
 impl<'a> Scanner<'a> {
-	/// Construct a new scanner with the specified source code
+	/// Construct a new scanner with the specified source code, ignoring a leading byte order mark
 	pub fn new(source: &'a str) -> Self {
+		let source = source.strip_prefix('\u{FEFF}').unwrap_or(source);
 		Scanner {
 			source,
 			chars: Peekable::new(source.chars()),
@@ -165,22 +238,34 @@ impl<'a> Scanner<'a> {
 			current: 0,
 			line: Line::new(1, 1),
 			string_nesting: 0,
+			tab_width: Line::TAB_WIDTH,
 		}
 	}
+	/// Count tabs as the specified number of columns (at least one) rather than [`Line::TAB_WIDTH`]
+	pub fn with_tab_width(mut self, tab_width: u16) -> Self {
+		self.tab_width = tab_width.max(1);
+		self
+	}
+	/// The source code being scanned, which the contents of each token is a slice of
+	pub fn source(&self) -> &'a str {
+		self.source
+	}
 	/// Construct a new token with the specified type and the stored start and line
 	fn new_token(&self, token_type: TokenType) -> Token<'a> {
 		Token {
 			token_type,
 			contents: &self.source[self.start..self.current],
 			line: self.start_line,
+			span: self.start..self.current,
 		}
 	}
-	/// Construct an error token with the specified type and the stored start and line
+	/// Construct an error token with the specified message, spanning the source consumed since the stored start
 	fn new_error(&self, message: &'static str) -> Token<'a> {
 		Token {
 			token_type: TokenType::Error,
 			contents: message,
 			line: self.start_line,
+			span: self.start..self.current,
 		}
 	}
 	/// Check if we have reached the end of the source code
@@ -192,7 +277,7 @@ impl<'a> Scanner<'a> {
 	fn advance(&mut self) -> Option<char> {
 		if let Some(c) = self.chars.next() {
 			self.current += c.len_utf8();
-			self.line.advance(c);
+			self.line.advance(c, self.tab_width);
 			Some(c)
 		} else {
 			None
@@ -205,6 +290,12 @@ impl<'a> Scanner<'a> {
 				Some('\t' | '\n' | '\x0C' | '\r' | ' ') => {
 					self.advance();
 				}
+				// A shebang line at the very start makes scripts executable, so is treated like a comment
+				Some('#') if self.current == 0 && self.chars.peek2() == Some('!') => {
+					while self.chars.peek1() != Some('\n') && !self.at_end() {
+						self.advance();
+					}
+				}
 				// Comments are treated as whitespace
 				Some('/') => match self.chars.peek2() {
 					Some('/') => {
@@ -213,6 +304,7 @@ impl<'a> Scanner<'a> {
 						}
 					}
 					Some('*') => {
+						self.start = self.current;
 						self.start_line = self.line;
 						self.advance();
 						self.advance();
@@ -247,54 +339,35 @@ impl<'a> Scanner<'a> {
 		while self.chars.peek1().filter(|c| c.is_ascii_digit() || *c == '_').is_some() {
 			self.advance();
 		}
-		if self.matches('.') && self.chars.peek2().filter(|c| c.is_ascii_digit()).is_some() {
+		// The dot is only part of the number if a digit follows, so `3.foo` is `3`, `.` and `foo`
+		if self.chars.peek1() == Some('.') && self.chars.peek2().filter(|c| c.is_ascii_digit()).is_some() {
+			self.advance();
 			while self.chars.peek1().filter(|c| c.is_ascii_digit()).is_some() {
 				self.advance();
 			}
 		}
 		self.new_token(TokenType::NumberLiteral)
 	}
-	/// Checks if the current token is part of a keyword
-	fn check_keyword(&self, start_offset: usize, val: &str, token_type: TokenType) -> TokenType {
-		if val.len() == self.current - (self.start + start_offset) {
-			if &self.source[self.start + start_offset..self.current] == val {
-				return token_type;
-			}
+	/// Consume a loop label in the user's source code which is a single quote followed by an identifier e.g. `'outer`
+	fn comsume_label(&mut self) -> Token<'a> {
+		if self.chars.peek1().filter(|c| c.is_alphabetic()).is_none() {
+			return self.new_error("Expected a label name after '");
+		}
+		while self.chars.peek1().filter(|c| c.is_alphanumeric()).is_some() {
+			self.advance();
 		}
-		TokenType::Identifier
+		self.new_token(TokenType::Label)
 	}
 	/// Consumes an identifer, checking if it is a keyword or a user identifier
 	fn comsume_ident(&mut self) -> Token<'a> {
-		while self.chars.peek1().filter(|c| c.is_alphanumeric()).is_some() {
+		while self.chars.peek1().filter(|c| c.is_alphanumeric() || *c == '_').is_some() {
 			self.advance();
 		}
 
-		let token_type = match self.get_byte(self.start as isize) {
-			b'a' => self.check_keyword(1, "nd", TokenType::And),
-			b'o' => self.check_keyword(1, "r", TokenType::Or),
-			b'i' => self.check_keyword(1, "f", TokenType::If),
-			b'e' => self.check_keyword(1, "lse", TokenType::Else),
-			b't' => self.check_keyword(1, "rue", TokenType::True),
-			b'f' => match self.get_byte(self.start as isize + 1) {
-				b'a' => self.check_keyword(2, "lse", TokenType::False),
-				b'o' => self.check_keyword(2, "r", TokenType::For),
-				b'n' => self.check_keyword(2, "", TokenType::Fn),
-				_ => TokenType::Identifier,
-			},
-			b'r' => self.check_keyword(1, "eturn", TokenType::Return),
-			b'l' => self.check_keyword(1, "et", TokenType::Let),
-			b'n' => self.check_keyword(1, "ull", TokenType::Null),
-			b'p' => self.check_keyword(1, "rint", TokenType::Print),
-			b'w' => self.check_keyword(1, "hile", TokenType::While),
-			_ => TokenType::Identifier,
-		};
+		let token_type = keyword(&self.source[self.start..self.current]);
 		info!("Token {:?}", token_type);
 		self.new_token(token_type)
 	}
-	/// Get the byte at the specified position
-	fn get_byte(&self, byte: isize) -> u8 {
-		unsafe { *self.source.as_ptr().offset(byte) }
-	}
 	/// Try to consume the character specified, returning false if impossible
 	fn matches(&mut self, val: char) -> bool {
 		if self.chars.peek1().filter(|&c| c == val).is_some() {
@@ -320,7 +393,10 @@ impl<'a> Scanner<'a> {
 			')' => self.new_token(TokenType::RightParen),
 			'{' => self.new_token(TokenType::LeftBrace),
 			'}' => self.new_token(TokenType::RightBrace),
+			'[' => self.new_token(TokenType::LeftBracket),
+			']' => self.new_token(TokenType::RightBracket),
 			',' => self.new_token(TokenType::Comma),
+			':' => self.new_token(TokenType::Colon),
 			'.' => self.new_token(TokenType::Dot),
 			'+' => self.new_token(TokenType::Plus),
 			'-' => self.new_token(TokenType::Minus),
@@ -347,8 +423,9 @@ impl<'a> Scanner<'a> {
 			}
 
 			'"' => self.comsume_string(),
+			'\'' => self.comsume_label(),
 			_ if next.is_ascii_digit() => self.comsume_number(),
-			_ if next.is_alphabetic() => self.comsume_ident(),
+			_ if next.is_alphabetic() || next == '_' => self.comsume_ident(),
 
 			_ => self.new_error("Unknown character"),
 		}
@@ -376,4 +453,115 @@ fn
 			break;
 		}
 	}
+
+	// Identifiers starting with multi-byte characters (some of which look like keywords) or containing keywords are not keywords
+	let mut scanner = Scanner::new("über ïf éxit ƒn ñull ℓet 老if fnc if老 lets print老 prints __line__ _");
+	let tokens = core::iter::from_fn(|| Some(scanner.next()).filter(|token| token.token_type != TokenType::End)).collect::<Vec<_>>();
+	let types = tokens.iter().map(|token| token.token_type).collect::<Vec<_>>();
+	use TokenType::*;
+	assert_eq!(
+		types,
+		[
+			Identifier,
+			Identifier,
+			Identifier,
+			Identifier,
+			Identifier,
+			Identifier,
+			Identifier,
+			Identifier,
+			Identifier,
+			Identifier,
+			Identifier,
+			Prints,
+			CurrentLine,
+			Identifier
+		]
+	);
+	assert_eq!(tokens[0].contents, "über");
+	assert_eq!(tokens[6].contents, "老if");
+}
+
+#[test]
+fn number_literals() {
+	fn tokens(source: &str) -> Vec<(TokenType, &str)> {
+		let mut scanner = Scanner::new(source);
+		core::iter::from_fn(|| Some(scanner.next()).filter(|token| token.token_type != TokenType::End))
+			.map(|token| (token.token_type, token.contents))
+			.collect()
+	}
+	use TokenType::*;
+	assert_eq!(tokens("3.14"), [(NumberLiteral, "3.14")]);
+	assert_eq!(tokens("1_000.5"), [(NumberLiteral, "1_000.5")]);
+	assert_eq!(tokens("3."), [(NumberLiteral, "3"), (Dot, ".")]);
+	assert_eq!(tokens("3.x"), [(NumberLiteral, "3"), (Dot, "."), (Identifier, "x")]);
+	// Literals must start with a digit, so a leading dot is a separate token
+	assert_eq!(tokens(".5"), [(Dot, "."), (NumberLiteral, "5")]);
+}
+
+#[test]
+fn shebang() {
+	let mut scanner = Scanner::new("#!/usr/bin/env interpreter\nprint(1) # 2;");
+	let tokens = core::iter::from_fn(|| Some(scanner.next()).filter(|token| token.token_type != TokenType::End)).collect::<Vec<_>>();
+	assert_eq!(tokens[0].token_type, TokenType::Print);
+	assert_eq!(tokens[0].line, Line::new(2, 1));
+	// A `#` anywhere else is still an error, reported on the second line
+	assert_eq!(tokens[4].token_type, TokenType::Error);
+	assert_eq!(tokens[4].line, Line::new(2, 10));
+
+	assert!(Parser::compile("#!/usr/bin/env interpreter\nprint(1);", &mut Chunk::new()));
+	assert!(!Parser::compile("print(1);\n#!/usr/bin/env interpreter", &mut Chunk::new()));
+}
+
+#[test]
+fn columns() {
+	fn lines(mut scanner: Scanner<'_>) -> Vec<(&str, Line)> {
+		core::iter::from_fn(|| Some(scanner.next()).filter(|token| token.token_type != TokenType::End))
+			.map(|token| (token.contents, token.line))
+			.collect()
+	}
+	let crlf = lines(Scanner::new("let a = 1;\r\nprint(a);\r\n"));
+	assert_eq!(crlf[4], (";", Line::new(1, 10)));
+	assert_eq!(crlf[5], ("print", Line::new(2, 1)));
+	assert_eq!(crlf[7], ("a", Line::new(2, 7)));
+
+	let tabs = "\tlet x\n\t\tprint\nab\tc";
+	let default = lines(Scanner::new(tabs));
+	assert_eq!(default[0], ("let", Line::new(1, 5)));
+	assert_eq!(default[2], ("print", Line::new(2, 9)));
+	assert_eq!(default[4], ("c", Line::new(3, 5)));
+	let wide = lines(Scanner::new(tabs).with_tab_width(8));
+	assert_eq!(wide[0], ("let", Line::new(1, 9)));
+	assert_eq!(wide[2], ("print", Line::new(2, 17)));
+	assert_eq!(wide[4], ("c", Line::new(3, 9)));
+}
+
+#[test]
+fn byte_order_mark() {
+	let mut scanner = Scanner::new("\u{FEFF}print");
+	let token = scanner.next();
+	assert_eq!((token.token_type, token.contents, token.line), (TokenType::Print, "print", Line::new(1, 1)));
+	// Only a leading byte order mark is ignored
+	let mut scanner = Scanner::new("print \u{FEFF}");
+	scanner.next();
+	assert_eq!(scanner.next().token_type, TokenType::Error);
+}
+
+#[test]
+fn error_tokens() {
+	fn first_error(source: &str) -> String {
+		let mut scanner = Scanner::new(source);
+		let token = core::iter::from_fn(|| Some(scanner.next()))
+			.find(|token| matches!(token.token_type, TokenType::Error | TokenType::End))
+			.unwrap();
+		token.describe_error(token.contents, scanner.source())
+	}
+	assert_eq!(first_error("let a = 1;\nprint(a @ 2);"), "Unknown character '@' at 2:9");
+	assert_eq!(first_error("print(\"open);"), "Unclosed string '\"open);' at 1:7");
+	assert_eq!(first_error("1 /* never\nclosed"), "Unclosed multiline comment '/* never\nclosed' at 1:3");
+
+	let mut scanner = Scanner::new("老 @");
+	scanner.next();
+	let token = scanner.next();
+	assert_eq!((token.token_type, token.span), (TokenType::Error, 4..5));
 }
@@ -1,6 +1,8 @@
 /// The type of error that the interpreter has found, either a compile error or an interpret error.
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum InterpretError {
 	CompileError,
 	InterpretError,
+	/// The script called `exit(code)`, halting with the specified exit code.
+	Exit(i32),
 }
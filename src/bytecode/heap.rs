@@ -1,81 +1,418 @@
-use core::alloc::Layout;
-use std::alloc::{alloc, dealloc};
+use core::{
+	borrow::Borrow,
+	hash::{BuildHasherDefault, Hash, Hasher},
+	ptr::NonNull,
+};
+use std::{collections::HashSet, rc::Rc};
 
+use crate::bytecode::prelude::Value;
+
+/// The kind of a heap object
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
-#[repr(u8)]
 pub enum ObjTy {
 	Str,
-	Other,
+	Struct,
+	Array,
+}
+
+/// A heap object, which is owned by an [OwnedObj] and referred to by any number of [ObjRef]s
+pub enum Obj {
+	/// A string along with the hash of its contents, which is computed when it is allocated since strings are immutable
+	Str(String, u64),
+	Struct(Struct),
+	/// A list of values, created by an array literal like `[1, 2, 3]`
+	Array(Vec<Value>),
 }
-impl ObjTy {
-	pub fn free(boxed: Box<Self>) {
-		match &*boxed {
-			ObjTy::Str => unsafe { dealloc(Box::into_raw(boxed) as *mut u8, Layout::new::<Obj<String>>()) },
-			ObjTy::Other => unreachable!(),
+
+impl Obj {
+	pub fn object_ty(&self) -> ObjTy {
+		match self {
+			Obj::Str(..) => ObjTy::Str,
+			Obj::Struct(_) => ObjTy::Struct,
+			Obj::Array(_) => ObjTy::Array,
 		}
 	}
-	pub fn of<T: 'static>() -> Self {
-		let id = core::any::TypeId::of::<T>();
-		if id == core::any::TypeId::of::<String>() {
-			Self::Str
-		} else {
-			Self::Other
+	/// The number of bytes used by the object, including any buffer it owns
+	pub fn size(&self) -> usize {
+		core::mem::size_of::<Obj>()
+			+ match self {
+				Obj::Str(string, _) => string.capacity(),
+				Obj::Struct(instance) => instance.fields.capacity() * core::mem::size_of::<Value>(),
+				Obj::Array(values) => values.capacity() * core::mem::size_of::<Value>(),
+			}
+	}
+}
+
+/// A type that can be stored on the heap, as one of the variants of [Obj]
+pub trait ObjKind: Sized + 'static {
+	fn into_obj(self) -> Obj;
+	fn from_obj(obj: &Obj) -> Option<&Self>;
+	fn from_obj_mut(obj: &mut Obj) -> Option<&mut Self>;
+}
+
+macro_rules! obj_kind {
+	($($ty:ty => $variant:ident),* $(,)?) => {
+		$(
+			impl ObjKind for $ty {
+				fn into_obj(self) -> Obj {
+					Obj::$variant(self)
+				}
+				fn from_obj(obj: &Obj) -> Option<&Self> {
+					match obj {
+						Obj::$variant(val) => Some(val),
+						_ => None,
+					}
+				}
+				fn from_obj_mut(obj: &mut Obj) -> Option<&mut Self> {
+					match obj {
+						Obj::$variant(val) => Some(val),
+						_ => None,
+					}
+				}
+			}
+		)*
+	};
+}
+
+obj_kind! {
+	Struct => Struct,
+	Vec<Value> => Array,
+}
+
+impl ObjKind for String {
+	fn into_obj(self) -> Obj {
+		let hash = hash_str(&self);
+		Obj::Str(self, hash)
+	}
+	fn from_obj(obj: &Obj) -> Option<&Self> {
+		match obj {
+			Obj::Str(string, _) => Some(string),
+			_ => None,
 		}
 	}
+	/// Strings are immutable, which keeps their cached hash valid
+	fn from_obj_mut(_: &mut Obj) -> Option<&mut Self> {
+		None
+	}
 }
 
-#[repr(C)]
-pub struct Obj<T> {
-	ty: ObjTy,
-	val: T,
+/// Hashes the contents of a string, giving the same hash every time so it can be cached in the object
+pub fn hash_str(string: &str) -> u64 {
+	const STATE: ahash::RandomState = ahash::RandomState::with_seeds(0x243f_6a88_85a3_08d3, 0x1319_8a2e_0370_7344, 0xa409_3822_299f_31d0, 0x082e_fa98_ec4e_6c89);
+	STATE.hash_one(string)
 }
 
+/// The name and fields of a struct declaration, shared by all instances of the struct
+///
+/// The field names are string constants of the chunk that declared the struct, which are interned along with its other strings so that a property can be found by comparing handles.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StructLayout {
+	pub name: String,
+	pub fields: Vec<ObjRef>,
+}
+
+impl StructLayout {
+	/// Finds the offset of the field with the specified name
+	pub fn field(&self, name: &str) -> Option<usize> {
+		self.fields.iter().position(|field| field.as_str() == Some(name))
+	}
+
+	/// Finds the offset of the field whose name is the interned string `name`
+	#[inline]
+	pub fn field_by_key(&self, name: ObjRef) -> Option<usize> {
+		self.fields.iter().position(|&field| field == name)
+	}
+}
+
+/// An instance of a struct, with the values of the fields in the order they are declared in the [StructLayout]
+pub struct Struct {
+	pub layout: Rc<StructLayout>,
+	pub fields: Vec<Value>,
+}
+
+/// Owns a heap object, freeing it when dropped. No [ObjRef] to the object may be used after that.
+pub struct OwnedObj(ObjRef);
+
+impl OwnedObj {
+	pub fn new(val: impl ObjKind) -> Self {
+		Self(ObjRef(NonNull::from(Box::leak(Box::new(val.into_obj())))))
+	}
+	/// A handle to the object, which is valid for as long as this is alive
+	#[inline]
+	pub fn handle(&self) -> ObjRef {
+		self.0
+	}
+	/// The number of bytes used by the object, including any buffer it owns
+	pub fn size(&self) -> usize {
+		self.0.get().size()
+	}
+}
+
+impl Drop for OwnedObj {
+	fn drop(&mut self) {
+		// SAFETY: the pointer was leaked from a box in `OwnedObj::new`, and this is the only place it is reclaimed
+		drop(unsafe { Box::from_raw(self.0 .0.as_ptr()) });
+	}
+}
+
+/// A cheap handle to a heap object, which must not be used after the [OwnedObj] owning it is dropped
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
-pub struct ObjRef(*mut ObjTy);
+pub struct ObjRef(NonNull<Obj>);
 
 impl ObjRef {
+	/// Allocates an object, returning a handle to it along with its owner
+	#[must_use]
+	#[inline]
+	pub fn new(val: impl ObjKind) -> (Self, OwnedObj) {
+		let owned = OwnedObj::new(val);
+		(owned.handle(), owned)
+	}
+
+	#[inline]
+	fn get(&self) -> &Obj {
+		// SAFETY: objects are owned by the chunk or runtime that hands out their handles, which keeps them alive while they are reachable
+		unsafe { self.0.as_ref() }
+	}
+
+	#[inline]
+	fn get_mut(&mut self) -> &mut Obj {
+		// SAFETY: as in `get`, and the interpreter is single threaded so no other reference to the object is in use
+		unsafe { self.0.as_mut() }
+	}
+
+	/// Gets the object as the specified type, panicking if it is of another type
+	#[inline]
+	pub fn as_ref_unchecked<T: ObjKind>(&self) -> &T {
+		self.as_ref().expect("object is of a different type")
+	}
+
+	/// Gets the object mutably as the specified type, panicking if it is of another type
+	#[inline]
+	pub fn as_mut_unchecked<T: ObjKind>(&mut self) -> &mut T {
+		self.as_mut().expect("object is of a different type")
+	}
+
+	#[inline]
+	pub fn as_mut<T: ObjKind>(&mut self) -> Option<&mut T> {
+		T::from_obj_mut(self.get_mut())
+	}
+
+	#[inline]
+	pub fn as_ref<T: ObjKind>(&self) -> Option<&T> {
+		T::from_obj(self.get())
+	}
+
+	/// The address of the object, which identifies it while it is alive
 	#[must_use]
 	#[inline]
-	pub fn new<T: 'static>(val: T) -> (Self, Box<ObjTy>) {
-		let ptr = unsafe { alloc(Layout::new::<Obj<T>>()) as *mut Obj<T> };
-		let ty = ObjTy::of::<T>();
-		unsafe { ptr.write(Obj { ty, val }) };
-		(Self(unsafe { ptr as *mut ObjTy }), unsafe { Box::from_raw(ptr as *mut ObjTy) })
+	pub fn addr(&self) -> usize {
+		self.0.as_ptr() as usize
 	}
 
+	/// The address of the object, which can be turned back into a handle with [`ObjRef::from_exposed_addr`]
 	#[inline]
-	pub fn as_ref_unchecked<T>(&self) -> &T {
-		&unsafe { &*(self.0 as *const Obj<T>) }.val
+	pub(crate) fn expose_addr(self) -> usize {
+		self.0.as_ptr().expose_provenance()
 	}
 
+	/// Recreates a handle from the address of an object.
+	///
+	/// # Safety
+	/// The address must come from [`ObjRef::expose_addr`] on a handle to an object that is still alive.
 	#[inline]
-	pub fn as_mut_unchecked<T>(&mut self) -> &mut T {
-		&mut unsafe { &mut *(self.0 as *mut Obj<T>) }.val
+	pub(crate) unsafe fn from_exposed_addr(addr: usize) -> Self {
+		// SAFETY: the address came from a `NonNull` so is not null
+		Self(unsafe { NonNull::new_unchecked(core::ptr::with_exposed_provenance_mut(addr)) })
 	}
 
+	/// Gets the contents of a string object
 	#[inline]
-	pub fn as_mut<T: 'static>(&mut self) -> Option<&mut T> {
-		(self.object_ty() == ObjTy::of::<T>()).then(|| self.as_mut_unchecked())
+	pub fn as_str(&self) -> Option<&str> {
+		match self.get() {
+			Obj::Str(string, _) => Some(string),
+			_ => None,
+		}
 	}
 
+	/// The hash of a string object's contents, which was cached when it was allocated
 	#[inline]
-	pub fn as_ref<T: 'static>(&self) -> Option<&T> {
-		(self.object_ty() == ObjTy::of::<T>()).then(|| self.as_ref_unchecked())
+	pub fn str_hash(&self) -> Option<u64> {
+		match self.get() {
+			Obj::Str(_, hash) => Some(*hash),
+			_ => None,
+		}
 	}
 
 	#[must_use]
 	#[inline]
 	pub fn object_ty(&self) -> ObjTy {
-		unsafe { *(self.0) }
+		self.get().object_ty()
+	}
+}
+
+impl ObjRef {
+	/// Structs and arrays nested deeper than this are shown as `...`, so that formatting can't overflow the stack
+	const MAX_DISPLAY_DEPTH: usize = 64;
+
+	/// Writes the object for the user, where `parents` are the structs and arrays containing it. One that contains itself is shown as `...` rather than recursing forever.
+	fn write(&self, f: &mut core::fmt::Formatter<'_>, parents: &mut Vec<ObjRef>) -> core::fmt::Result {
+		match self.get() {
+			Obj::Str(string, _) => f.write_str(string),
+			Obj::Struct(instance) => {
+				if parents.contains(self) || parents.len() >= Self::MAX_DISPLAY_DEPTH {
+					return f.write_str("...");
+				}
+				write!(f, "{} {{", instance.layout.name)?;
+				parents.push(*self);
+				for (index, (name, value)) in instance.layout.fields.iter().zip(&instance.fields).enumerate() {
+					write!(f, "{} {name:?}: ", if index == 0 { "" } else { "," })?;
+					match value.as_obj() {
+						Some(obj) => obj.write(f, parents)?,
+						None => write!(f, "{value}")?,
+					}
+				}
+				parents.pop();
+				f.write_str(" }")
+			}
+			Obj::Array(values) => {
+				if parents.contains(self) || parents.len() >= Self::MAX_DISPLAY_DEPTH {
+					return f.write_str("...");
+				}
+				f.write_str("[")?;
+				parents.push(*self);
+				for (index, value) in values.iter().enumerate() {
+					if index != 0 {
+						f.write_str(", ")?;
+					}
+					match value.as_obj() {
+						Some(obj) => obj.write(f, parents)?,
+						None => write!(f, "{value}")?,
+					}
+				}
+				parents.pop();
+				f.write_str("]")
+			}
+		}
 	}
 }
 
 impl core::fmt::Debug for ObjRef {
 	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-		f.write_str(match self.object_ty() {
-			ObjTy::Str => self.as_ref_unchecked::<String>(),
-			ObjTy::Other => todo!(),
-		})
+		self.write(f, &mut Vec::new())
+	}
+}
+
+impl core::fmt::Debug for OwnedObj {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		core::fmt::Debug::fmt(&self.0, f)
+	}
+}
+
+/// A hash table of string objects, looked up by the cached hash and then the contents so finding a string doesn't scan the table
+#[derive(Default)]
+pub struct StringTable(HashSet<Interned, BuildHasherDefault<CachedHash>>);
+
+impl StringTable {
+	/// Finds the string object with the specified contents
+	pub fn find(&self, val: &str) -> Option<ObjRef> {
+		let key: &dyn StrKey = &(hash_str(val), val);
+		self.0.get(key).map(|interned| interned.0)
+	}
+
+	/// Adds a string object, which must not be equal to one already in the table
+	pub fn insert(&mut self, string: ObjRef) {
+		let inserted = self.0.insert(Interned(string));
+		debug_assert!(inserted, "{string:?} is already interned");
+	}
+
+	/// Keeps only the strings for which the predicate is true
+	pub fn retain(&mut self, mut keep: impl FnMut(ObjRef) -> bool) {
+		self.0.retain(|interned| keep(interned.0));
+	}
+
+	pub fn len(&self) -> usize {
+		self.0.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+}
+
+/// A string object in a [`StringTable`], hashed by its cached hash and compared by its contents
+struct Interned(ObjRef);
+
+/// The hash and contents of a string, so a `&str` can be looked up in a [`StringTable`] without allocating an object
+trait StrKey {
+	fn str_hash(&self) -> u64;
+	fn contents(&self) -> &str;
+}
+
+impl StrKey for Interned {
+	fn str_hash(&self) -> u64 {
+		self.0.str_hash().expect("only strings are interned")
+	}
+	fn contents(&self) -> &str {
+		self.0.as_ref_unchecked::<String>()
+	}
+}
+
+impl StrKey for (u64, &str) {
+	fn str_hash(&self) -> u64 {
+		self.0
+	}
+	fn contents(&self) -> &str {
+		self.1
+	}
+}
+
+impl<'a> Borrow<dyn StrKey + 'a> for Interned {
+	fn borrow(&self) -> &(dyn StrKey + 'a) {
+		self
+	}
+}
+
+impl Hash for dyn StrKey + '_ {
+	fn hash<H: Hasher>(&self, state: &mut H) {
+		state.write_u64(self.str_hash());
+	}
+}
+
+impl PartialEq for dyn StrKey + '_ {
+	fn eq(&self, other: &Self) -> bool {
+		self.str_hash() == other.str_hash() && self.contents() == other.contents()
+	}
+}
+
+impl Eq for dyn StrKey + '_ {}
+
+impl Hash for Interned {
+	fn hash<H: Hasher>(&self, state: &mut H) {
+		(self as &dyn StrKey).hash(state);
+	}
+}
+
+impl PartialEq for Interned {
+	fn eq(&self, other: &Self) -> bool {
+		(self as &dyn StrKey) == (other as &dyn StrKey)
+	}
+}
+
+impl Eq for Interned {}
+
+/// Passes through the cached hash of a string rather than hashing it again
+#[derive(Default)]
+struct CachedHash(u64);
+
+impl Hasher for CachedHash {
+	fn finish(&self) -> u64 {
+		self.0
+	}
+	fn write(&mut self, _: &[u8]) {
+		unreachable!("only cached hashes are written")
+	}
+	fn write_u64(&mut self, hash: u64) {
+		self.0 = hash;
 	}
 }
 
@@ -90,5 +427,85 @@ fn mine() {
 		ObjRef::new(real_str)
 	};
 
-	assert_eq!(refer.as_ref::<String>(), Some(&"hello".to_string()))
+	assert_eq!(refer.as_ref::<String>(), Some(&"hello".to_string()));
+	drop(owned);
+}
+
+#[test]
+fn free_strings() {
+	let objects = (0..100).map(|index| ObjRef::new(format!("string {index}"))).collect::<Vec<_>>();
+	for (index, (string, _)) in objects.iter().enumerate() {
+		assert_eq!(string.as_ref::<String>(), Some(&format!("string {index}")));
+	}
+	// Freeing drops each string's buffer along with the object
+	drop(objects);
+}
+
+#[test]
+fn allocate_access_free() {
+	let (a, a_owned) = ObjRef::new("a".to_string());
+	let (b, b_owned) = ObjRef::new("b".to_string());
+	let layout = Rc::new(StructLayout {
+		name: "Pair".to_string(),
+		fields: vec![a, b],
+	});
+	assert_eq!((layout.field("b"), layout.field_by_key(a), layout.field("c")), (Some(1), Some(0), None));
+	for round in 0..10 {
+		let (string, string_owned) = ObjRef::new(format!("round {round}"));
+		let (mut pair, pair_owned) = ObjRef::new(Struct {
+			layout: layout.clone(),
+			fields: vec![Value::int(round), Value::obj(string)],
+		});
+		assert_eq!((string.object_ty(), pair.object_ty()), (ObjTy::Str, ObjTy::Struct));
+		assert!(string.as_ref::<Struct>().is_none());
+		assert!(pair.as_ref::<String>().is_none());
+
+		pair.as_mut::<Struct>().unwrap().fields[0] = Value::int(round + 1);
+		assert_eq!(format!("{pair:?}"), format!("Pair {{ a: {}, b: round {round} }}", round + 1));
+		assert!(pair_owned.size() > core::mem::size_of::<Obj>());
+		drop(pair_owned);
+		drop(string_owned);
+	}
+}
+
+#[test]
+fn arrays() {
+	let (string, string_owned) = ObjRef::new("two".to_string());
+	let (mut array, array_owned) = ObjRef::new(vec![Value::int(1), Value::obj(string), Value::NULL]);
+	assert_eq!(array.object_ty(), ObjTy::Array);
+	assert!(array.as_ref::<String>().is_none());
+	assert_eq!(format!("{array:?}"), "[1, two, null]");
+
+	// An array containing itself is cut off rather than printed forever
+	let this = Value::obj(array);
+	array.as_mut::<Vec<Value>>().unwrap().push(this);
+	assert_eq!(format!("{array:?}"), "[1, two, null, ...]");
+	assert!(array_owned.size() >= core::mem::size_of::<Obj>() + 4 * core::mem::size_of::<Value>());
+
+	let (empty, empty_owned) = ObjRef::new(Vec::<Value>::new());
+	assert_eq!(format!("{empty:?}"), "[]");
+	drop((array_owned, empty_owned, string_owned));
+}
+
+#[test]
+fn string_table() {
+	let mut table = StringTable::default();
+	let objects = (0..1000).map(|index| ObjRef::new(format!("string {index}"))).collect::<Vec<_>>();
+	for (string, _) in &objects {
+		assert_eq!(table.find(string.as_str().unwrap()), None);
+		table.insert(*string);
+	}
+	assert_eq!(table.len(), 1000);
+	// Equal contents find the same object, whichever buffer they are in
+	for (index, (string, _)) in objects.iter().enumerate() {
+		assert_eq!(table.find(&format!("string {index}")), Some(*string));
+		assert_eq!(string.str_hash(), Some(hash_str(&format!("string {index}"))));
+	}
+	assert_eq!(table.find("string"), None);
+
+	table.retain(|string| string.as_str().is_some_and(|string| string.ends_with('0')));
+	assert_eq!(table.len(), 100);
+	assert_eq!(table.find("string 10"), Some(objects[10].0));
+	assert_eq!(table.find("string 11"), None);
+	drop(objects);
 }
@@ -13,13 +13,20 @@ impl Line {
 	pub fn new(line: u16, col: u16) -> Self {
 		Self { line, col }
 	}
-	/// Advances the line number if the char is relevant
-	pub fn advance(&mut self, c: char) {
-		if c == '\n' {
-			self.line += 1;
-			self.col = 1;
-		} else {
-			self.col += 1;
+	/// The default number of columns between tab stops
+	pub const TAB_WIDTH: u16 = 4;
+
+	/// Advances the line number if the char is relevant, with a tab moving the column to the next tab stop
+	pub fn advance(&mut self, c: char, tab_width: u16) {
+		match c {
+			'\n' => {
+				self.line += 1;
+				self.col = 1;
+			}
+			// Only the newline of a `\r\n` counts, so Windows line endings don't shift the column
+			'\r' => {}
+			'\t' => self.col += tab_width - (self.col - 1) % tab_width,
+			_ => self.col += 1,
 		}
 	}
 }
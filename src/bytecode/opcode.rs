@@ -24,7 +24,7 @@ opcode! {
 	u8,
 
 	/// The operation code, defining an operation in the bytecode.
-	#[derive(Debug, PartialEq, Eq)]
+	#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 	pub enum Opcode {
 		0 => Return,
 
@@ -62,11 +62,134 @@ opcode! {
 		25 => SetLocal,
 		26 => SetLongLocal,
 
+		// The jumps have a signed offset from the end of the instruction, so they can go in either direction
 		27 => Jump,
 		28 => JumpIfFalse,
-		29=> JumpBack,
 
-		30 => Modolo,
+		30 => Modulo,
+
+		31 => Exit,
+		32 => Panic,
+
+		33 => Contains,
+		34 => IsType,
+		35 => CallNative,
+		36 => PrintInline,
+		37 => Debug,
+		38 => LongDebug,
+
+		39 => MakeStruct,
+		40 => GetProperty,
+		41 => GetLongProperty,
+		42 => SetProperty,
+		43 => SetLongProperty,
+
+		44 => JumpIfFalsy,
+
+		45 => MakeArray,
+
+		46 => PopJumpIfTrue,
+
+		// The jumps with a four byte offset, for jumping further than a two byte offset (-32768 to 32767) reaches
+		47 => LongJump,
+		48 => LongJumpIfFalse,
+		49 => LongJumpIfFalsy,
+		51 => LongPopJumpIfTrue,
+
+		52 => GreaterEqual,
+		53 => LessEqual,
+		54 => NotEqual,
+
+		55 => JumpIfTruthy,
+		56 => LongJumpIfTruthy,
+
+		57 => Dup,
+		58 => Swap,
+
+		// `+` where an operand is known to be a string
+		59 => Concat,
+
+		// Small ints which are pushed without using the constant pool
+		60 => LoadZero,
+		61 => LoadOne,
+		62 => LoadSmallInt,
+
+		// `is` with a struct name, which has the index of the struct as its operand
+		63 => IsStruct,
+	}
+}
+
+impl Opcode {
+	/// The number of bytes in the offset of a jump, or `None` if this is not a jump
+	pub fn jump_length(self) -> Option<usize> {
+		match self {
+			Self::Jump | Self::JumpIfFalse | Self::JumpIfFalsy | Self::JumpIfTruthy | Self::PopJumpIfTrue => Some(2),
+			Self::LongJump | Self::LongJumpIfFalse | Self::LongJumpIfFalsy | Self::LongJumpIfTruthy | Self::LongPopJumpIfTrue => Some(4),
+			_ => None,
+		}
+	}
+
+	/// The form of a jump with a four byte offset
+	pub fn long_jump(self) -> Self {
+		match self {
+			Self::Jump => Self::LongJump,
+			Self::JumpIfFalse => Self::LongJumpIfFalse,
+			Self::JumpIfFalsy => Self::LongJumpIfFalsy,
+			Self::JumpIfTruthy => Self::LongJumpIfTruthy,
+			Self::PopJumpIfTrue => Self::LongPopJumpIfTrue,
+			_ => unreachable!("{self:?} is not a short jump"),
+		}
+	}
+}
+
+/// Decodes the signed offset of a jump, stored in `length` bytes
+pub fn jump_offset(operand: usize, length: usize) -> isize {
+	if length == 2 {
+		operand as u16 as i16 as isize
+	} else {
+		operand as u32 as i32 as isize
+	}
+}
+
+opcode! {
+	u8,
+
+	/// The built-in types that can be checked against with `is`, stored as the operand of [`Opcode::IsType`].
+	///
+	/// A name that is not a type is checked with [`ValueType::Unknown`], with the name pushed after the value so the runtime error can report it.
+	#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+	pub enum ValueType {
+		0 => Number,
+		1 => Bool,
+		2 => Null,
+		3 => String,
+		4 => Int,
+		5 => Array,
+	}
+}
+
+impl ValueType {
+	/// Finds the built-in type with the specified name
+	pub fn from_name(name: &str) -> Option<Self> {
+		match name {
+			"Number" => Some(Self::Number),
+			"Bool" => Some(Self::Bool),
+			"Null" => Some(Self::Null),
+			"String" => Some(Self::String),
+			"Int" => Some(Self::Int),
+			"Array" => Some(Self::Array),
+			_ => None,
+		}
+	}
+
+	/// Checks if the value is of this type, where ints are also numbers
+	pub fn matches(self, value: &Value) -> bool {
+		match (self, value.kind()) {
+			(Self::Number | Self::Int, ValueKind::Int(_)) | (Self::Number, ValueKind::Number(_)) | (Self::Bool, ValueKind::Bool(_)) | (Self::Null, ValueKind::Null) => true,
+			(Self::String, ValueKind::Obj(obj)) => obj.object_ty() == ObjTy::Str,
+			(Self::Array, ValueKind::Obj(obj)) => obj.object_ty() == ObjTy::Array,
+			_ => false,
+		}
 	}
 }
 
@@ -74,51 +197,71 @@ opcode! {
 #[cfg(feature = "trace_execution")]
 pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
 	/// Disassembles a simple instruction of one byte.
-	fn simple_instruction(opcode: Opcode, offset: usize) -> usize {
+	fn simple_instruction(opcode: Opcode, offset: usize) -> Option<usize> {
 		println!("{opcode:?}");
-		offset + 1
+		Some(offset + 1)
 	}
 
-	/// Disassembles the short constant instruction
-	fn constant_instruction(chunk: &Chunk, opcode: Opcode, offset: usize) -> usize {
-		let constant_idx = chunk[offset + 1];
-		let constant = chunk.constant(constant_idx as usize);
+	/// Disassembles an instruction with a constant index operand of `length` bytes
+	fn constant_instruction(chunk: &Chunk, opcode: Opcode, offset: usize, length: usize) -> Option<usize> {
+		let constant_idx = chunk.operand(offset, length)?;
+		let constant = chunk.constant(constant_idx);
 		println!("{:<16} {constant_idx} {constant:?}", format!("{:?}", opcode));
 
-		offset + 2
+		Some(offset + 1 + length)
 	}
 
-	/// Disassembles the long constant instruction
-	fn long_constant_instruction(chunk: &Chunk, opcode: Opcode, offset: usize) -> usize {
-		let mut constant_idx = 0;
-		for i in 0..3 {
-			constant_idx <<= 8;
-			constant_idx ^= chunk[offset + i + 1] as usize;
-		}
-		let constant = chunk.constant(constant_idx);
-		println!("{:<16} {constant_idx} {constant:?}", format!("{:?}", opcode));
+	/// Disassembles an instruction with a plain operand of `length` bytes
+	fn value_instruction(chunk: &Chunk, opcode: Opcode, offset: usize, length: usize) -> Option<usize> {
+		let value = chunk.operand(offset, length)?;
+		println!("{:<16} {value}", format!("{:?}", opcode));
 
-		offset + 4
+		Some(offset + 1 + length)
 	}
 
-	/// Disassembles the long
-	fn value_instruction(chunk: &Chunk, opcode: Opcode, offset: usize, length: usize) -> usize {
-		let mut value = 0;
-		for i in 0..length {
-			value <<= 8;
-			value ^= chunk[offset + i + 1] as usize;
-		}
+	/// Disassembles a jump, showing its signed offset and the offset it lands on
+	fn jump_instruction(chunk: &Chunk, opcode: Opcode, offset: usize, length: usize) -> Option<usize> {
+		let jump = jump_offset(chunk.operand(offset, length)?, length);
+		let next = offset + 1 + length;
+		println!("{:<16} {jump:+} -> {}", format!("{:?}", opcode), next as isize + jump);
+
+		Some(next)
+	}
+
+	/// Disassembles a small int, which is stored in its operand as a signed byte
+	fn small_int_instruction(chunk: &Chunk, opcode: Opcode, offset: usize) -> Option<usize> {
+		let value = chunk.operand(offset, 1)? as u8 as i8;
 		println!("{:<16} {value}", format!("{:?}", opcode));
 
-		offset + 1 + length
+		Some(offset + 2)
+	}
+
+	/// Disassembles a native call, which has the native index and the argument count as operands
+	fn native_instruction(chunk: &Chunk, opcode: Opcode, offset: usize) -> Option<usize> {
+		let native = NATIVES.get(chunk.operand(offset, 1)?).map_or("unknown", |native| native.name);
+		let args = chunk.operand(offset + 1, 1)?;
+		println!("{:<16} {native} ({args} args)", format!("{:?}", opcode));
+
+		Some(offset + 3)
+	}
+
+	/// Disassembles a struct construction, which has the struct index followed by the offset of each field in the order they are pushed
+	fn struct_instruction(chunk: &Chunk, opcode: Opcode, offset: usize) -> Option<usize> {
+		let layout = chunk.structs.get(chunk.operand(offset, 1)?)?;
+		let fields = (0..layout.fields.len())
+			.map(|index| layout.fields.get(chunk.operand(offset + 1 + index, 1)?)?.as_str())
+			.collect::<Option<Vec<_>>>()?;
+		println!("{:<16} {} {{ {} }}", format!("{:?}", opcode), layout.name, fields.join(", "));
+
+		Some(offset + 2 + layout.fields.len())
 	}
 
 	// Log the byte number
 	trace!(target: "Disassembly", "{:0>4} ", offset);
 
-	let line = chunk.lines[offset];
+	let line = chunk.line_at(offset);
 	// Log the line number or "|" if it is the same as the last instruction
-	if offset != 0 && chunk.lines[offset - 1] == line {
+	if offset != 0 && chunk.line_at(offset - 1) == line {
 		print!("     | ");
 	} else {
 		print!("{:>6} ", line.to_string());
@@ -127,21 +270,35 @@ pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
 	let opcode_id = chunk[offset];
 	let opcode = opcode_id.into();
 	// Log the rest of the instructionbased on the opcode
-	match opcode {
+	let next = match opcode {
 		Opcode::Unknown => {
-			warn!("Unknown instruction {opcode_id}");
-			offset + 1
+			// The following bytes can't be decoded reliably, so stop disassembling
+			warn!("Unknown instruction {opcode_id}, stopping disassembly");
+			return chunk.len();
 		}
 
-		Opcode::Constant | Opcode::DefineGlobalVariable | Opcode::GetGlobalVariable | Opcode::SetGlobal => constant_instruction(chunk, opcode, offset),
-		Opcode::LongConstant | Opcode::DefineLongGlobalVariable | Opcode::GetLongGlobalVariable | Opcode::SetLongGlobal => long_constant_instruction(chunk, opcode, offset),
+		Opcode::Constant | Opcode::DefineGlobalVariable | Opcode::GetGlobalVariable | Opcode::SetGlobal | Opcode::Debug | Opcode::GetProperty | Opcode::SetProperty => {
+			constant_instruction(chunk, opcode, offset, 1)
+		}
+		Opcode::LongConstant | Opcode::DefineLongGlobalVariable | Opcode::GetLongGlobalVariable | Opcode::SetLongGlobal | Opcode::LongDebug | Opcode::GetLongProperty | Opcode::SetLongProperty => {
+			constant_instruction(chunk, opcode, offset, 3)
+		}
 
-		Opcode::GetLocal | Opcode::SetLocal => value_instruction(chunk, opcode, offset, 1),
+		Opcode::GetLocal | Opcode::SetLocal | Opcode::IsType | Opcode::IsStruct | Opcode::MakeArray => value_instruction(chunk, opcode, offset, 1),
 		Opcode::GetLongLocal | Opcode::SetLongLocal => value_instruction(chunk, opcode, offset, 3),
-		Opcode::Jump | Opcode::JumpIfFalse | Opcode::JumpBack => value_instruction(chunk, opcode, offset, 2),
+		_ if let Some(length) = opcode.jump_length() => jump_instruction(chunk, opcode, offset, length),
+
+		Opcode::LoadSmallInt => small_int_instruction(chunk, opcode, offset),
+		Opcode::CallNative => native_instruction(chunk, opcode, offset),
+		Opcode::MakeStruct => struct_instruction(chunk, opcode, offset),
 
 		_ => simple_instruction(opcode, offset),
-	}
+	};
+	// The operands are missing or refer to nothing, so the rest of the chunk can't be trusted either
+	next.unwrap_or_else(|| {
+		warn!("Truncated or malformed {:?} instruction at {offset}, stopping disassembly", Opcode::from(opcode_id));
+		chunk.len()
+	})
 }
 
 #[test]
@@ -149,3 +306,23 @@ fn opcode() {
 	init_logger();
 	info!("{}", core::mem::size_of::<Opcode>());
 }
+
+#[test]
+#[cfg(feature = "trace_execution")]
+fn disassemble_truncated() {
+	init_logger();
+	let line = Line::new(1, 1);
+	let mut chunk = Chunk::new();
+	chunk.push(Opcode::Constant, line);
+	assert_eq!(disassemble_instruction(&chunk, 0), chunk.len());
+
+	let mut chunk = Chunk::new();
+	chunk.push(Opcode::Jump, line);
+	chunk.push(0, line);
+	assert_eq!(disassemble_instruction(&chunk, 0), chunk.len());
+
+	let mut chunk = Chunk::new();
+	chunk.push(Opcode::CallNative, line);
+	chunk.push(0, line);
+	assert_eq!(disassemble_instruction(&chunk, 0), chunk.len());
+}
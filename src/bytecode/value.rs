@@ -0,0 +1,287 @@
+//! The values that the runtime works with.
+//!
+//! A [Value] is stored either as the [ValueKind] enum itself (the default), or with the `nan_boxing` feature packed into 8 bytes where numbers are raw floats and everything else is tagged in the unused quiet NaN space.
+//! Code outside this module constructs values with [`Value::number`] and friends and matches on [`Value::kind`], so it works with either representation.
+
+use crate::bytecode::prelude::*;
+
+/// What a [Value] holds, which is matched on to handle each type
+#[derive(Clone, Copy, Debug)]
+pub enum ValueKind {
+	Number(f64),
+	/// An integer, produced by number literals without a decimal point, which stays exact under `+`, `-`, `*` and `%`
+	Int(i64),
+	Bool(bool),
+	Null,
+	Obj(ObjRef),
+}
+
+/// A value stored as the [ValueKind] enum, which is the reference representation
+#[cfg(not(feature = "nan_boxing"))]
+#[derive(Clone, Copy)]
+pub struct Value(ValueKind);
+
+#[cfg(not(feature = "nan_boxing"))]
+impl Value {
+	pub const NULL: Self = Self(ValueKind::Null);
+	/// The smallest int that can be stored
+	pub const MIN_INT: i64 = i64::MIN;
+	/// The largest int that can be stored
+	pub const MAX_INT: i64 = i64::MAX;
+
+	#[inline]
+	pub const fn number(number: f64) -> Self {
+		Self(ValueKind::Number(number))
+	}
+	/// An int, which must be in the range [`Value::MIN_INT`] to [`Value::MAX_INT`]
+	#[inline]
+	pub const fn int(int: i64) -> Self {
+		Self(ValueKind::Int(int))
+	}
+	#[inline]
+	pub const fn bool(value: bool) -> Self {
+		Self(ValueKind::Bool(value))
+	}
+	#[inline]
+	pub const fn obj(obj: ObjRef) -> Self {
+		Self(ValueKind::Obj(obj))
+	}
+	/// Unpacks the value so it can be matched on
+	#[inline]
+	pub const fn kind(self) -> ValueKind {
+		self.0
+	}
+}
+
+/// A value packed into the bits of an `f64`.
+///
+/// Numbers are stored as themselves, with every NaN replaced by [`Value::CANONICAL_NAN`]. The rest of the quiet NaNs hold the other kinds:
+/// - with the sign bit set, an int in the low 51 bits (so ints are limited to [`Value::MIN_INT`] to [`Value::MAX_INT`])
+/// - otherwise, a tag in bits 48 to 50 followed by the 48 bit address for objects
+#[cfg(feature = "nan_boxing")]
+#[derive(Clone, Copy)]
+pub struct Value(u64);
+
+#[cfg(feature = "nan_boxing")]
+impl Value {
+	/// The exponent and quiet bit, which are set for every value that is not a number
+	const QNAN: u64 = 0x7FF8_0000_0000_0000;
+	/// The only NaN that a number can be, which is also the NaN produced by arithmetic on most platforms
+	const CANONICAL_NAN: u64 = Self::QNAN;
+	const SIGN: u64 = 1 << 63;
+	const PAYLOAD: u64 = (1 << 48) - 1;
+	const INT_BITS: u32 = 51;
+	const TAG_SHIFT: u32 = 48;
+	const TAG_NULL: u64 = 1;
+	const TAG_FALSE: u64 = 2;
+	const TAG_TRUE: u64 = 3;
+	const TAG_OBJ: u64 = 4;
+
+	pub const NULL: Self = Self(Self::QNAN | Self::TAG_NULL << Self::TAG_SHIFT);
+	/// The smallest int that can be stored
+	pub const MIN_INT: i64 = -(1 << (Self::INT_BITS - 1));
+	/// The largest int that can be stored
+	pub const MAX_INT: i64 = (1 << (Self::INT_BITS - 1)) - 1;
+
+	#[inline]
+	pub fn number(number: f64) -> Self {
+		Self(if number.is_nan() { Self::CANONICAL_NAN } else { number.to_bits() })
+	}
+	/// An int, which must be in the range [`Value::MIN_INT`] to [`Value::MAX_INT`]
+	#[inline]
+	pub fn int(int: i64) -> Self {
+		assert!((Self::MIN_INT..=Self::MAX_INT).contains(&int), "{int} is out of the range of ints");
+		Self(Self::SIGN | Self::QNAN | (int as u64 & ((1 << Self::INT_BITS) - 1)))
+	}
+	#[inline]
+	pub const fn bool(value: bool) -> Self {
+		Self(Self::QNAN | (if value { Self::TAG_TRUE } else { Self::TAG_FALSE }) << Self::TAG_SHIFT)
+	}
+	#[inline]
+	pub fn obj(obj: ObjRef) -> Self {
+		let addr = obj.expose_addr() as u64;
+		debug_assert!(addr & !Self::PAYLOAD == 0, "object address {addr:#x} does not fit in 48 bits");
+		Self(Self::QNAN | Self::TAG_OBJ << Self::TAG_SHIFT | addr)
+	}
+	/// Unpacks the value so it can be matched on
+	#[inline]
+	pub fn kind(self) -> ValueKind {
+		if self.0 & Self::QNAN != Self::QNAN || self.0 == Self::CANONICAL_NAN {
+			ValueKind::Number(f64::from_bits(self.0))
+		} else if self.0 & Self::SIGN != 0 {
+			// Shifting the int to the top and back extends its sign
+			ValueKind::Int(((self.0 << (64 - Self::INT_BITS)) as i64) >> (64 - Self::INT_BITS))
+		} else {
+			match (self.0 >> Self::TAG_SHIFT) & 0b111 {
+				Self::TAG_NULL => ValueKind::Null,
+				Self::TAG_FALSE => ValueKind::Bool(false),
+				Self::TAG_TRUE => ValueKind::Bool(true),
+				// SAFETY: only `Value::obj` makes this tag, from the address of a live object
+				Self::TAG_OBJ => ValueKind::Obj(unsafe { ObjRef::from_exposed_addr((self.0 & Self::PAYLOAD) as usize) }),
+				tag => unreachable!("invalid value tag {tag}"),
+			}
+		}
+	}
+}
+
+impl Value {
+	/// An int, or `None` if it is outside of the range that can be stored
+	#[inline]
+	pub fn try_int(int: i64) -> Option<Self> {
+		(Self::MIN_INT..=Self::MAX_INT).contains(&int).then(|| Self::int(int))
+	}
+
+	/// Checks if the value counts as true in a condition: `null` and `false` are falsy, everything else (including `0` and `""`) is truthy
+	pub fn truthy(&self) -> bool {
+		!matches!(self.kind(), ValueKind::Null | ValueKind::Bool(false))
+	}
+
+	/// The name of the value's type for error messages, where ints are numbers as they are to the user
+	pub fn type_name(&self) -> &'static str {
+		match self.kind() {
+			ValueKind::Number(_) | ValueKind::Int(_) => "number",
+			ValueKind::Bool(_) => "bool",
+			ValueKind::Null => "null",
+			ValueKind::Obj(obj) => match obj.object_ty() {
+				ObjTy::Str => "string",
+				ObjTy::Struct => "struct",
+				ObjTy::Array => "array",
+			},
+		}
+	}
+
+	/// Checks if the value is an int or a number, as `is Number` does
+	#[inline]
+	pub fn is_number(&self) -> bool {
+		matches!(self.kind(), ValueKind::Number(_) | ValueKind::Int(_))
+	}
+
+	/// Gets the value of a number, which is `None` for ints (see [`Value::as_f64`])
+	#[inline]
+	pub fn as_number(&self) -> Option<f64> {
+		match self.kind() {
+			ValueKind::Number(number) => Some(number),
+			_ => None,
+		}
+	}
+
+	#[inline]
+	pub fn as_int(&self) -> Option<i64> {
+		match self.kind() {
+			ValueKind::Int(int) => Some(int),
+			_ => None,
+		}
+	}
+
+	#[inline]
+	pub fn as_bool(&self) -> Option<bool> {
+		match self.kind() {
+			ValueKind::Bool(value) => Some(value),
+			_ => None,
+		}
+	}
+
+	#[inline]
+	pub fn as_obj(&self) -> Option<ObjRef> {
+		match self.kind() {
+			ValueKind::Obj(obj) => Some(obj),
+			_ => None,
+		}
+	}
+
+	/// Converts an int or a number to a float, which may round ints larger than 2^53
+	#[inline]
+	pub fn as_f64(&self) -> Option<f64> {
+		match self.kind() {
+			ValueKind::Number(number) => Some(number),
+			ValueKind::Int(int) => Some(int as f64),
+			_ => None,
+		}
+	}
+}
+
+impl From<ValueKind> for Value {
+	/// Stores the value, where an int must be in the range [`Value::MIN_INT`] to [`Value::MAX_INT`]
+	fn from(kind: ValueKind) -> Self {
+		match kind {
+			ValueKind::Number(number) => Self::number(number),
+			ValueKind::Int(int) => Self::int(int),
+			ValueKind::Bool(value) => Self::bool(value),
+			ValueKind::Null => Self::NULL,
+			ValueKind::Obj(obj) => Self::obj(obj),
+		}
+	}
+}
+
+impl core::fmt::Debug for Value {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		core::fmt::Display::fmt(self, f)
+	}
+}
+
+/// How values are shown to the user, with strings written without quotes
+impl core::fmt::Display for Value {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self.kind() {
+			ValueKind::Number(n) => write!(f, "{}", n),
+			ValueKind::Int(n) => write!(f, "{}", n),
+			ValueKind::Bool(v) => write!(f, "{}", v),
+			ValueKind::Null => write!(f, "null"),
+			ValueKind::Obj(s) => write!(f, "{:?}", s),
+		}
+	}
+}
+
+impl PartialEq for Value {
+	fn eq(&self, other: &Self) -> bool {
+		match (self.kind(), other.kind()) {
+			(ValueKind::Number(l0), ValueKind::Number(r0)) => l0 == r0,
+			(ValueKind::Int(l0), ValueKind::Int(r0)) => l0 == r0,
+			// Ints are promoted to numbers, so `1 == 1.0`
+			(ValueKind::Int(l0), ValueKind::Number(r0)) | (ValueKind::Number(r0), ValueKind::Int(l0)) => l0 as f64 == r0,
+			(ValueKind::Bool(l0), ValueKind::Bool(r0)) => l0 == r0,
+			(ValueKind::Obj(l0), ValueKind::Obj(r0)) => {
+				l0.object_ty() == r0.object_ty()
+					&& match l0.object_ty() {
+						ObjTy::Str => l0 == r0,
+						// Structs and arrays are only equal if they are the same instance
+						ObjTy::Struct | ObjTy::Array => l0 == r0,
+					}
+			}
+			(ValueKind::Null, ValueKind::Null) => true,
+			_ => false,
+		}
+	}
+}
+
+#[test]
+fn value_round_trip() {
+	let (string, owned) = ObjRef::new("hello".to_string());
+	let ints = [0, 1, -1, 42, Value::MIN_INT, Value::MAX_INT, Value::MIN_INT + 1, Value::MAX_INT - 1];
+	for int in ints {
+		assert!(matches!(Value::int(int).kind(), ValueKind::Int(value) if value == int), "{int}");
+	}
+	let numbers = [0., -0., 1.5, -2.25, f64::MAX, f64::MIN_POSITIVE, f64::INFINITY, f64::NEG_INFINITY];
+	for number in numbers {
+		assert!(matches!(Value::number(number).kind(), ValueKind::Number(value) if value.to_bits() == number.to_bits()), "{number}");
+	}
+	// Every NaN is still a number, whatever its sign or payload
+	for nan in [f64::NAN, -f64::NAN, f64::from_bits(0x7FF0_0000_0000_0001), f64::from_bits(0xFFFF_FFFF_FFFF_FFFF)] {
+		assert!(matches!(Value::number(nan).kind(), ValueKind::Number(value) if value.is_nan()));
+	}
+	assert!(matches!(Value::bool(true).kind(), ValueKind::Bool(true)));
+	assert!(matches!(Value::bool(false).kind(), ValueKind::Bool(false)));
+	assert!(matches!(Value::NULL.kind(), ValueKind::Null));
+	assert_eq!(Value::obj(string).as_obj(), Some(string));
+	assert_eq!(Value::obj(string).as_obj().unwrap().as_str(), Some("hello"));
+
+	assert_eq!(Value::try_int(Value::MAX_INT).and_then(|value| value.as_int()), Some(Value::MAX_INT));
+	assert!(Value::MAX_INT.checked_add(1).and_then(Value::try_int).is_none());
+	drop(owned);
+}
+
+#[test]
+fn value_size() {
+	let expected = if cfg!(feature = "nan_boxing") { 8 } else { 16 };
+	assert_eq!(core::mem::size_of::<Value>(), expected);
+}
@@ -1,97 +1,356 @@
-use std::{collections::hash_map::Entry, fmt::Arguments};
+use std::{collections::hash_map::Entry, fmt::Arguments, io::Write};
 
 use ahash::{AHashMap, AHashSet};
 
 use crate::bytecode::prelude::*;
 
+pub mod natives;
+
+/// Reports a runtime error with the current line, then returns an [`InterpretError`] from the enclosing function
 macro_rules! runtime_error {
 	($runtime:ident, $($arg:tt)+) => {
 		{
-			let line = unsafe{$runtime.chunk.as_ref().unwrap()}.lines[$runtime.offset()];
-			error!(target: "nonew", $($arg)+);
-			println!(" [line {line}] in script");
+			let line = $runtime.current_line();
+			let message = format!($($arg)+);
+			error!(target: "nonew", "{message}");
+			$runtime.last_error = Some(message);
+			// Finishes the line of the error logged above, which is left open by the `nonew` target
+			let _ = writeln!($runtime.output, " [line {line}] in script");
 			$runtime.reset_stack();
+			return Err(InterpretError::InterpretError);
 		}
 	};
 }
 
-/// The interpeter's runtime, containing the current [Chunk], a pointer to the next instruction and the stack
+/// The interpeter's runtime, containing the current [Chunk], the offset of the next instruction and the stack
 pub struct Runtime {
 	/// The [`Chunk`] that is being interpreted
-	pub chunk: *const Chunk,
-	/// The instruction pointer, pointing to the next instruction
-	ip: *const u8,
+	chunk: Chunk,
+	/// Chunks that are suspended while a nested chunk from `eval` runs, whose constants must be kept alive
+	outer_chunks: Vec<Chunk>,
+	/// The instruction pointer, the offset of the next byte of bytecode to read
+	ip: usize,
+	/// Offset of the opcode of the instruction being executed, so errors report its line even after the operands are read
+	instruction: usize,
 
 	/// The stack of values that can be pushed to and popped from
 	stack: Vec<Value>,
 	/// Pointer to the top of the stack (leading to slightly better performance)
 	stack_top: *mut Value,
+	/// The stack height when the running chunk started, which local slots are counted from so that the locals of `eval`'d code are above those of its caller
+	frame_base: usize,
+	/// The maximum number of values on the stack before a stack overflow error
+	max_stack: usize,
+	/// The number of instructions each interpret may execute (including those of `eval`) before a runtime error
+	instruction_limit: u64,
+	/// The number of instructions the current interpret has left, refilled by [`Runtime::reset`]
+	instructions_left: u64,
+	/// The longest that a single `sleep` may last before it is a runtime error
+	max_sleep: std::time::Duration,
 	/// All the heap objects need to be stored so they can be deleted by garbage collection
-	objects: Vec<Box<ObjTy>>,
+	objects: Vec<OwnedObj>,
+	/// The number of bytes used by the heap objects
+	bytes_allocated: usize,
 	/// A hash table of all strings (to reduce memory usage and comparison times)
-	strings: AHashSet<ObjRef>,
-	/// Hash set of global variables
-	globals: AHashMap<String, Value>,
+	///
+	/// The entries are weak: the table does not keep strings alive, and the garbage collector removes strings from it before they are freed.
+	strings: StringTable,
+	/// Hash set of global variables, keyed by the interned string of their name so a lookup only hashes a pointer
+	///
+	/// The names are roots for the garbage collector, since the chunk that defined a global may already have been dropped.
+	globals: AHashMap<ObjRef, Value>,
+	/// Whether dividing by zero gives infinity or NaN as in IEEE 754, rather than a runtime error
+	ieee_division: bool,
+	/// Whether natives such as `read_file` are allowed to access the file system
+	fs_access: bool,
+	/// Whether defining a global that already exists replaces it, as is wanted in the REPL, rather than raising a runtime error
+	global_redefinition: bool,
+	/// Where the output of `print` is written (stdout by default)
+	output: Box<dyn Write>,
+	/// Environment variables set by `set_env`, which `env` reads before those of the process
+	///
+	/// The process environment itself is never changed, since other threads (of the embedder, or other tests) may be reading it.
+	env: AHashMap<String, String>,
+	/// The message of the most recent runtime error, see [`Runtime::last_error`]
+	last_error: Option<String>,
+	/// The code of an `exit` inside `eval`, which the call to `eval` passes on as an exit of the script that called it
+	eval_exit: Option<i32>,
+	/// When the runtime was created, used by the `clock` native
+	start_time: std::time::Instant,
+	/// The random number generator used by the `random` natives
+	rng: natives::Rng,
+	/// Regex patterns that have already been compiled by natives
+	#[cfg(feature = "regex")]
+	regexes: AHashMap<String, regex::Regex>,
+}
+
+/// Applies an arithmetic or comparison operator to two numbers.
+///
+/// Division by zero follows IEEE 754 here, it is the [Runtime] that raises an error for it unless [`Runtime::with_ieee_division`] is enabled.
+///
+/// Two ints give an exact int, where overflow (past [`Value::MAX_INT`]) is an error rather than wrapping or promoting. The exception is `/`, which always gives a number (so `7 / 2` is `3.5`), and `%` by zero which gives NaN like it does for numbers.
+/// If either operand is a number, the other is promoted to a number.
+///
+/// `%` is the truncated remainder (like Rust and C), which has the sign of the dividend so `-7 % 3` is `-1`; `mod` gives the floored remainder.
+pub fn arithmetic(opcode: Opcode, a: Value, b: Value) -> Result<Value, String> {
+	if let (Some(a), Some(b)) = (a.as_int(), b.as_int()) {
+		let (result, symbol) = match opcode {
+			Opcode::Add => (a.checked_add(b), "+"),
+			Opcode::Subtract => (a.checked_sub(b), "-"),
+			Opcode::Multiply => (a.checked_mul(b), "*"),
+			Opcode::Modulo if b != 0 => (a.checked_rem(b), "%"),
+			Opcode::Greater => return Ok(Value::bool(a > b)),
+			Opcode::Less => return Ok(Value::bool(a < b)),
+			Opcode::GreaterEqual => return Ok(Value::bool(a >= b)),
+			Opcode::LessEqual => return Ok(Value::bool(a <= b)),
+			_ => return arithmetic(opcode, Value::number(a as f64), Value::number(b as f64)),
+		};
+		return result.and_then(Value::try_int).ok_or_else(|| format!("Integer overflow in {a} {symbol} {b}"));
+	}
+	let (Some(a), Some(b)) = (a.as_f64(), b.as_f64()) else {
+		let verb = match opcode {
+			Opcode::Add => "add",
+			Opcode::Subtract => "subtract",
+			Opcode::Multiply => "multiply",
+			Opcode::Divide => "divide",
+			Opcode::Modulo => "take the remainder of",
+			_ => "compare",
+		};
+		return Err(format!("Cannot {verb} {} and {}", a.type_name(), b.type_name()));
+	};
+	Ok(match opcode {
+		Opcode::Add => Value::number(a + b),
+		Opcode::Subtract => Value::number(a - b),
+		Opcode::Multiply => Value::number(a * b),
+		Opcode::Divide => Value::number(a / b),
+		Opcode::Modulo => Value::number(a % b),
+		Opcode::Greater => Value::bool(a > b),
+		Opcode::Less => Value::bool(a < b),
+		Opcode::GreaterEqual => Value::bool(a >= b),
+		Opcode::LessEqual => Value::bool(a <= b),
+		_ => unreachable!("{opcode:?} is not an arithmetic operator"),
+	})
+}
+
+/// A snapshot of the memory used by a [Runtime], see [`Runtime::mem_stats`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemStats {
+	/// Live heap objects
+	pub objects: usize,
+	/// Interned strings
+	pub strings: usize,
+	/// Defined global variables
+	pub globals: usize,
+	/// Values currently on the stack
+	pub stack: usize,
+	/// Bytes used by the heap objects
+	pub bytes: usize,
+}
+
+impl core::fmt::Display for MemStats {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		let Self {
+			objects,
+			strings,
+			globals,
+			stack,
+			bytes,
+		} = self;
+		write!(f, "objects: {objects}, strings: {strings}, globals: {globals}, stack: {stack}, bytes: {bytes}")
+	}
 }
 
-impl<'source> Runtime {
+impl Runtime {
 	/// Construct a new runtime with the specified [Chunk]
-	pub fn new(chunk: &Chunk) -> Self {
+	///
+	/// The chunk's strings are interned in the runtime, as the globals are found by the identity of their name.
+	pub fn new(chunk: Chunk) -> Self {
 		let mut stack = Vec::with_capacity(5);
-		Self {
-			chunk,
-			ip: chunk.as_ptr(),
+		let mut runtime = Self {
+			ip: 0,
+			instruction: 0,
+			chunk: Chunk::new(),
+			outer_chunks: Vec::new(),
 			stack_top: stack.as_mut_ptr(),
+			frame_base: 0,
 			stack,
+			max_stack: 1 << 16,
+			instruction_limit: u64::MAX,
+			instructions_left: u64::MAX,
+			max_sleep: std::time::Duration::MAX,
 			objects: Vec::new(),
-			strings: AHashSet::new(),
+			bytes_allocated: 0,
+			strings: StringTable::default(),
 			globals: AHashMap::new(),
-		}
+			output: Box::new(std::io::stdout()),
+			env: AHashMap::new(),
+			last_error: None,
+			eval_exit: None,
+			fs_access: false,
+			global_redefinition: false,
+			ieee_division: false,
+			start_time: std::time::Instant::now(),
+			rng: natives::Rng::from_entropy(),
+			#[cfg(feature = "regex")]
+			regexes: AHashMap::new(),
+		};
+		runtime.reset(chunk);
+		runtime
+	}
+
+	/// Allow or deny scripts access to the file system (denied by default so embedders can sandbox scripts)
+	pub fn with_fs_access(mut self, allowed: bool) -> Self {
+		self.fs_access = allowed;
+		self
+	}
+
+	/// Let `let` replace a global that is already defined instead of raising a runtime error, so code can be re-entered interactively
+	pub fn with_global_redefinition(mut self, allowed: bool) -> Self {
+		self.global_redefinition = allowed;
+		self
+	}
+
+	/// Limit the number of values on the stack, above which a stack overflow error is raised
+	pub fn with_max_stack(mut self, max_stack: usize) -> Self {
+		self.max_stack = max_stack;
+		self
+	}
+
+	/// Limit the number of instructions each interpret may execute, so that scripts which loop forever end in a runtime error
+	pub fn with_instruction_limit(mut self, limit: u64) -> Self {
+		self.instruction_limit = limit;
+		self.instructions_left = limit;
+		self
+	}
+
+	/// Limit how long a single call to `sleep` may last, with longer sleeps raising a runtime error
+	pub fn with_max_sleep(mut self, max_sleep: std::time::Duration) -> Self {
+		self.max_sleep = max_sleep;
+		self
+	}
+
+	/// Make `/`, `%` and `mod` by zero give infinity or NaN (as in IEEE 754) instead of raising a runtime error
+	pub fn with_ieee_division(mut self, enabled: bool) -> Self {
+		self.ieee_division = enabled;
+		self
+	}
+
+	/// Write the output of `print` to the specified writer instead of stdout
+	pub fn with_output(mut self, output: impl Write + 'static) -> Self {
+		self.set_output(output);
+		self
+	}
+
+	/// Write the output of `print` to the specified writer from now on, such as between interprets
+	pub fn set_output(&mut self, output: impl Write + 'static) {
+		self.output = Box::new(output);
 	}
 
-	/// Reset Runtime and load new chunk
-	pub fn reset(&mut self, chunk: &Chunk) {
-		self.chunk = chunk;
-		self.ip = chunk.as_ptr();
+	/// Reset the stack and load a new chunk
+	///
+	/// The heap objects (and the globals referencing them) are kept, so the chunk's objects are moved into the runtime's heap where they can outlive it.
+	pub fn reset(&mut self, mut chunk: Chunk) {
+		self.adopt_objects(&mut chunk);
+		self.load_chunk(chunk);
 		self.reset_stack();
-		self.free_objects();
-		self.strings.clear();
+		self.instructions_left = self.instruction_limit;
+	}
+
+	/// Moves the objects of a chunk into the runtime, since values such as globals may still reference them after the chunk is dropped
+	///
+	/// The chunk's strings are interned, with any that are already interned replaced by the runtime's object so that equal strings compare equal.
+	pub(crate) fn adopt_objects(&mut self, chunk: &mut Chunk) {
+		let mut duplicates = AHashSet::new();
+		chunk.intern_strings(|string| match self.strings.find(string.as_ref_unchecked::<String>()) {
+			Some(existing) => {
+				duplicates.insert(string.addr());
+				existing
+			}
+			None => {
+				self.strings.insert(string);
+				string
+			}
+		});
+		// The duplicates are freed as they are dropped
+		for obj in core::mem::take(&mut chunk.objects) {
+			if !duplicates.contains(&obj.handle().addr()) {
+				self.bytes_allocated += obj.size();
+				self.objects.push(obj);
+			}
+		}
+	}
+
+	/// The [`Chunk`] that is being interpreted
+	pub fn chunk(&self) -> &Chunk {
+		&self.chunk
+	}
+
+	/// Replace the chunk being interpreted, starting at its first instruction and returning the old chunk
+	///
+	/// The instruction offsets are not reset when the old chunk is loaded again, so they must be saved and restored by the caller.
+	pub(crate) fn load_chunk(&mut self, chunk: Chunk) -> Chunk {
+		self.ip = 0;
+		self.instruction = 0;
+		std::mem::replace(&mut self.chunk, chunk)
 	}
 
 	/// Clear the stack and reset the stack top
 	pub fn reset_stack(&mut self) {
 		self.stack_top = self.stack.as_mut_ptr();
+		self.frame_base = 0;
 	}
 
 	/// Allocates a new string object, using string interning for cheaper comparsions
 	///
 	/// Note: strings are immutable
 	pub fn new_string(&mut self, val: String) -> ObjRef {
-		self.strings.iter().copied().find(|existing_str| existing_str.as_ref_unchecked::<String>() == &val).unwrap_or_else(|| {
+		self.stress_gc();
+		self.strings.find(&val).unwrap_or_else(|| {
 			let (obj_ref, owned) = ObjRef::new(val);
+			self.bytes_allocated += owned.size();
 			self.objects.push(owned);
 			self.strings.insert(obj_ref);
 			obj_ref
 		})
 	}
 
-	/// Read a byte of bytecode and move to the next one
+	/// With the `gc_stress` feature, collects before every allocation so any object that is not yet rooted (on the stack, in a global or in a chunk's constants) is freed straight away, exposing the bug.
+	///
+	/// This makes allocation-heavy scripts orders of magnitude slower, so it must never be enabled for benchmarks.
 	#[inline]
-	pub fn read_byte(&mut self) -> u8 {
-		unsafe {
-			let result = *self.ip;
-			self.ip = self.ip.offset(1);
-			result
-		}
+	fn stress_gc(&mut self) {
+		#[cfg(feature = "gc_stress")]
+		self.collect_garbage();
+	}
+
+	/// Allocates a heap object that is not interned, storing it so it can be garbage collected
+	pub fn new_object(&mut self, val: impl ObjKind) -> ObjRef {
+		self.stress_gc();
+		let (obj_ref, owned) = ObjRef::new(val);
+		self.bytes_allocated += owned.size();
+		self.objects.push(owned);
+		obj_ref
 	}
 
-	pub fn read_bytes(&mut self, n: u32) -> usize {
+	/// Read a byte of bytecode and move to the next one, raising a runtime error rather than reading past the end of a malformed chunk
+	///
+	/// The check before each instruction doesn't catch an operand that is cut off by the end of the chunk.
+	#[inline]
+	pub fn read_byte(&mut self) -> Result<u8, InterpretError> {
+		let Some(&byte) = self.chunk.code.get(self.ip) else {
+			runtime_error!(self, "ip out of bounds at offset {}", self.offset());
+		};
+		self.ip += 1;
+		Ok(byte)
+	}
+
+	pub fn read_bytes(&mut self, n: u32) -> Result<usize, InterpretError> {
 		let mut value = 0;
 		for i in 0..n {
 			value <<= 8;
-			value ^= self.read_byte() as usize;
+			value ^= self.read_byte()? as usize;
 		}
-		value
+		Ok(value)
 	}
 
 	// /// View all future bytecode
@@ -113,57 +372,131 @@ impl<'source> Runtime {
 
 	/// Read a short constant from the [Chunk].
 	#[inline]
-	pub fn short_constant<'s, 'v: 's>(&'s mut self) -> &'v Value {
-		unsafe { self.chunk.as_ref().unwrap().constant(self.read_byte() as usize) }
+	pub fn short_constant(&mut self) -> Result<Value, InterpretError> {
+		let index = self.read_byte()? as usize;
+		Ok(*self.chunk.constant(index))
 	}
 
 	/// Read a long constant from the [Chunk].
 	#[inline]
-	pub fn long_constant<'s, 'v: 's>(&'s mut self) -> &'v Value {
-		unsafe { self.chunk.as_ref().unwrap() }.constant(self.read_bytes(3))
+	pub fn long_constant(&mut self) -> Result<Value, InterpretError> {
+		let index = self.read_bytes(3)?;
+		Ok(*self.chunk.constant(index))
+	}
+
+	/// Read the signed offset of a jump instruction, which is four bytes for the long forms and two otherwise
+	#[inline]
+	fn jump_offset(&mut self, opcode: Opcode) -> Result<isize, InterpretError> {
+		let length = if opcode.jump_length() == Some(4) { 4 } else { 2 };
+		Ok(jump_offset(self.read_bytes(length as u32)?, length))
+	}
+
+	/// Moves the instruction pointer by the offset of a jump, which is relative to the end of the jump instruction
+	#[inline]
+	fn jump(&mut self, offset: isize) {
+		// Jumping before the start wraps around, so it is caught by the bounds check on the next instruction
+		self.ip = self.ip.wrapping_add_signed(offset);
 	}
 
 	/// Find the current offset (in bytes) from the start of the chunk to the instruction pointer
-	#[cfg(feature = "trace_execution")]
 	fn offset(&self) -> usize {
-		(unsafe { self.ip.offset_from((&*self.chunk).as_ptr()) }) as usize
+		self.ip
+	}
+
+	/// The source line of the instruction that is being executed
+	pub fn current_line(&self) -> Line {
+		self.chunk.line_at(self.instruction)
+	}
+
+	/// The message of the most recent runtime error, which is kept until the next error replaces it
+	pub fn last_error(&self) -> Option<&str> {
+		self.last_error.as_deref()
 	}
 
-	/// Push an item to the top of the stack
+	/// Push an item to the top of the stack, growing the stack if it is full and erroring if it has reached the maximum size
 	#[inline]
-	pub fn push_stack(&mut self, value: Value) {
+	pub fn push_stack(&mut self, value: Value) -> Result<(), InterpretError> {
 		unsafe {
-			// Update stack size
-			self.stack.set_len(self.stack.as_ptr().offset_from(self.stack_top) as usize);
-			*self.stack_top = value;
-			self.stack_top = self.stack_top.offset(1);
+			let len = self.stack_height();
+			if len == self.stack.capacity() {
+				if len >= self.max_stack {
+					runtime_error!(self, "Stack overflow (more than {} values)", self.max_stack);
+				}
+				// Every slot below the top has been written, so the length can be set for the values to be kept when the stack is moved
+				self.stack.set_len(len);
+				self.stack.reserve_exact(len.min(self.max_stack - len));
+				self.stack_top = self.stack.as_mut_ptr().add(len);
+			}
+			self.stack_top.write(value);
+			self.stack_top = self.stack_top.add(1);
 		}
+		Ok(())
 	}
-	pub fn set_stack(&mut self, index: usize, value: Value) {
-		unsafe { *self.stack.as_mut_ptr().add(index) = value }
+	/// The number of values on the stack
+	#[inline]
+	fn stack_height(&self) -> usize {
+		unsafe { self.stack_top.offset_from(self.stack.as_ptr()) as usize }
 	}
-	/// Pops an item from the top of the stack, returning it
+	/// Overwrites the value in a local slot counted from the frame base, erroring if the slot is not below the top
 	#[inline]
-	pub fn pop_stack(&mut self) -> Result<&'source Value, InterpretError> {
+	pub fn set_stack(&mut self, index: usize, value: Value) -> Result<(), InterpretError> {
+		let height = self.stack_height();
+		if self.frame_base + index >= height {
+			runtime_error!(self, "Invalid local slot {index}, stack height {height}");
+		}
+		unsafe { *self.stack.as_mut_ptr().add(self.frame_base + index) = value }
+		Ok(())
+	}
+	/// Pops an item from the top of the stack, returning it by value so it can't outlive the slot being reused
+	#[inline]
+	pub fn pop_stack(&mut self) -> Result<Value, InterpretError> {
 		if self.stack_top == self.stack.as_mut_ptr() {
 			error!("Stack underflow");
 			return Err(InterpretError::InterpretError);
 		}
 		unsafe {
 			self.stack_top = self.stack_top.offset(-1);
-			Ok(&*self.stack_top)
+			Ok(*self.stack_top)
 		}
 	}
 
-	/// Peeks at an item a certain distance from the top of the stack
+	/// Copies the item a certain distance from the top of the stack, erroring rather than reading below the stack if it doesn't have that many items
+	#[inline]
+	pub fn peep_stack(&self, distance: usize) -> Result<Value, InterpretError> {
+		if distance >= self.stack_height() {
+			error!("Stack underflow");
+			return Err(InterpretError::InterpretError);
+		}
+		Ok(unsafe { *self.stack_top.sub(distance + 1) })
+	}
+	/// Overwrites the item on top of the stack, which must have been checked to exist (such as by [`Runtime::peep_stack`])
 	#[inline]
-	pub fn peep_stack(&self, distance: isize) -> &'source Value {
-		unsafe { &*self.stack_top.offset(-distance - 1) }
+	fn set_top(&mut self, value: Value) {
+		debug_assert!(self.stack_height() > 0);
+		unsafe { *self.stack_top.sub(1) = value }
+	}
+	/// Copies the top `count` items of the stack in the order they were pushed, leaving them on the stack
+	pub fn peep_many(&self, count: usize) -> Result<Vec<Value>, InterpretError> {
+		if count > self.stack_height() {
+			error!("Stack underflow");
+			return Err(InterpretError::InterpretError);
+		}
+		Ok(unsafe { std::slice::from_raw_parts(self.stack_top.sub(count), count) }.to_vec())
 	}
-	/// Peeks at an item a certain distance from the bottom of the stack
+	/// Removes the top `count` items from the stack, which must have been checked to exist (such as by [`Runtime::peep_many`])
 	#[inline]
-	pub fn peep_bottom_stack(&self, distance: usize) -> &'source Value {
-		unsafe { &*self.stack.as_ptr().offset(distance as isize) }
+	fn drop_stack(&mut self, count: usize) {
+		debug_assert!(count <= self.stack_height());
+		self.stack_top = unsafe { self.stack_top.sub(count) };
+	}
+	/// Peeks at a local slot a certain distance from the frame base, erroring if it is not below the top
+	#[inline]
+	pub fn peep_bottom_stack(&mut self, distance: usize) -> Result<Value, InterpretError> {
+		let height = self.stack_height();
+		if self.frame_base + distance >= height {
+			runtime_error!(self, "Invalid local slot {distance}, stack height {height}");
+		}
+		Ok(unsafe { *self.stack.as_ptr().add(self.frame_base + distance) })
 	}
 
 	// /// Allocates an object, storing it in the objects list so it can be garbage collected. Returns a raw pointer to the object.
@@ -173,19 +506,102 @@ impl<'source> Runtime {
 	// 	unsafe { self.objects.as_mut_ptr_range().end.offset(-1) }
 	// }
 
+	/// Frees all heap objects that can no longer be reached from the stack, the globals or the constants of the running chunks, returning the number of objects freed.
+	#[cfg(feature = "gc")]
+	pub fn collect_garbage(&mut self) -> usize {
+		// Mark: start from the roots and then trace through the fields of any structs and the elements of any arrays
+		let mut grey = Vec::new();
+		let mut current = self.stack.as_ptr();
+		while current != self.stack_top {
+			unsafe {
+				if let Some(obj) = (*current).as_obj() {
+					grey.push(obj);
+				}
+				current = current.offset(1);
+			}
+		}
+		grey.extend(self.globals.keys().copied());
+		grey.extend(self.globals.values().filter_map(Value::as_obj));
+		for chunk in self.outer_chunks.iter().chain([&self.chunk]) {
+			grey.extend(chunk.strings.iter().copied());
+		}
+		let mut marked = AHashSet::new();
+		while let Some(obj) = grey.pop() {
+			if !marked.insert(obj.addr()) {
+				continue;
+			}
+			let children = match obj.as_ref::<Struct>() {
+				Some(instance) => {
+					// The instances keep the field names alive, since the chunk that declared the struct may already have been dropped
+					grey.extend(instance.layout.fields.iter().copied());
+					&instance.fields
+				}
+				None => match obj.as_ref::<Vec<Value>>() {
+					Some(values) => values,
+					None => continue,
+				},
+			};
+			grey.extend(children.iter().filter_map(Value::as_obj));
+		}
+
+		// The intern table is not a root, so it is fixed up after marking but before sweeping to never hold a freed string.
+		// An equal string created later is then allocated afresh instead of reviving the dead one.
+		self.strings.retain(|string| marked.contains(&string.addr()));
+
+		// Sweep
+		let before = self.objects.len();
+		for obj in core::mem::take(&mut self.objects) {
+			if marked.contains(&obj.handle().addr()) {
+				self.objects.push(obj);
+			} else {
+				self.bytes_allocated -= obj.size();
+			}
+		}
+		before - self.objects.len()
+	}
+
+	/// Without the garbage collector, objects are kept for the lifetime of the runtime
+	#[cfg(not(feature = "gc"))]
+	pub fn collect_garbage(&mut self) -> usize {
+		0
+	}
+
 	/// Removes all heap allocated objects (do not leave references to these objects)
 	#[inline]
 	fn free_objects(&mut self) {
-		while let Some(obj) = self.objects.pop() {
-			ObjTy::free(obj)
+		self.objects.clear();
+		self.bytes_allocated = 0;
+	}
+
+	/// Counts the memory currently used by the runtime
+	pub fn mem_stats(&self) -> MemStats {
+		MemStats {
+			objects: self.objects.len(),
+			strings: self.strings.len(),
+			globals: self.globals.len(),
+			stack: self.stack_height(),
+			bytes: self.bytes_allocated,
 		}
 	}
 
 	/// Interprets the [Chunk], matching each opcode instruction.
 	pub fn interpret(&mut self) -> Result<(), InterpretError> {
 		trace!("Interpreting chunk");
-		assert_ne!(unsafe { &*self.chunk }.len(), 0, "Chunk should not be empty");
+		// An empty chunk has nothing to run (not even a return)
+		if self.chunk.is_empty() {
+			return Ok(());
+		}
 		loop {
+			// A chunk without a trailing return would otherwise read past the end of the bytecode.
+			// This is checked before updating `instruction` so the error reports the line of the last instruction
+			if self.ip >= self.chunk.len() {
+				runtime_error!(self, "ip out of bounds at offset {}", self.offset());
+			}
+			if self.instructions_left == 0 {
+				runtime_error!(self, "Instruction limit of {} reached", self.instruction_limit);
+			}
+			self.instructions_left -= 1;
+
 			#[cfg(feature = "trace_execution")]
 			{
 				let mut current = self.stack.as_ptr();
@@ -200,171 +616,618 @@ impl<'source> Runtime {
 					}
 					println!();
 				}
-				let chunk = unsafe { &*self.chunk };
-				let offset = self.offset();
-
-				disassemble_instruction(chunk, offset);
+				disassemble_instruction(&self.chunk, self.offset());
 			}
 
-			let instruction = self.read_byte();
+			self.instruction = self.ip;
+			let instruction = self.read_byte()?;
 			let opcode = instruction.into();
 
 			macro_rules! binary_op {
-				($op:tt => $resultv:tt) => {
-					{
-						let b = self.pop_stack()?;
-						let a = self.pop_stack()?;
-						if let [Value::Number(a), Value::Number(b)] = [a,b]{
-							self.push_stack(Value::$resultv(a $op b));
-						}else{
-							runtime_error!(self, "Operands must be numbers");
-						}
-
+				($symbol:literal) => {{
+					let b = self.pop_stack()?;
+					let a = self.pop_stack()?;
+					if matches!(opcode, Opcode::Divide | Opcode::Modulo) && !self.ieee_division && b.as_f64() == Some(0.) {
+						runtime_error!(self, "Division by zero in {a} {} {b}", $symbol);
 					}
-				};
+					match arithmetic(opcode, a, b) {
+						Ok(result) => self.push_stack(result)?,
+						Err(message) => runtime_error!(self, "{message}"),
+					}
+				}};
 			}
 
 			match opcode {
-				Opcode::Unknown => warn!("Unknown opcode"),
+				// Carrying on would misread operand bytes as opcodes
+				Opcode::Unknown => runtime_error!(self, "Unknown opcode {instruction} at offset {}", self.offset() - 1),
 
 				Opcode::Constant => {
-					let constant = self.short_constant();
-					self.push_stack(constant.clone());
+					let constant = self.short_constant()?;
+					self.push_stack(constant)?;
 				}
 				Opcode::LongConstant => {
-					let constant = self.long_constant();
-					self.push_stack(constant.clone());
+					let constant = self.long_constant()?;
+					self.push_stack(constant)?;
+				}
+				Opcode::LoadZero => self.push_stack(Value::int(0))?,
+				Opcode::LoadOne => self.push_stack(Value::int(1))?,
+				Opcode::LoadSmallInt => {
+					let value = self.read_byte()? as i8;
+					self.push_stack(Value::int(value.into()))?;
 				}
 				Opcode::Return => return Ok(()),
+				// The unary operators replace their operand on top of the stack rather than popping it and pushing the result
 				Opcode::Negate => {
-					let input = self.pop_stack()?;
-					if let Value::Number(input) = input {
-						self.push_stack(Value::Number(-input));
-					} else {
-						runtime_error!(self, "Operands must be numbers");
-					}
+					let input = self.peep_stack(0)?;
+					let result = match input.kind() {
+						ValueKind::Number(input) => Value::number(-input),
+						ValueKind::Int(input) => match input.checked_neg().and_then(Value::try_int) {
+							Some(result) => result,
+							None => runtime_error!(self, "Integer overflow in -{input}"),
+						},
+						_ => runtime_error!(self, "Operand to '-' must be a number, got {}", input.type_name()),
+					};
+					self.set_top(result);
 				}
-				Opcode::Add => {
-					fn get_str<'a>(b: &'a Value) -> Option<&'a str> {
-						match b {
-							Value::Obj(x) => x.as_ref::<String>().map(|x| x.as_str()),
-							_ => None,
-						}
-					}
-
+				// Both join strings or add numbers, but `Add` checks for numbers first and `Concat` for strings
+				Opcode::Add | Opcode::Concat => {
 					let b = self.pop_stack()?;
 					let a = self.pop_stack()?;
-					if let [Value::Number(a), Value::Number(b)] = [a, b] {
-						self.push_stack(Value::Number(a + b));
-					} else if let Some(b) = get_str(b)
-						&& let Some(a) = get_str(a)
+					let numbers = opcode == Opcode::Add && a.as_f64().is_some() && b.as_f64().is_some();
+					if !numbers
+						&& let (Some(a), Some(b)) = (a.as_obj(), b.as_obj())
+						&& let (Some(a), Some(b)) = (a.as_str(), b.as_str())
 					{
 						let obj_ref = self.new_string(a.to_string() + b);
-						self.push_stack(Value::Obj(obj_ref));
+						self.push_stack(Value::obj(obj_ref))?;
 					} else {
-						runtime_error!(self, "Operands to '+' must be numbers or strings");
+						match arithmetic(Opcode::Add, a, b) {
+							Ok(result) => self.push_stack(result)?,
+							Err(message) => runtime_error!(self, "{message}"),
+						}
 					}
 				}
-				Opcode::Subtract => binary_op!(- => Number),
-				Opcode::Multiply => binary_op!(* => Number),
-				Opcode::Divide => binary_op!(/ => Number),
-				Opcode::Modolo => binary_op!(% => Number),
-				Opcode::Null => self.push_stack(Value::Null),
-				Opcode::True => self.push_stack(Value::Bool(true)),
-				Opcode::False => self.push_stack(Value::Bool(false)),
+				Opcode::Subtract => binary_op!("-"),
+				Opcode::Multiply => binary_op!("*"),
+				Opcode::Divide => binary_op!("/"),
+				Opcode::Modulo => binary_op!("%"),
+				Opcode::Greater => binary_op!(">"),
+				Opcode::Less => binary_op!("<"),
+				Opcode::GreaterEqual => binary_op!(">="),
+				Opcode::LessEqual => binary_op!("<="),
+				Opcode::Null => self.push_stack(Value::NULL)?,
+				Opcode::True => self.push_stack(Value::bool(true))?,
+				Opcode::False => self.push_stack(Value::bool(false))?,
 				Opcode::Not => {
-					let input = self.pop_stack()?;
-					if let Value::Bool(x) = input {
-						self.push_stack(Value::Bool(!x))
-					} else {
-						runtime_error!(self, "Operand must be a boolean");
-					}
+					let input = self.peep_stack(0)?;
+					let Some(x) = input.as_bool() else {
+						runtime_error!(self, "Operand to '!' must be a bool, got {}", input.type_name());
+					};
+					self.set_top(Value::bool(!x));
 				}
 				Opcode::Equal => {
 					let b = self.pop_stack()?;
 					let a = self.pop_stack()?;
-					self.push_stack(Value::Bool(a == b));
+					self.push_stack(Value::bool(a == b))?;
+				}
+				Opcode::NotEqual => {
+					let b = self.pop_stack()?;
+					let a = self.pop_stack()?;
+					self.push_stack(Value::bool(a != b))?;
+				}
+				Opcode::Contains => {
+					let container = self.pop_stack()?;
+					let item = self.pop_stack()?;
+					match container.as_obj().as_ref().and_then(ObjRef::as_str) {
+						Some(string) => {
+							let item = item.as_obj();
+							let Some(item) = item.as_ref().and_then(ObjRef::as_str) else {
+								runtime_error!(self, "Only strings can be found in a string");
+							};
+							let contains = string.contains(item);
+							self.push_stack(Value::bool(contains))?;
+						}
+						None => runtime_error!(self, "Cannot use 'in' on a {}", container.type_name()),
+					}
+				}
+				Opcode::IsType => {
+					let value_type = ValueType::from(self.read_byte()?);
+					if value_type == ValueType::Unknown {
+						let name = self.pop_stack()?;
+						let value = self.pop_stack()?;
+						runtime_error!(self, "Cannot check if {value:?} is {name}, which is not a type");
+					}
+					let value = self.pop_stack()?;
+					self.push_stack(Value::bool(value_type.matches(&value)))?;
+				}
+				Opcode::IsStruct => {
+					let index = self.read_byte()? as usize;
+					let layout = self.chunk.structs.get(index).cloned();
+					let value = self.pop_stack()?;
+					let Some(layout) = layout else {
+						runtime_error!(self, "Cannot check if {value:?} is an undeclared struct");
+					};
+					let is_struct = value.as_obj().and_then(|obj| obj.as_ref::<Struct>().map(|instance| std::rc::Rc::ptr_eq(&instance.layout, &layout)));
+					self.push_stack(Value::bool(is_struct.unwrap_or(false)))?;
+				}
+				Opcode::CallNative => {
+					let index = self.read_byte()? as usize;
+					let Some(native) = NATIVES.get(index) else {
+						runtime_error!(self, "No native function at index {index}");
+					};
+					let arg_count = self.read_byte()? as usize;
+					// The arguments stay on the stack during the call so that they are rooted if the native allocates
+					let args = self.peep_many(arg_count)?;
+					match (native.function)(self, &args) {
+						Ok(_) if let Some(code) = self.eval_exit.take() => return Err(InterpretError::Exit(code)),
+						Ok(result) => {
+							self.drop_stack(arg_count);
+							self.push_stack(result)?
+						}
+						Err(message) => {
+							runtime_error!(self, "{message}");
+						}
+					}
 				}
-				Opcode::Greater => binary_op!(> => Bool),
-				Opcode::Less => binary_op!(< => Bool),
 				Opcode::Print => {
-					warn!(target: "user logs", "program: {:?}", self.pop_stack());
+					let value = self.pop_stack()?;
+					if let Err(e) = writeln!(self.output, "{value}") {
+						runtime_error!(self, "Could not print: {e}");
+					}
+				}
+				Opcode::PrintInline => {
+					// Flushed so that prompts appear before any blocking input
+					let value = self.pop_stack()?;
+					if let Err(e) = write!(self.output, "{value}").and_then(|_| self.output.flush()) {
+						runtime_error!(self, "Could not print: {e}");
+					}
+				}
+				Opcode::MakeStruct => {
+					let index = self.read_byte()? as usize;
+					let Some(layout) = self.chunk.structs.get(index).cloned() else {
+						runtime_error!(self, "No struct declared at index {index}");
+					};
+					let offsets = (0..layout.fields.len()).map(|_| self.read_byte().map(usize::from)).collect::<Result<Vec<_>, _>>()?;
+					// The values are pushed in the order they are written, which may differ from the declaration
+					let mut fields = vec![Value::NULL; layout.fields.len()];
+					for (&offset, value) in offsets.iter().zip(self.peep_many(offsets.len())?) {
+						let Some(field) = fields.get_mut(offset) else {
+							runtime_error!(self, "{} has no field at offset {offset}", layout.name);
+						};
+						*field = value;
+					}
+					let instance = self.new_object(Struct { layout, fields });
+					self.drop_stack(offsets.len());
+					self.push_stack(Value::obj(instance))?;
+				}
+				Opcode::MakeArray => {
+					let count = self.read_byte()? as usize;
+					// The elements are only popped once the array holds them, so they stay rooted while it is allocated
+					let values = self.peep_many(count)?;
+					let array = self.new_object(values);
+					self.drop_stack(count);
+					self.push_stack(Value::obj(array))?;
+				}
+				Opcode::GetProperty | Opcode::GetLongProperty | Opcode::SetProperty | Opcode::SetLongProperty => {
+					let name = if matches!(opcode, Opcode::GetProperty | Opcode::SetProperty) {
+						self.short_constant()?
+					} else {
+						self.long_constant()?
+					};
+					let value = if matches!(opcode, Opcode::SetProperty | Opcode::SetLongProperty) {
+						Some(self.pop_stack()?)
+					} else {
+						None
+					};
+					let target = self.pop_stack()?;
+					let Some(mut obj) = target.as_obj() else {
+						runtime_error!(self, "Only structs have properties, not {target}");
+					};
+					let Some(instance) = obj.as_mut::<Struct>() else {
+						runtime_error!(self, "Only structs have properties, not {target}");
+					};
+					// The name is interned, as are the field names, so they can be compared by handle
+					let Some(offset) = name.as_obj().and_then(|name| instance.layout.field_by_key(name)) else {
+						runtime_error!(self, "{} has no field '{name}'", instance.layout.name);
+					};
+					match value {
+						Some(value) => {
+							instance.fields[offset] = value;
+							self.push_stack(value)?;
+						}
+						None => self.push_stack(instance.fields[offset])?,
+					}
+				}
+				Opcode::Debug | Opcode::LongDebug => {
+					let line = self.chunk.line_at(self.offset() - 1);
+					let source = if opcode == Opcode::Debug { self.short_constant()? } else { self.long_constant()? };
+					// The value is left on the stack since `debug` evaluates to it
+					let value = self.peep_stack(0)?;
+					if let Err(e) = writeln!(self.output, "[line {}] {source} = {value}", line.line) {
+						runtime_error!(self, "Could not print: {e}");
+					}
+				}
+				Opcode::Dup => {
+					let top = self.pop_stack()?;
+					self.push_stack(top)?;
+					self.push_stack(top)?;
+				}
+				Opcode::Swap => {
+					let b = self.pop_stack()?;
+					let a = self.pop_stack()?;
+					self.push_stack(b)?;
+					self.push_stack(a)?;
 				}
 				Opcode::Pop => {
-					self.pop_stack();
+					self.pop_stack()?;
+				}
+				Opcode::Exit => {
+					let code = self.pop_stack()?;
+					match code.kind() {
+						ValueKind::Int(code) if i32::try_from(code).is_ok() => return Err(InterpretError::Exit(code as i32)),
+						ValueKind::Number(code) if code.fract() == 0. && (i32::MIN as f64..=i32::MAX as f64).contains(&code) => return Err(InterpretError::Exit(code as i32)),
+						_ => {
+							runtime_error!(self, "Exit code must be an integer, found {code:?}");
+						}
+					}
+				}
+				Opcode::Panic => {
+					let message = self.pop_stack()?;
+					runtime_error!(self, "{message:?}");
 				}
 
 				Opcode::DefineGlobalVariable | Opcode::DefineLongGlobalVariable => {
-					if let Value::Obj(name) = if opcode == Opcode::DefineGlobalVariable { self.short_constant() } else { self.long_constant() } {
-						if let Some(name) = name.as_ref::<String>() {
-							let value = self.pop_stack()?.clone();
-
-							match self.globals.entry(name.clone()) {
-								Entry::Occupied(_) => {
-									runtime_error!(self, "Variable {name} is already defined.");
-									return Err(InterpretError::InterpretError);
-								}
-								Entry::Vacant(entry) => entry.insert(value),
-							};
-							trace!("Globals {name} val {value:?} {:?}", self.globals);
-						}
+					if let Some(key) = (if opcode == Opcode::DefineGlobalVariable { self.short_constant()? } else { self.long_constant()? }).as_obj()
+						&& let Some(name) = key.as_ref::<String>()
+					{
+						let value = self.pop_stack()?;
+
+						match self.globals.entry(key) {
+							Entry::Occupied(mut entry) if self.global_redefinition => {
+								entry.insert(value);
+							}
+							Entry::Occupied(_) => {
+								runtime_error!(self, "Variable {name} is already defined.");
+							}
+							Entry::Vacant(entry) => {
+								entry.insert(value);
+							}
+						};
+						trace!("Globals {name} val {value:?} {:?}", self.globals);
 					}
 				}
 				Opcode::GetGlobalVariable | Opcode::GetLongGlobalVariable => {
-					if let Value::Obj(name) = (if opcode == Opcode::GetGlobalVariable { self.short_constant() } else { self.long_constant() }) {
-						if let Some(name) = name.as_ref::<String>() {
-							if let Some(value) = self.globals.get(name) {
-								trace!("Globals {name} val {value:?} {:?}", self.globals);
-								self.push_stack(*value);
-							} else {
-								runtime_error!(self, "Undefined variable: {name}");
-								return Err(InterpretError::InterpretError);
-							}
+					if let Some(key) = (if opcode == Opcode::GetGlobalVariable { self.short_constant()? } else { self.long_constant()? }).as_obj()
+						&& let Some(name) = key.as_ref::<String>()
+					{
+						if let Some(value) = self.globals.get(&key) {
+							trace!("Globals {name} val {value:?} {:?}", self.globals);
+							self.push_stack(*value)?;
+						} else if let Some(value) = find_constant(name) {
+							self.push_stack(value)?;
+						} else {
+							runtime_error!(self, "Undefined variable: {name}");
 						}
 					}
 				}
 				Opcode::SetGlobal | Opcode::SetLongGlobal => {
-					if let Value::Obj(name) = (if opcode == Opcode::SetGlobal { self.short_constant() } else { self.long_constant() }) {
-						if let Some(name) = name.as_ref::<String>() {
-							let value = self.peep_stack(0).clone();
-							match self.globals.entry(name.clone()) {
-								Entry::Occupied(mut entry) => entry.insert(value),
-								Entry::Vacant(_) => {
-									runtime_error!(self, "Attempt to assign to variable '{name}' before defenition");
-									return Err(InterpretError::InterpretError);
-								}
-							};
-							info!("Glboals {name} val {value:?} {:?}", self.globals);
-						}
+					if let Some(key) = (if opcode == Opcode::SetGlobal { self.short_constant()? } else { self.long_constant()? }).as_obj()
+						&& let Some(name) = key.as_ref::<String>()
+					{
+						let value = self.peep_stack(0)?;
+						match self.globals.entry(key) {
+							Entry::Occupied(mut entry) => entry.insert(value),
+							Entry::Vacant(_) => {
+								runtime_error!(self, "Attempt to assign to variable '{name}' before defenition");
+							}
+						};
+						info!("Glboals {name} val {value:?} {:?}", self.globals);
 					}
 				}
 				Opcode::SetLocal | Opcode::SetLongLocal => {
-					let slot = if opcode == Opcode::SetLocal { self.read_byte() as usize } else { self.read_bytes(3) };
-					self.set_stack(slot, self.peep_stack(0).clone());
+					let slot = if opcode == Opcode::SetLocal { self.read_byte()? as usize } else { self.read_bytes(3)? };
+					self.set_stack(slot, self.peep_stack(0)?)?;
 				}
 				Opcode::GetLocal | Opcode::GetLongLocal => {
-					let slot = if opcode == Opcode::GetLocal { self.read_byte() as usize } else { self.read_bytes(3) };
-					self.push_stack(self.peep_bottom_stack(slot).clone());
-				}
-				Opcode::Jump => {
-					let offset = self.read_bytes(2);
-					self.ip = unsafe { self.ip.add(offset as usize) };
-				}
-				Opcode::JumpIfFalse => {
-					let offset = self.read_bytes(2);
-					let Value::Bool(x) = self.peep_stack(0) else {
-						runtime_error!(self, "Value must be a boolean");
-						continue;
+					let slot = if opcode == Opcode::GetLocal { self.read_byte()? as usize } else { self.read_bytes(3)? };
+					let value = self.peep_bottom_stack(slot)?;
+					self.push_stack(value)?;
+				}
+				Opcode::Jump | Opcode::LongJump => {
+					let offset = self.jump_offset(opcode)?;
+					self.jump(offset);
+				}
+				Opcode::JumpIfFalse | Opcode::LongJumpIfFalse => {
+					let offset = self.jump_offset(opcode)?;
+					// The error aborts before the jump, so the then-branch never runs with the condition left on the stack
+					let condition = self.peep_stack(0)?;
+					let Some(x) = condition.as_bool() else {
+						runtime_error!(self, "Condition must be a bool, got {}", condition.type_name());
 					};
 					if !x {
-						self.ip = unsafe { self.ip.add(offset as usize) };
+						self.jump(offset);
+					}
+				}
+				// Used by `and` and `or`, which work with any value rather than just booleans
+				Opcode::JumpIfFalsy | Opcode::LongJumpIfFalsy => {
+					let offset = self.jump_offset(opcode)?;
+					if !self.peep_stack(0)?.truthy() {
+						self.jump(offset);
 					}
 				}
-				Opcode::JumpBack => {
-					let offset = self.read_bytes(2);
-					self.ip = unsafe { self.ip.sub(offset as usize) };
+				Opcode::JumpIfTruthy | Opcode::LongJumpIfTruthy => {
+					let offset = self.jump_offset(opcode)?;
+					if self.peep_stack(0)?.truthy() {
+						self.jump(offset);
+					}
+				}
+				// Ends each iteration of a while loop, popping the condition and jumping back to the start of the body while it is true
+				Opcode::PopJumpIfTrue | Opcode::LongPopJumpIfTrue => {
+					let offset = self.jump_offset(opcode)?;
+					let condition = self.pop_stack()?;
+					let Some(x) = condition.as_bool() else {
+						runtime_error!(self, "Condition must be a bool, got {}", condition.type_name());
+					};
+					if x {
+						self.jump(offset);
+					}
 				}
 			}
 		}
 	}
 }
+
+impl Drop for Runtime {
+	fn drop(&mut self) {
+		self.free_objects();
+	}
+}
+
+#[test]
+fn int_arithmetic() {
+	use ValueKind::{Bool, Int, Number};
+	let op = |opcode, a: ValueKind, b: ValueKind| arithmetic(opcode, a.into(), b.into()).map(Value::kind);
+
+	// Two ints stay exact, even past the precision of a float when the representation allows it
+	assert!(matches!(op(Opcode::Add, Int(Value::MAX_INT - 1), Int(1)), Ok(Int(Value::MAX_INT))));
+	assert!(matches!(op(Opcode::Subtract, Int(3), Int(5)), Ok(Int(-2))));
+	assert!(matches!(op(Opcode::Multiply, Int(-4), Int(5)), Ok(Int(-20))));
+	assert!(matches!(op(Opcode::Modulo, Int(-7), Int(3)), Ok(Int(-1))));
+	assert!(matches!(op(Opcode::Greater, Int(2), Int(1)), Ok(Bool(true))));
+	assert!(matches!(op(Opcode::Less, Int(2), Int(1)), Ok(Bool(false))));
+	// Division always gives a number
+	assert!(matches!(op(Opcode::Divide, Int(7), Int(2)), Ok(Number(3.5))));
+	assert!(matches!(op(Opcode::Divide, Int(6), Int(2)), Ok(Number(3.))));
+	assert!(matches!(op(Opcode::Divide, Int(1), Int(0)), Ok(Number(f64::INFINITY))));
+	assert!(matches!(op(Opcode::Modulo, Int(1), Int(0)), Ok(Number(n)) if n.is_nan()));
+
+	// Overflow past the range of ints is an error
+	assert!(op(Opcode::Add, Int(Value::MAX_INT), Int(1)).is_err());
+	assert!(op(Opcode::Subtract, Int(Value::MIN_INT), Int(1)).is_err());
+	assert!(op(Opcode::Multiply, Int(Value::MAX_INT), Int(2)).is_err());
+	assert!(op(Opcode::Multiply, Int(Value::MIN_INT), Int(-1)).is_err());
+	// Only the smallest i64 can overflow in a remainder, which is out of range with `nan_boxing`
+	if Value::MIN_INT == i64::MIN {
+		assert!(op(Opcode::Modulo, Int(i64::MIN), Int(-1)).is_err());
+	}
+
+	// Mixing promotes to a number
+	assert!(matches!(op(Opcode::Add, Int(1), Number(0.5)), Ok(Number(1.5))));
+	assert!(matches!(op(Opcode::Subtract, Number(0.5), Int(1)), Ok(Number(-0.5))));
+	assert!(matches!(op(Opcode::Multiply, Int(3), Number(0.5)), Ok(Number(1.5))));
+	assert!(matches!(op(Opcode::Modulo, Number(7.5), Int(2)), Ok(Number(1.5))));
+	assert!(matches!(op(Opcode::Less, Int(1), Number(1.5)), Ok(Bool(true))));
+	assert!(matches!(op(Opcode::Add, Number(1.), Number(2.)), Ok(Number(3.))));
+
+	assert!(op(Opcode::Add, Int(1), Bool(true)).is_err());
+	assert!(op(Opcode::Less, ValueKind::Null, Number(1.)).is_err());
+
+	assert_eq!(Value::int(1), Value::number(1.));
+	assert_ne!(Value::int(1), Value::number(1.5));
+	assert_ne!(Value::int(Value::MAX_INT), Value::int(Value::MAX_INT - 1));
+	assert_eq!(Value::int(-3).to_string(), "-3");
+}
+
+#[test]
+fn drop_runtime_objects() {
+	let mut chunk = Chunk::new();
+	chunk.make_string("constant".to_string());
+	let mut runtime = Runtime::new(Chunk::new());
+	for index in 0..100 {
+		let string = runtime.new_string(format!("global_{index}"));
+		runtime.globals.insert(string, Value::obj(string));
+	}
+	runtime.reset(chunk);
+	assert_eq!(runtime.objects.len(), 101);
+	// Dropping the runtime (and an unused chunk) frees every object
+	let mut unused = Chunk::new();
+	unused.make_string("unused".to_string());
+	drop(unused);
+	drop(runtime);
+}
+
+#[test]
+#[cfg(feature = "gc")]
+fn weak_interning() {
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new());
+	let source = r#"let i = 0; while i < 1000 { format("unique {}", i); i = i + 1; }"#;
+	assert!(matches!(crate::interpret(source, &mut runtime), Ok(())));
+	// Stress mode collects during the loop, so the table never grows
+	if !cfg!(feature = "gc_stress") {
+		assert!(runtime.strings.len() > 1000);
+	}
+	runtime.collect_garbage();
+	// Only the constants of the chunk are still interned
+	assert_eq!(runtime.strings.len(), runtime.chunk.strings.len());
+	assert_eq!(runtime.objects.len(), runtime.strings.len());
+
+	// A string equal to a collected one is interned again
+	let string = runtime.new_string("unique 5".to_string());
+	runtime.push_stack(Value::obj(string)).unwrap();
+	assert_eq!(string.as_str(), Some("unique 5"));
+	assert_eq!(runtime.new_string("unique 5".to_string()), string);
+	assert_eq!(runtime.strings.len(), runtime.chunk.strings.len() + 1);
+}
+
+#[test]
+fn unknown_opcode() {
+	init_logger();
+	let line = Line::new(1, 1);
+	let mut chunk = Chunk::new();
+	chunk.push(Opcode::Null, line);
+	chunk.push(u8::MAX, line);
+	chunk.push(Opcode::Return, line);
+	let mut runtime = Runtime::new(chunk);
+	assert!(matches!(runtime.interpret(), Err(InterpretError::InterpretError)));
+}
+
+#[test]
+fn missing_return() {
+	init_logger();
+	let line = Line::new(1, 1);
+	let mut chunk = Chunk::new();
+	chunk.push(Opcode::Null, line);
+	chunk.push(Opcode::Pop, line);
+	let mut runtime = Runtime::new(chunk);
+	assert!(matches!(runtime.interpret(), Err(InterpretError::InterpretError)));
+}
+
+#[test]
+fn invalid_local_slot() {
+	init_logger();
+	let line = Line::new(1, 1);
+	for (opcode, operand) in [
+		(Opcode::GetLocal, &[1][..]),
+		(Opcode::SetLocal, &[1]),
+		(Opcode::GetLongLocal, &[0, 1, 0]),
+		(Opcode::SetLongLocal, &[255, 255, 255]),
+	] {
+		let mut chunk = Chunk::new();
+		chunk.push(Opcode::Null, line);
+		chunk.push(opcode, line);
+		for &byte in operand {
+			chunk.push(byte, line);
+		}
+		chunk.push(Opcode::Return, line);
+		let mut runtime = Runtime::new(chunk);
+		assert!(matches!(runtime.interpret(), Err(InterpretError::InterpretError)));
+	}
+
+	// The slot of the one value on the stack is still valid
+	let mut chunk = Chunk::new();
+	chunk.push(Opcode::Null, line);
+	chunk.push(Opcode::GetLocal, line);
+	chunk.push(0, line);
+	chunk.push(Opcode::SetLocal, line);
+	chunk.push(0, line);
+	chunk.push(Opcode::Pop, line);
+	chunk.push(Opcode::Pop, line);
+	chunk.push(Opcode::Return, line);
+	let mut runtime = Runtime::new(chunk);
+	assert!(matches!(runtime.interpret(), Ok(())));
+}
+
+#[test]
+fn dup_and_swap() {
+	init_logger();
+	let line = Line::new(1, 1);
+	let instructions = [Opcode::Dup, Opcode::Swap, Opcode::Pop, Opcode::Swap, Opcode::Dup];
+	let (t, n) = (Value::bool(true), Value::NULL);
+	let stacks = [vec![t, n, n], vec![t, n, n], vec![t, n], vec![n, t], vec![n, t, t]];
+	for (index, expected) in stacks.iter().enumerate() {
+		let mut chunk = Chunk::new();
+		for &opcode in [Opcode::True, Opcode::Null].iter().chain(&instructions[..=index]) {
+			chunk.push(opcode, line);
+		}
+		chunk.push(Opcode::Return, line);
+		let mut runtime = Runtime::new(chunk);
+		assert!(matches!(runtime.interpret(), Ok(())));
+		assert_eq!(runtime.peep_many(runtime.stack_height()).unwrap(), *expected, "{:?}", &instructions[..=index]);
+	}
+
+	// There must be a value to copy and two to exchange, as there must be an operand for the unary operators which replace it in place
+	for (opcode, values) in [(Opcode::Dup, 0), (Opcode::Swap, 1), (Opcode::Negate, 0), (Opcode::Not, 0)] {
+		let mut chunk = Chunk::new();
+		for _ in 0..values {
+			chunk.push(Opcode::Null, line);
+		}
+		chunk.push(opcode, line);
+		chunk.push(Opcode::Return, line);
+		let mut runtime = Runtime::new(chunk);
+		assert!(matches!(runtime.interpret(), Err(InterpretError::InterpretError)), "{opcode:?}");
+	}
+}
+
+#[test]
+fn peep_empty_stack() {
+	init_logger();
+	let line = Line::new(1, 1);
+	for (opcode, operand) in [
+		(Opcode::JumpIfFalse, &[0, 0][..]),
+		(Opcode::JumpIfFalsy, &[0, 0]),
+		(Opcode::JumpIfTruthy, &[0, 0]),
+		(Opcode::SetLocal, &[0]),
+		(Opcode::SetGlobal, &[0]),
+		(Opcode::Debug, &[0]),
+	] {
+		let mut chunk = Chunk::new();
+		chunk.make_string("x".to_string());
+		chunk.push(opcode, line);
+		for &byte in operand {
+			chunk.push(byte, line);
+		}
+		chunk.push(Opcode::Return, line);
+		let mut runtime = Runtime::new(chunk).with_output(std::io::sink());
+		assert!(matches!(runtime.interpret(), Err(InterpretError::InterpretError)), "{opcode:?}");
+	}
+}
+
+#[test]
+fn malformed_operands() {
+	init_logger();
+	let line = Line::new(1, 1);
+	for (code, message) in [
+		(&[Opcode::LoadSmallInt.into()][..], "ip out of bounds at offset 1"),
+		(&[Opcode::LongConstant.into(), 0], "ip out of bounds at offset 2"),
+		(&[Opcode::Jump.into(), 0], "ip out of bounds at offset 2"),
+		(&[Opcode::CallNative.into(), u8::MAX, 0, Opcode::Return.into()], "No native function at index 255"),
+		(&[Opcode::MakeStruct.into(), 0, Opcode::Return.into()], "No struct declared at index 0"),
+	] {
+		let mut chunk = Chunk::new();
+		for &byte in code {
+			chunk.push(byte, line);
+		}
+		let mut runtime = Runtime::new(chunk);
+		assert!(matches!(runtime.interpret(), Err(InterpretError::InterpretError)), "{code:?}");
+		assert_eq!(runtime.last_error(), Some(message));
+	}
+}
+
+#[test]
+fn jump_out_of_bounds() {
+	init_logger();
+	let line = Line::new(1, 1);
+	for (opcode, operand) in [
+		(Opcode::Jump, &[0, 10][..]),
+		(Opcode::Jump, &[255, 246]),
+		(Opcode::PopJumpIfTrue, &[255, 246]),
+		(Opcode::LongJump, &[0, 1, 0, 0]),
+		(Opcode::LongJump, &[128, 0, 0, 0]),
+		(Opcode::LongPopJumpIfTrue, &[255, 255, 255, 246]),
+		(Opcode::JumpIfTruthy, &[0, 10]),
+		(Opcode::LongJumpIfTruthy, &[0, 1, 0, 0]),
+	] {
+		let mut chunk = Chunk::new();
+		if matches!(opcode, Opcode::PopJumpIfTrue | Opcode::LongPopJumpIfTrue | Opcode::JumpIfTruthy | Opcode::LongJumpIfTruthy) {
+			chunk.push(Opcode::True, line);
+		}
+		chunk.push(opcode, line);
+		for &byte in operand {
+			chunk.push(byte, line);
+		}
+		chunk.push(Opcode::Return, line);
+		let mut runtime = Runtime::new(chunk);
+		assert!(matches!(runtime.interpret(), Err(InterpretError::InterpretError)));
+	}
+}
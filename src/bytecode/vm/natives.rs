@@ -0,0 +1,956 @@
+use core::ops::RangeInclusive;
+use std::{
+	io::Write,
+	time::{Duration, Instant},
+};
+
+use crate::bytecode::prelude::*;
+
+mod json;
+
+/// A function implemented in Rust which can be called by name from a script, e.g. `matches(line, "[0-9]+")`.
+///
+/// Natives are resolved by the compiler, which checks the number of arguments and emits [`Opcode::CallNative`].
+pub struct Native {
+	pub name: &'static str,
+	/// The number of arguments that the native accepts
+	pub arity: RangeInclusive<usize>,
+	/// Computes the result from the arguments, returning a message on a runtime error
+	pub function: fn(&mut Runtime, &[Value]) -> Result<Value, String>,
+}
+
+/// All of the natives, indexed by the operand of [`Opcode::CallNative`]
+pub const NATIVES: &[Native] = &[
+	Native {
+		name: "matches",
+		arity: 2..=2,
+		function: matches,
+	},
+	Native {
+		name: "find",
+		arity: 2..=2,
+		function: find,
+	},
+	Native {
+		name: "ord",
+		arity: 1..=1,
+		function: ord,
+	},
+	Native {
+		name: "chr",
+		arity: 1..=1,
+		function: chr,
+	},
+	Native {
+		name: "random",
+		arity: 0..=0,
+		function: random,
+	},
+	Native {
+		name: "random_int",
+		arity: 2..=2,
+		function: random_int,
+	},
+	Native {
+		name: "seed",
+		arity: 1..=1,
+		function: seed,
+	},
+	Native {
+		name: "sleep",
+		arity: 1..=1,
+		function: sleep,
+	},
+	Native {
+		name: "clock",
+		arity: 0..=0,
+		function: clock,
+	},
+	Native {
+		name: "read_file",
+		arity: 1..=1,
+		function: read_file,
+	},
+	Native {
+		name: "write_file",
+		arity: 2..=2,
+		function: write_file,
+	},
+	Native {
+		name: "append_file",
+		arity: 2..=2,
+		function: append_file,
+	},
+	Native {
+		name: "env",
+		arity: 1..=1,
+		function: env,
+	},
+	Native {
+		name: "set_env",
+		arity: 2..=2,
+		function: set_env,
+	},
+	Native {
+		name: "number",
+		arity: 1..=1,
+		function: number,
+	},
+	Native {
+		name: "bool",
+		arity: 1..=1,
+		function: bool,
+	},
+	Native {
+		name: "format",
+		arity: 1..=255,
+		function: format,
+	},
+	Native {
+		name: "mod",
+		arity: 2..=2,
+		function: modulo,
+	},
+	Native {
+		name: "to_fixed",
+		arity: 2..=2,
+		function: to_fixed,
+	},
+	Native {
+		name: "to_precision",
+		arity: 2..=2,
+		function: to_precision,
+	},
+	Native {
+		name: "hash",
+		arity: 1..=1,
+		function: hash,
+	},
+	Native {
+		name: "gc",
+		arity: 0..=0,
+		function: gc,
+	},
+	Native {
+		name: "mem_stats",
+		arity: 0..=0,
+		function: mem_stats,
+	},
+	Native {
+		name: "eval",
+		arity: 1..=1,
+		function: eval,
+	},
+	Native {
+		name: "id",
+		arity: 1..=1,
+		function: id,
+	},
+	Native {
+		name: "json_parse",
+		arity: 1..=1,
+		function: json_parse,
+	},
+	Native {
+		name: "json_stringify",
+		arity: 1..=1,
+		function: json_stringify,
+	},
+];
+
+/// Numeric constants that are readable as globals by any script, unless shadowed by a `let` with the same name
+pub const CONSTANTS: &[(&str, f64)] = &[("PI", core::f64::consts::PI), ("E", core::f64::consts::E), ("INF", f64::INFINITY), ("NAN", f64::NAN)];
+
+/// Finds the value of the built-in constant with the specified name
+pub fn find_constant(name: &str) -> Option<Value> {
+	CONSTANTS.iter().find(|(constant, _)| *constant == name).map(|&(_, value)| Value::number(value))
+}
+
+/// Finds the index of the native with the specified name
+pub fn find_native(name: &str) -> Option<usize> {
+	NATIVES.iter().position(|native| native.name == name)
+}
+
+/// Reads an argument that must be a string, returning the handle that its contents are borrowed from
+fn string_arg(native: &str, value: &Value) -> Result<ObjRef, String> {
+	value
+		.as_obj()
+		.filter(|obj| obj.as_str().is_some())
+		.ok_or_else(|| format!("Expected a string argument to '{native}' but got {value:?}"))
+}
+
+/// Reads an argument that must be a number
+fn number_arg(native: &str, value: &Value) -> Result<f64, String> {
+	value.as_f64().ok_or_else(|| format!("Expected a number argument to '{native}' but got {value:?}"))
+}
+
+/// `ord(c)` returns the unicode code point of a single character string
+fn ord(_runtime: &mut Runtime, args: &[Value]) -> Result<Value, String> {
+	let string = string_arg("ord", &args[0])?;
+	let string = string.as_ref_unchecked::<String>();
+	let mut chars = string.chars();
+	match (chars.next(), chars.next()) {
+		(Some(c), None) => Ok(Value::int(c as i64)),
+		_ => Err(format!("Expected a single character string to 'ord' but got a length of {}", string.chars().count())),
+	}
+}
+
+/// `chr(n)` returns a string containing the character with the specified unicode code point
+fn chr(runtime: &mut Runtime, args: &[Value]) -> Result<Value, String> {
+	let code = number_arg("chr", &args[0])?;
+	let c = (code.fract() == 0. && (0. ..=u32::MAX as f64).contains(&code))
+		.then(|| char::from_u32(code as u32))
+		.flatten()
+		.ok_or_else(|| format!("{code} is not a valid unicode code point"))?;
+	Ok(Value::obj(runtime.new_string(c.to_string())))
+}
+
+/// A small pseudo random number generator (SplitMix64), stored in the [`Runtime`] so each runtime can be seeded independently
+pub struct Rng(u64);
+
+impl Rng {
+	/// Construct a generator that always produces the same sequence for the same seed
+	pub fn new(seed: u64) -> Self {
+		Self(seed)
+	}
+	/// Construct a generator with a seed that is different for each run
+	pub fn from_entropy() -> Self {
+		use std::hash::{BuildHasher, Hasher};
+		let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+		hasher.write_u128(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |time| time.as_nanos()));
+		Self(hasher.finish())
+	}
+	/// Generates the next 64 random bits
+	pub fn next_u64(&mut self) -> u64 {
+		self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+		let mut z = self.0;
+		z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+		z ^ (z >> 31)
+	}
+	/// Generates a float in the range [0, 1)
+	pub fn next_f64(&mut self) -> f64 {
+		(self.next_u64() >> 11) as f64 / (1_u64 << 53) as f64
+	}
+}
+
+/// `random()` returns a random number in the range [0, 1)
+fn random(runtime: &mut Runtime, _args: &[Value]) -> Result<Value, String> {
+	Ok(Value::number(runtime.rng.next_f64()))
+}
+
+/// `random_int(lo, hi)` returns a random integer between lo and hi inclusive
+fn random_int(runtime: &mut Runtime, args: &[Value]) -> Result<Value, String> {
+	let low = number_arg("random_int", &args[0])?;
+	let high = number_arg("random_int", &args[1])?;
+	if low.fract() != 0. || high.fract() != 0. {
+		return Err(format!("Expected integer bounds to 'random_int' but got {low} and {high}"));
+	}
+	if low > high {
+		return Err(format!("The lower bound {low} to 'random_int' is greater than the upper bound {high}"));
+	}
+	let result = (low + (runtime.rng.next_f64() * (high - low + 1.)).floor()) as i64;
+	Value::try_int(result).ok_or_else(|| format!("The bounds {low} and {high} to 'random_int' are too large"))
+}
+
+/// `seed(n)` resets the random number generator so the following random numbers are reproducible
+fn seed(runtime: &mut Runtime, args: &[Value]) -> Result<Value, String> {
+	runtime.rng = Rng::new(number_arg("seed", &args[0])?.to_bits());
+	Ok(Value::NULL)
+}
+
+/// `sleep(ms)` pauses execution for the specified number of milliseconds
+fn sleep(runtime: &mut Runtime, args: &[Value]) -> Result<Value, String> {
+	/// Long sleeps are split into slices, so that a future interrupt can be checked between them
+	const SLICE: Duration = Duration::from_millis(50);
+
+	let milliseconds = number_arg("sleep", &args[0])?;
+	if !(milliseconds >= 0. && milliseconds.is_finite()) {
+		return Err(format!("Expected a non-negative number of milliseconds to 'sleep' but got {milliseconds}"));
+	}
+	let duration = Duration::try_from_secs_f64(milliseconds / 1000.).map_err(|_| format!("Cannot sleep for {milliseconds} milliseconds"))?;
+	if duration > runtime.max_sleep {
+		return Err(format!("Cannot sleep for {milliseconds} milliseconds, the limit is {} milliseconds", runtime.max_sleep.as_millis()));
+	}
+	let end = Instant::now().checked_add(duration).ok_or_else(|| format!("Cannot sleep for {milliseconds} milliseconds"))?;
+	while let Some(remaining) = end.checked_duration_since(Instant::now()).filter(|remaining| !remaining.is_zero()) {
+		std::thread::sleep(remaining.min(SLICE));
+	}
+	Ok(Value::NULL)
+}
+
+/// `clock()` returns the number of seconds since the runtime was created
+fn clock(runtime: &mut Runtime, _args: &[Value]) -> Result<Value, String> {
+	Ok(Value::number(runtime.start_time.elapsed().as_secs_f64()))
+}
+
+/// Checks that the runtime is allowed to access the file system
+fn check_fs(runtime: &Runtime, native: &str) -> Result<(), String> {
+	if runtime.fs_access {
+		Ok(())
+	} else {
+		Err(format!("'{native}' requires file system access, which is disabled (run with --allow-fs)"))
+	}
+}
+
+/// `read_file(path)` returns the contents of the file, relative to the current working directory
+fn read_file(runtime: &mut Runtime, args: &[Value]) -> Result<Value, String> {
+	check_fs(runtime, "read_file")?;
+	let path = string_arg("read_file", &args[0])?;
+	let path = path.as_ref_unchecked::<String>();
+	let contents = std::fs::read_to_string(path).map_err(|e| format!("Could not read file '{path}': {e}"))?;
+	Ok(Value::obj(runtime.new_string(contents)))
+}
+
+/// `write_file(path, contents)` creates or replaces the file with the contents
+fn write_file(runtime: &mut Runtime, args: &[Value]) -> Result<Value, String> {
+	check_fs(runtime, "write_file")?;
+	let path = string_arg("write_file", &args[0])?;
+	let path = path.as_ref_unchecked::<String>();
+	let contents = string_arg("write_file", &args[1])?;
+	let contents = contents.as_ref_unchecked::<String>();
+	std::fs::write(path, contents).map_err(|e| format!("Could not write file '{path}': {e}"))?;
+	Ok(Value::NULL)
+}
+
+/// `append_file(path, contents)` adds the contents to the end of the file, creating it if necessary
+fn append_file(runtime: &mut Runtime, args: &[Value]) -> Result<Value, String> {
+	use std::io::Write;
+	check_fs(runtime, "append_file")?;
+	let path = string_arg("append_file", &args[0])?;
+	let path = path.as_ref_unchecked::<String>();
+	let contents = string_arg("append_file", &args[1])?;
+	let contents = contents.as_ref_unchecked::<String>();
+	std::fs::OpenOptions::new()
+		.append(true)
+		.create(true)
+		.open(path)
+		.and_then(|mut file| file.write_all(contents.as_bytes()))
+		.map_err(|e| format!("Could not append to file '{path}': {e}"))?;
+	Ok(Value::NULL)
+}
+
+/// `env(name)` returns the value of the environment variable (as set by `set_env` or else inherited by the process), or null if it is unset (or is not valid unicode)
+fn env(runtime: &mut Runtime, args: &[Value]) -> Result<Value, String> {
+	let name = string_arg("env", &args[0])?;
+	let name = name.as_ref_unchecked::<String>();
+	let value = match runtime.env.get(name) {
+		Some(value) => Ok(value.clone()),
+		None => std::env::var(name),
+	};
+	Ok(value.map_or(Value::NULL, |value| Value::obj(runtime.new_string(value))))
+}
+
+/// `set_env(name, value)` sets the environment variable for later calls to `env` in this runtime, which requires the same permission as file system access
+fn set_env(runtime: &mut Runtime, args: &[Value]) -> Result<Value, String> {
+	check_fs(runtime, "set_env")?;
+	let name = string_arg("set_env", &args[0])?;
+	let name = name.as_ref_unchecked::<String>();
+	let value = string_arg("set_env", &args[1])?;
+	let value = value.as_ref_unchecked::<String>();
+	if name.is_empty() || name.contains(['=', '\0']) || value.contains('\0') {
+		return Err(format!("Invalid environment variable '{name}'"));
+	}
+	runtime.env.insert(name.clone(), value.clone());
+	Ok(Value::NULL)
+}
+
+/// `mod(a, b)` returns the floored remainder, which has the sign of the divisor (like Python) so `mod(-7, 3)` is `2`.
+///
+/// Unlike `%` which truncates, this is always in the range `0..b` for a positive `b`. As with `%`, a divisor of zero gives NaN.
+fn modulo(runtime: &mut Runtime, args: &[Value]) -> Result<Value, String> {
+	let (a, b) = (number_arg("mod", &args[0])?, number_arg("mod", &args[1])?);
+	if b == 0. && !runtime.ieee_division {
+		return Err(format!("Division by zero in mod({a}, 0)"));
+	}
+	let remainder = a % b;
+	Ok(Value::number(if remainder != 0. && (remainder < 0.) != (b < 0.) { remainder + b } else { remainder }))
+}
+
+/// `number(x)` converts a string to a number using the same syntax as number literals (with an optional leading `-`), returning null if it is invalid.
+///
+/// Whitespace around the number is ignored, numbers are returned unchanged and any other value gives null.
+fn number(_runtime: &mut Runtime, args: &[Value]) -> Result<Value, String> {
+	if args[0].is_number() {
+		return Ok(args[0]);
+	}
+	let string = args[0].as_obj();
+	let Some(string) = string.as_ref().and_then(ObjRef::as_str).map(|string| string.trim()) else {
+		return Ok(Value::NULL);
+	};
+	let number = match string.strip_prefix('-') {
+		Some(positive) => parse_number(positive).map(|number| match number.kind() {
+			ValueKind::Int(int) => Value::int(-int),
+			_ => Value::number(-number.as_f64().unwrap_or_default()),
+		}),
+		None => parse_number(string),
+	};
+	Ok(number.unwrap_or(Value::NULL))
+}
+
+/// `bool(x)` converts the value to a bool based on whether it is truthy
+fn bool(_runtime: &mut Runtime, args: &[Value]) -> Result<Value, String> {
+	Ok(Value::bool(args[0].truthy()))
+}
+
+/// `format("({}, {})", x, y)` replaces each `{}` with the next argument, with `{{` and `}}` giving literal braces
+fn format(runtime: &mut Runtime, args: &[Value]) -> Result<Value, String> {
+	let template = string_arg("format", &args[0])?;
+	let template = template.as_ref_unchecked::<String>();
+	let values = &args[1..];
+	let mut result = String::with_capacity(template.len());
+	let mut placeholders = 0;
+	let mut chars = template.chars().peekable();
+	while let Some(c) = chars.next() {
+		match c {
+			'{' if chars.next_if_eq(&'{').is_some() => result.push('{'),
+			'}' if chars.next_if_eq(&'}').is_some() => result.push('}'),
+			'{' if chars.next_if_eq(&'}').is_some() => {
+				if let Some(value) = values.get(placeholders) {
+					result.push_str(&value.to_string());
+				}
+				placeholders += 1;
+			}
+			'{' | '}' => return Err(format!("Unmatched '{c}' in format string (use '{c}{c}' for a literal brace)")),
+			c => result.push(c),
+		}
+	}
+	if placeholders != values.len() {
+		return Err(format!("Format string has {placeholders} placeholders but {} arguments were given", values.len()));
+	}
+	Ok(Value::obj(runtime.new_string(result)))
+}
+
+/// Reads an argument that must be an integer in the specified range, such as a number of digits
+fn integer_arg(native: &str, value: &Value, range: RangeInclusive<usize>) -> Result<usize, String> {
+	let number = number_arg(native, value)?;
+	if number.fract() != 0. || !(*range.start() as f64..=*range.end() as f64).contains(&number) {
+		return Err(format!("Expected an integer from {} to {} as an argument to '{native}' but got {number}", range.start(), range.end()));
+	}
+	Ok(number as usize)
+}
+
+/// `to_fixed(x, digits)` formats the number with the specified number of decimal places.
+///
+/// The exact binary value is rounded, with ties going to even (so `to_fixed(0.125, 2)` is `"0.12"`).
+fn to_fixed(runtime: &mut Runtime, args: &[Value]) -> Result<Value, String> {
+	let number = number_arg("to_fixed", &args[0])?;
+	let digits = integer_arg("to_fixed", &args[1], 0..=100)?;
+	Ok(Value::obj(runtime.new_string(format!("{number:.digits$}"))))
+}
+
+/// `to_precision(x, sig)` formats the number with the specified number of significant figures, using the same rounding as `to_fixed`.
+///
+/// Very large or small numbers use exponential notation, e.g. `1.2e9`.
+fn to_precision(runtime: &mut Runtime, args: &[Value]) -> Result<Value, String> {
+	let number = number_arg("to_precision", &args[0])?;
+	let significant = integer_arg("to_precision", &args[1], 1..=100)?;
+	let exponential = format!("{number:.*e}", significant - 1);
+	let result = match exponential.split_once('e').and_then(|(_, exponent)| exponent.parse::<i32>().ok()) {
+		Some(exponent) if number.is_finite() && (-6..significant as i32).contains(&exponent) => {
+			let decimals = (significant as i32 - 1 - exponent).max(0) as usize;
+			format!("{number:.decimals$}")
+		}
+		_ if number.is_finite() => exponential,
+		_ => number.to_string(),
+	};
+	Ok(Value::obj(runtime.new_string(result)))
+}
+
+/// `hash(v)` returns a number derived from the value, which is the same for equal values and deterministic across runs.
+///
+/// Strings hash their contents, and numbers, bools and null hash their bits with a type tag. The result is an integer below 2^53 (or [`Value::MAX_INT`] if that is smaller) so it is exactly representable.
+fn hash(_runtime: &mut Runtime, args: &[Value]) -> Result<Value, String> {
+	/// FNV-1a is used because it has no random key, unlike the hashers in the standard library
+	fn fnv1a(tag: u8, bytes: &[u8]) -> u64 {
+		let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+		for &byte in core::iter::once(&tag).chain(bytes) {
+			hash = (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3);
+		}
+		hash
+	}
+	let hash = match args[0].kind() {
+		ValueKind::Null => fnv1a(0, &[]),
+		ValueKind::Bool(value) => fnv1a(1, &[value as u8]),
+		// Negative zero is equal to zero, so must have the same hash
+		ValueKind::Number(number) => fnv1a(2, &(if number == 0. { 0. } else { number }).to_bits().to_le_bytes()),
+		// Ints are equal to the number with the same value
+		ValueKind::Int(int) => fnv1a(2, &(int as f64).to_bits().to_le_bytes()),
+		ValueKind::Obj(obj) => match obj.as_ref::<String>() {
+			Some(string) => fnv1a(3, string.as_bytes()),
+			None => return Err(format!("Cannot hash {:?}", args[0])),
+		},
+	};
+	// With `nan_boxing` ints have fewer bits, so the top bits are dropped to stay in range
+	Ok(Value::int((hash >> 11) as i64 & Value::MAX_INT))
+}
+
+/// `id(v)` returns a number that is equal for two values exactly when they are the same heap object.
+///
+/// Values that are not on the heap (numbers, bools and null) have no identity, so return `0`. The number is opaque and should not be relied on beyond comparing it to other ids while the objects are alive.
+fn id(_runtime: &mut Runtime, args: &[Value]) -> Result<Value, String> {
+	Ok(Value::int(args[0].as_obj().map_or(0, |obj| obj.addr() as i64)))
+}
+
+/// `gc()` forces a garbage collection, returning the number of objects freed
+fn gc(runtime: &mut Runtime, _args: &[Value]) -> Result<Value, String> {
+	Ok(Value::int(runtime.collect_garbage() as i64))
+}
+
+/// `mem_stats()` prints the number of live objects, interned strings, globals, stack values and bytes allocated
+fn mem_stats(runtime: &mut Runtime, _args: &[Value]) -> Result<Value, String> {
+	let stats = runtime.mem_stats();
+	writeln!(runtime.output, "{stats}").map_err(|err| format!("Failed to print memory stats: {err}"))?;
+	Ok(Value::NULL)
+}
+
+/// `eval(source)` compiles and runs the source in the current runtime (so it shares the globals), returning the value of the final expression
+fn eval(runtime: &mut Runtime, args: &[Value]) -> Result<Value, String> {
+	let source = string_arg("eval", &args[0])?.as_ref_unchecked::<String>().clone();
+	let mut chunk = Chunk::new();
+	if !Parser::compile_eval(&source, &mut chunk) {
+		return Err("Failed to compile the source passed to 'eval'".to_string());
+	}
+	// There is nothing to run (or any line to attach a return to) if the source is empty
+	if chunk.is_empty() {
+		return Ok(Value::NULL);
+	}
+	// The objects are moved to the runtime since the result or a global may still reference them after the chunk is dropped
+	runtime.adopt_objects(&mut chunk);
+
+	// The instruction pointer is saved so the outer chunk can resume where it left off
+	let (outer_ip, outer_instruction, outer_base) = (runtime.ip, runtime.instruction, runtime.frame_base);
+	// The locals of the nested chunk start above everything the outer chunk has on the stack
+	runtime.frame_base = runtime.stack_height();
+	let outer_chunk = runtime.load_chunk(chunk);
+	runtime.outer_chunks.push(outer_chunk);
+	let result = runtime.interpret().and_then(|_| runtime.pop_stack());
+	let outer_chunk = runtime.outer_chunks.pop().expect("the outer chunk was pushed before interpreting");
+	runtime.load_chunk(outer_chunk);
+	runtime.ip = outer_ip;
+	runtime.instruction = outer_instruction;
+	runtime.frame_base = outer_base;
+	match result {
+		Ok(value) => Ok(value),
+		Err(InterpretError::Exit(code)) => {
+			// A native can only return a value or an error message, so the exit is left for the call to `eval` to pass on
+			runtime.eval_exit = Some(code);
+			Ok(Value::NULL)
+		}
+		Err(_) => Err("Runtime error inside of 'eval'".to_string()),
+	}
+}
+
+/// `matches(s, pattern)` checks if the regex pattern matches anywhere in the string
+#[cfg(feature = "regex")]
+fn matches(runtime: &mut Runtime, args: &[Value]) -> Result<Value, String> {
+	let haystack = string_arg("matches", &args[0])?;
+	let haystack = haystack.as_ref_unchecked::<String>();
+	let pattern = string_arg("matches", &args[1])?;
+	let pattern = pattern.as_ref_unchecked::<String>();
+	Ok(Value::bool(runtime.regex(pattern)?.is_match(haystack)))
+}
+
+/// `find(s, pattern)` returns the first substring matching the regex pattern or null
+#[cfg(feature = "regex")]
+fn find(runtime: &mut Runtime, args: &[Value]) -> Result<Value, String> {
+	let haystack = string_arg("find", &args[0])?;
+	let haystack = haystack.as_ref_unchecked::<String>();
+	let pattern = string_arg("find", &args[1])?;
+	let pattern = pattern.as_ref_unchecked::<String>();
+	let found = runtime.regex(pattern)?.find(haystack).map(|found| found.as_str().to_string());
+	Ok(found.map_or(Value::NULL, |found| Value::obj(runtime.new_string(found))))
+}
+
+/// `json_parse(s)` converts JSON text into a value
+fn json_parse(runtime: &mut Runtime, args: &[Value]) -> Result<Value, String> {
+	let source = string_arg("json_parse", &args[0])?;
+	let json = json::parse(source.as_ref_unchecked::<String>())?;
+	json_value(runtime, json)
+}
+
+/// Allocates the strings and arrays of parsed JSON
+fn json_value(runtime: &mut Runtime, json: json::Json) -> Result<Value, String> {
+	Ok(match json {
+		json::Json::Null => Value::NULL,
+		json::Json::Bool(value) => Value::bool(value),
+		json::Json::Number(number) => Value::number(number),
+		json::Json::String(string) => Value::obj(runtime.new_string(string)),
+		json::Json::Array(items) => {
+			// The array is allocated at its full length and kept on the stack, so it roots the elements already converted while the rest are allocated
+			let mut array = runtime.new_object(vec![Value::NULL; items.len()]);
+			runtime.push_stack(Value::obj(array)).map_err(|_| "Stack overflow while converting JSON".to_string())?;
+			for (index, item) in items.into_iter().enumerate() {
+				let value = json_value(runtime, item)?;
+				array.as_mut_unchecked::<Vec<Value>>()[index] = value;
+			}
+			runtime.drop_stack(1);
+			Value::obj(array)
+		}
+	})
+}
+
+/// `json_stringify(v)` converts a value into JSON text
+fn json_stringify(runtime: &mut Runtime, args: &[Value]) -> Result<Value, String> {
+	let text = json::stringify(&args[0])?;
+	Ok(Value::obj(runtime.new_string(text)))
+}
+
+#[cfg(feature = "regex")]
+impl Runtime {
+	/// Compiles the pattern, caching it so patterns used in loops are only compiled once
+	fn regex(&mut self, pattern: &str) -> Result<&regex::Regex, String> {
+		if !self.regexes.contains_key(pattern) {
+			let regex = regex::Regex::new(pattern).map_err(|e| format!("Invalid regex pattern: {e}"))?;
+			self.regexes.insert(pattern.to_string(), regex);
+		}
+		Ok(&self.regexes[pattern])
+	}
+}
+
+#[cfg(not(feature = "regex"))]
+fn matches(_runtime: &mut Runtime, _args: &[Value]) -> Result<Value, String> {
+	Err("'matches' requires the regex feature".to_string())
+}
+
+#[cfg(not(feature = "regex"))]
+fn find(_runtime: &mut Runtime, _args: &[Value]) -> Result<Value, String> {
+	Err("'find' requires the regex feature".to_string())
+}
+
+#[cfg(test)]
+fn call(runtime: &mut Runtime, name: &str, args: &[Value]) -> Result<Value, String> {
+	(NATIVES[find_native(name).unwrap()].function)(runtime, args)
+}
+
+/// Allocates a string for a test, pushing it to the stack so it is rooted even if a later allocation collects garbage
+#[cfg(test)]
+fn string(runtime: &mut Runtime, string: &str) -> Value {
+	let value = Value::obj(runtime.new_string(string.to_string()));
+	runtime.push_stack(value).unwrap();
+	value
+}
+
+#[test]
+#[cfg(feature = "regex")]
+fn regex() {
+	let mut runtime = Runtime::new(Chunk::new());
+	let [found, missing, pattern, invalid] = ["error 404", "error", "[0-9]+", "(unclosed"].map(|arg| string(&mut runtime, arg));
+	assert_eq!(call(&mut runtime, "matches", &[found, pattern]), Ok(Value::bool(true)));
+	assert_eq!(call(&mut runtime, "matches", &[missing, pattern]), Ok(Value::bool(false)));
+	let expected = string(&mut runtime, "404");
+	assert_eq!(call(&mut runtime, "find", &[found, pattern]), Ok(expected));
+	assert_eq!(call(&mut runtime, "find", &[missing, pattern]), Ok(Value::NULL));
+	assert!(call(&mut runtime, "find", &[missing, invalid]).unwrap_err().contains("Invalid regex pattern"));
+
+	assert!(!Parser::compile(r#"let x = matches("a");"#, &mut Chunk::new()));
+	assert!(Parser::compile(r#"let x = matches("a", "b");"#, &mut Chunk::new()));
+}
+
+#[test]
+fn ord_chr() {
+	let mut runtime = Runtime::new(Chunk::new());
+	let [a, e_acute, empty, ab] = ["A", "é", "", "ab"].map(|arg| string(&mut runtime, arg));
+	assert_eq!(call(&mut runtime, "ord", &[a]), Ok(Value::number(65.)));
+	assert_eq!(call(&mut runtime, "ord", &[e_acute]), Ok(Value::number(233.)));
+	assert_eq!(
+		call(&mut runtime, "ord", &[empty]),
+		Err("Expected a single character string to 'ord' but got a length of 0".to_string())
+	);
+	assert_eq!(call(&mut runtime, "ord", &[ab]), Err("Expected a single character string to 'ord' but got a length of 2".to_string()));
+
+	assert_eq!(call(&mut runtime, "chr", &[Value::number(65.)]), Ok(a));
+	assert_eq!(call(&mut runtime, "chr", &[Value::number(233.)]), Ok(e_acute));
+	assert!(call(&mut runtime, "chr", &[Value::number(0xD800 as f64)]).is_err());
+	assert!(call(&mut runtime, "chr", &[Value::number(0x110000 as f64)]).is_err());
+	assert!(call(&mut runtime, "chr", &[Value::number(-1.)]).is_err());
+	assert!(call(&mut runtime, "chr", &[Value::number(65.5)]).is_err());
+	assert!(call(&mut runtime, "chr", &[a]).is_err());
+}
+
+#[test]
+fn random_seed() {
+	let mut runtime = Runtime::new(Chunk::new());
+	let mut sequence = || {
+		call(&mut runtime, "seed", &[Value::number(42.)]).unwrap();
+		(0..10).map(|_| call(&mut runtime, "random", &[]).unwrap()).collect::<Vec<_>>()
+	};
+	let first = sequence();
+	assert_eq!(first, sequence());
+	assert!(first.iter().all(|value| matches!(value.kind(), ValueKind::Number(n) if (0. ..1.).contains(&n))));
+
+	for _ in 0..100 {
+		let Some(n) = call(&mut runtime, "random_int", &[Value::int(-2), Value::int(2)]).ok().and_then(|n| n.as_int()) else {
+			panic!()
+		};
+		assert!((-2..=2).contains(&n));
+	}
+	assert_eq!(call(&mut runtime, "random_int", &[Value::number(3.), Value::number(3.)]), Ok(Value::number(3.)));
+	assert!(call(&mut runtime, "random_int", &[Value::number(3.), Value::number(1.)]).is_err());
+	assert!(call(&mut runtime, "random_int", &[Value::number(0.5), Value::number(1.)]).is_err());
+}
+
+#[test]
+fn sleep_clock() {
+	let mut runtime = Runtime::new(Chunk::new());
+	let Some(start) = call(&mut runtime, "clock", &[]).ok().and_then(|start| start.as_number()) else {
+		panic!()
+	};
+	assert_eq!(call(&mut runtime, "sleep", &[Value::number(50.)]), Ok(Value::NULL));
+	let Some(end) = call(&mut runtime, "clock", &[]).ok().and_then(|end| end.as_number()) else {
+		panic!()
+	};
+	assert!(end - start >= 0.05, "slept for {}s", end - start);
+
+	assert!(call(&mut runtime, "sleep", &[Value::number(-1.)]).is_err());
+	assert!(call(&mut runtime, "sleep", &[Value::number(f64::NAN)]).is_err());
+	assert!(call(&mut runtime, "sleep", &[Value::NULL]).is_err());
+	assert!(call(&mut runtime, "sleep", &[Value::number(1e40)]).is_err());
+
+	let mut runtime = Runtime::new(Chunk::new()).with_max_sleep(Duration::from_millis(10));
+	assert_eq!(call(&mut runtime, "sleep", &[Value::number(10.)]), Ok(Value::NULL));
+	assert_eq!(
+		call(&mut runtime, "sleep", &[Value::number(10.5)]),
+		Err("Cannot sleep for 10.5 milliseconds, the limit is 10 milliseconds".to_string())
+	);
+}
+
+#[test]
+fn file_io() {
+	let path = std::env::temp_dir().join(format!("interpreter_file_io_{}.txt", std::process::id()));
+	let path = path.to_str().unwrap();
+
+	let mut sandboxed = Runtime::new(Chunk::new());
+	let [file, hello] = [path, "hello"].map(|arg| string(&mut sandboxed, arg));
+	assert!(call(&mut sandboxed, "write_file", &[file, hello]).unwrap_err().contains("file system access"));
+	assert!(call(&mut sandboxed, "read_file", &[file]).unwrap_err().contains("file system access"));
+	assert!(!std::path::Path::new(path).exists());
+
+	let mut runtime = Runtime::new(Chunk::new()).with_fs_access(true);
+	let [file, hello, world, expected] = [path, "hello", " world", "hello world"].map(|arg| string(&mut runtime, arg));
+	assert_eq!(call(&mut runtime, "write_file", &[file, hello]), Ok(Value::NULL));
+	assert_eq!(call(&mut runtime, "append_file", &[file, world]), Ok(Value::NULL));
+	assert_eq!(call(&mut runtime, "read_file", &[file]), Ok(expected));
+	std::fs::remove_file(path).unwrap();
+	assert!(call(&mut runtime, "read_file", &[file]).unwrap_err().starts_with("Could not read file"));
+}
+
+#[test]
+fn environment() {
+	let mut runtime = Runtime::new(Chunk::new()).with_fs_access(true);
+	let [name, value, unset, invalid] = ["INTERPRETER_ENV_TEST", "set by script", "INTERPRETER_ENV_TEST_UNSET", "A=B"].map(|arg| string(&mut runtime, arg));
+	assert_eq!(call(&mut runtime, "env", &[unset]), Ok(Value::NULL));
+	assert_eq!(call(&mut runtime, "set_env", &[name, value]), Ok(Value::NULL));
+	assert_eq!(call(&mut runtime, "env", &[name]), Ok(value));
+	assert!(call(&mut runtime, "set_env", &[invalid, value]).is_err());
+	// The variable is only set for the runtime, leaving the environment of the process (and other tests running in parallel) alone
+	assert!(std::env::var("INTERPRETER_ENV_TEST").is_err());
+	let mut other = Runtime::new(Chunk::new());
+	let name = string(&mut other, "INTERPRETER_ENV_TEST");
+	assert_eq!(call(&mut other, "env", &[name]), Ok(Value::NULL));
+
+	let mut sandboxed = Runtime::new(Chunk::new());
+	let [name, value] = ["INTERPRETER_ENV_TEST", "sandboxed"].map(|arg| string(&mut sandboxed, arg));
+	assert!(call(&mut sandboxed, "set_env", &[name, value]).is_err());
+}
+
+#[test]
+fn conversions() {
+	let mut runtime = Runtime::new(Chunk::new());
+	for (input, expected) in [
+		("42", Value::number(42.)),
+		("4_000.25", Value::number(4000.25)),
+		("  -7 \n", Value::number(-7.)),
+		("abc", Value::NULL),
+		("", Value::NULL),
+		("3.", Value::NULL),
+		(".5", Value::NULL),
+		("1 2", Value::NULL),
+		("--1", Value::NULL),
+	] {
+		let input = string(&mut runtime, input);
+		assert_eq!(call(&mut runtime, "number", &[input]), Ok(expected), "{input:?}");
+	}
+	assert_eq!(call(&mut runtime, "number", &[Value::NULL]), Ok(Value::NULL));
+	assert_eq!(call(&mut runtime, "number", &[Value::number(1.5)]), Ok(Value::number(1.5)));
+	assert_eq!(call(&mut runtime, "number", &[Value::bool(true)]), Ok(Value::NULL));
+
+	let empty = string(&mut runtime, "");
+	for (input, expected) in [(Value::NULL, false), (Value::bool(false), false), (Value::bool(true), true), (Value::number(0.), true), (empty, true)] {
+		assert_eq!(call(&mut runtime, "bool", &[input]), Ok(Value::bool(expected)), "{input:?}");
+	}
+}
+
+#[test]
+fn format_placeholders() {
+	let mut runtime = Runtime::new(Chunk::new());
+	let [point, plain, escaped, name, expected_point, expected_escaped, unmatched] = ["({}, {})", "no placeholders", "{{{}}}", "x", "(1, true)", "{x}", "{"].map(|arg| string(&mut runtime, arg));
+	assert_eq!(call(&mut runtime, "format", &[point, Value::number(1.), Value::bool(true)]), Ok(expected_point));
+	assert_eq!(call(&mut runtime, "format", &[plain]), Ok(plain));
+	assert_eq!(call(&mut runtime, "format", &[escaped, name]), Ok(expected_escaped));
+	assert_eq!(
+		call(&mut runtime, "format", &[point, Value::NULL]),
+		Err("Format string has 2 placeholders but 1 arguments were given".to_string())
+	);
+	assert_eq!(
+		call(&mut runtime, "format", &[plain, Value::NULL]),
+		Err("Format string has 0 placeholders but 1 arguments were given".to_string())
+	);
+	assert!(call(&mut runtime, "format", &[unmatched]).is_err());
+}
+
+#[test]
+fn number_formatting() {
+	let mut runtime = Runtime::new(Chunk::new());
+	let mut fixed = |number: f64, digits: i32| call(&mut runtime, "to_fixed", &[Value::number(number), Value::number(digits as f64)]).map(|value| value.to_string());
+	assert_eq!(fixed(0.1 + 0.2, 2), Ok("0.30".to_string()));
+	assert_eq!(fixed(3.24159, 0), Ok("3".to_string()));
+	assert_eq!(fixed(-1.5, 3), Ok("-1.500".to_string()));
+	// Ties round to even
+	assert_eq!(fixed(0.125, 2), Ok("0.12".to_string()));
+	assert_eq!(fixed(0.375, 2), Ok("0.38".to_string()));
+	assert_eq!(fixed(2.5, 0), Ok("2".to_string()));
+	assert!(fixed(1., -1).is_err());
+	assert!(fixed(1., 101).is_err());
+
+	let mut precision = |number: f64, digits: i32| call(&mut runtime, "to_precision", &[Value::number(number), Value::number(digits as f64)]).map(|value| value.to_string());
+	assert_eq!(precision(123.456, 4), Ok("123.5".to_string()));
+	assert_eq!(precision(0.000123456, 2), Ok("0.00012".to_string()));
+	assert_eq!(precision(9.99, 2), Ok("10".to_string()));
+	assert_eq!(precision(123456., 2), Ok("1.2e5".to_string()));
+	assert_eq!(precision(0., 3), Ok("0.00".to_string()));
+	assert_eq!(precision(f64::INFINITY, 3), Ok("inf".to_string()));
+	assert!(precision(1., 0).is_err());
+	assert!(call(&mut runtime, "to_fixed", &[Value::number(1.), Value::number(1.5)]).is_err());
+}
+
+#[test]
+fn value_hash() {
+	let mut runtime = Runtime::new(Chunk::new());
+	let mut hash = |value: Value| call(&mut runtime, "hash", &[value]).unwrap();
+	assert_eq!(hash(Value::number(1.)), hash(Value::number(1.)));
+	assert_eq!(hash(Value::number(0.)), hash(Value::number(-0.)));
+	assert_eq!(hash(Value::NULL), hash(Value::NULL));
+	assert_ne!(hash(Value::number(1.)), hash(Value::number(2.)));
+	assert_ne!(hash(Value::bool(false)), hash(Value::NULL));
+
+	// Strings with the same contents are equal even if they are separate objects
+	let (first, first_owned) = ObjRef::new("hello".to_string());
+	let (second, second_owned) = ObjRef::new("hello".to_string());
+	let (other, other_owned) = ObjRef::new("world".to_string());
+	assert_eq!(hash(Value::obj(first)), hash(Value::obj(second)));
+	assert_ne!(hash(Value::obj(first)), hash(Value::obj(other)));
+	drop([first_owned, second_owned, other_owned]);
+
+	// The hash is deterministic, so can be used in golden tests
+	assert_eq!(hash(Value::bool(true)), Value::number(287953414167825.));
+}
+
+#[test]
+fn object_id() {
+	let mut runtime = Runtime::new(Chunk::new());
+	let (hello, world) = (string(&mut runtime, "hello"), string(&mut runtime, "world"));
+	// Strings created at runtime are interned, so equal strings are the same object
+	let interned = string(&mut runtime, "hello");
+	assert_eq!(call(&mut runtime, "id", &[hello]), call(&mut runtime, "id", &[interned]));
+	assert_ne!(call(&mut runtime, "id", &[hello]), call(&mut runtime, "id", &[world]));
+	assert_ne!(call(&mut runtime, "id", &[hello]), Ok(Value::number(0.)));
+
+	assert_eq!(call(&mut runtime, "id", &[Value::number(3.)]), Ok(Value::number(0.)));
+	assert_eq!(call(&mut runtime, "id", &[Value::NULL]), Ok(Value::number(0.)));
+}
+
+#[test]
+#[cfg(feature = "gc")]
+fn garbage_collection() {
+	let mut runtime = Runtime::new(Chunk::new());
+	for index in 0..10 {
+		runtime.new_string(format!("garbage {index}"));
+	}
+	let kept = string(&mut runtime, "kept");
+	let global = runtime.new_string("global".to_string());
+	runtime.globals.insert(global, Value::obj(global));
+	// In stress mode each allocation has already freed the garbage before it
+	let garbage = if cfg!(feature = "gc_stress") { 0 } else { 10 };
+	assert_eq!(runtime.objects.len(), garbage + 2);
+
+	assert_eq!(call(&mut runtime, "gc", &[]), Ok(Value::int(garbage as i64)));
+	assert_eq!(runtime.objects.len(), 2);
+	assert_eq!(runtime.strings.len(), 2);
+	// The surviving strings are still interned
+	assert_eq!(string(&mut runtime, "kept"), kept);
+	assert_eq!(call(&mut runtime, "gc", &[]), Ok(Value::number(0.)));
+}
+
+#[test]
+fn memory_stats() {
+	let mut runtime = Runtime::new(Chunk::new()).with_output(std::io::sink());
+	let before = runtime.mem_stats();
+	assert_eq!(
+		before,
+		MemStats {
+			objects: 0,
+			strings: 0,
+			globals: 0,
+			stack: 0,
+			bytes: 0
+		}
+	);
+	for index in 0..5 {
+		string(&mut runtime, &format!("string {index}"));
+	}
+	let after = runtime.mem_stats();
+	assert_eq!((after.objects, after.strings), (5, 5));
+	assert!(after.bytes > before.bytes);
+	assert_eq!(call(&mut runtime, "mem_stats", &[]), Ok(Value::NULL));
+
+	#[cfg(feature = "gc")]
+	{
+		runtime.reset_stack();
+		runtime.collect_garbage();
+		assert_eq!(runtime.mem_stats(), before);
+	}
+}
+
+#[test]
+fn eval_source() {
+	init_logger();
+	let mut runtime = Runtime::new(Chunk::new());
+	let source = string(&mut runtime, "7");
+	assert_eq!(call(&mut runtime, "eval", &[source]), Ok(Value::number(7.)));
+	let source = string(&mut runtime, "");
+	assert_eq!(call(&mut runtime, "eval", &[source]), Ok(Value::NULL));
+	let source = string(&mut runtime, "let x = 1;");
+	assert_eq!(call(&mut runtime, "eval", &[source]), Ok(Value::NULL));
+	// Globals are shared with the code that called eval
+	let source = string(&mut runtime, "x");
+	assert_eq!(call(&mut runtime, "eval", &[source]), Ok(Value::number(1.)));
+	// Strings outlive the chunk they were compiled in
+	let source = string(&mut runtime, r#""hello""#);
+	let hello = call(&mut runtime, "eval", &[source]).unwrap();
+	assert_eq!(hello.to_string(), "hello");
+
+	let source = string(&mut runtime, "let;");
+	assert!(call(&mut runtime, "eval", &[source]).is_err());
+	let source = string(&mut runtime, "-true");
+	assert!(call(&mut runtime, "eval", &[source]).is_err());
+	let source = string(&mut runtime, "1 1");
+	assert!(call(&mut runtime, "eval", &[source]).is_err());
+}
+
+#[test]
+fn floored_modulo() {
+	let mut runtime = Runtime::new(Chunk::new());
+	let mut modulo = |a: f64, b: f64| call(&mut runtime, "mod", &[Value::number(a), Value::number(b)]).unwrap();
+	assert_eq!(modulo(7., 3.), Value::number(1.));
+	assert_eq!(modulo(-7., 3.), Value::number(2.));
+	assert_eq!(modulo(7., -3.), Value::number(-2.));
+	assert_eq!(modulo(-7., -3.), Value::number(-1.));
+	assert_eq!(modulo(6., -3.), Value::number(0.));
+	assert_eq!(modulo(5.5, 2.), Value::number(1.5));
+
+	assert!(call(&mut runtime, "mod", &[Value::number(1.), Value::number(0.)]).is_err());
+	assert!(call(&mut runtime, "mod", &[Value::NULL, Value::number(1.)]).is_err());
+	let mut runtime = Runtime::new(Chunk::new()).with_ieee_division(true);
+	assert!(matches!(call(&mut runtime, "mod", &[Value::number(1.), Value::number(0.)]), Ok(n) if n.as_number().is_some_and(f64::is_nan)));
+}
@@ -0,0 +1,305 @@
+//! Conversion between JSON text and values.
+//!
+//! Scalars and arrays convert both ways, and structs are written as objects. JSON objects can't be parsed until the language gains maps.
+//! A value that contains itself is an error rather than being written forever.
+
+use core::{iter::Peekable, str::CharIndices};
+
+use crate::bytecode::prelude::*;
+
+/// Arrays and structs nested deeper than this are an error, so that converting them can't overflow the stack
+const MAX_DEPTH: usize = 256;
+
+/// Writes the value as JSON text
+pub fn stringify(value: &Value) -> Result<String, String> {
+	let mut result = String::new();
+	write(value, &mut result, &mut Vec::new())?;
+	Ok(result)
+}
+
+/// Writes the value as JSON to `result`, where `parents` are the arrays and structs containing it
+fn write(value: &Value, result: &mut String, parents: &mut Vec<ObjRef>) -> Result<(), String> {
+	match value.kind() {
+		ValueKind::Null => result.push_str("null"),
+		ValueKind::Bool(value) => result.push_str(&value.to_string()),
+		ValueKind::Number(number) if number.is_finite() => result.push_str(&number.to_string()),
+		ValueKind::Int(int) => result.push_str(&int.to_string()),
+		ValueKind::Number(number) => return Err(format!("Cannot convert {number} to JSON")),
+		ValueKind::Obj(obj) => {
+			if let Some(string) = obj.as_ref::<String>() {
+				result.push_str(&escape(string));
+				return Ok(());
+			}
+			if parents.contains(&obj) {
+				return Err("Cannot convert a value that contains itself to JSON".to_string());
+			}
+			if parents.len() >= MAX_DEPTH {
+				return Err(format!("Cannot convert values nested more than {MAX_DEPTH} deep to JSON"));
+			}
+			parents.push(obj);
+			if let Some(values) = obj.as_ref::<Vec<Value>>() {
+				result.push('[');
+				for (index, value) in values.iter().enumerate() {
+					if index != 0 {
+						result.push(',');
+					}
+					write(value, result, parents)?;
+				}
+				result.push(']');
+			} else if let Some(instance) = obj.as_ref::<Struct>() {
+				result.push('{');
+				for (index, (name, value)) in instance.layout.fields.iter().zip(&instance.fields).enumerate() {
+					if index != 0 {
+						result.push(',');
+					}
+					result.push_str(&escape(name.as_str().unwrap_or_default()));
+					result.push(':');
+					write(value, result, parents)?;
+				}
+				result.push('}');
+			} else {
+				return Err(format!("Cannot convert {value:?} to JSON"));
+			}
+			parents.pop();
+		}
+	}
+	Ok(())
+}
+
+/// Surrounds the string in quotes, escaping any characters that JSON does not allow inside of a string
+fn escape(string: &str) -> String {
+	let mut result = String::with_capacity(string.len() + 2);
+	result.push('"');
+	for c in string.chars() {
+		match c {
+			'"' => result.push_str("\\\""),
+			'\\' => result.push_str("\\\\"),
+			'\n' => result.push_str("\\n"),
+			'\r' => result.push_str("\\r"),
+			'\t' => result.push_str("\\t"),
+			'\x08' => result.push_str("\\b"),
+			'\x0C' => result.push_str("\\f"),
+			c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+			c => result.push(c),
+		}
+	}
+	result.push('"');
+	result
+}
+
+/// A value parsed from JSON text, before any strings are allocated on the heap
+#[derive(Debug, PartialEq)]
+pub enum Json {
+	Null,
+	Bool(bool),
+	Number(f64),
+	String(String),
+	Array(Vec<Json>),
+}
+
+/// A simple recursive descent JSON parser
+struct JsonParser<'a> {
+	source: &'a str,
+	chars: Peekable<CharIndices<'a>>,
+}
+
+impl<'a> JsonParser<'a> {
+	/// Creates an error message at the current position
+	fn error(&mut self, message: &str) -> String {
+		let position = self.chars.peek().map_or(self.source.len(), |&(index, _)| index);
+		format!("Invalid JSON at byte {position}: {message}")
+	}
+
+	fn skip_whitespace(&mut self) {
+		while self.chars.next_if(|(_, c)| matches!(c, ' ' | '\t' | '\n' | '\r')).is_some() {}
+	}
+
+	/// Consumes the specified keyword e.g. `true`
+	fn keyword(&mut self, keyword: &str, value: Json) -> Result<Json, String> {
+		for expected in keyword.chars() {
+			if self.chars.next_if(|&(_, c)| c == expected).is_none() {
+				return Err(self.error(&format!("Expected '{keyword}'")));
+			}
+		}
+		Ok(value)
+	}
+
+	fn number(&mut self, start: usize) -> Result<Json, String> {
+		let mut end = start;
+		while let Some((index, c)) = self.chars.next_if(|(_, c)| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')) {
+			end = index + c.len_utf8();
+		}
+		let text = &self.source[start..end];
+		let valid_start = text.strip_prefix('-').unwrap_or(text);
+		if !valid_start.starts_with(|c: char| c.is_ascii_digit()) || (valid_start.starts_with('0') && valid_start[1..].starts_with(|c: char| c.is_ascii_digit())) {
+			return Err(format!("Invalid JSON at byte {start}: Invalid number '{text}'"));
+		}
+		text.parse().map(Json::Number).map_err(|_| format!("Invalid JSON at byte {start}: Invalid number '{text}'"))
+	}
+
+	/// Reads the four hex digits of a `\u` escape
+	fn hex_escape(&mut self) -> Result<u32, String> {
+		let mut value = 0;
+		for _ in 0..4 {
+			match self.chars.next().and_then(|(_, c)| c.to_digit(16)) {
+				Some(digit) => value = value * 16 + digit,
+				None => return Err(self.error("Expected four hex digits in unicode escape")),
+			}
+		}
+		Ok(value)
+	}
+
+	fn string(&mut self) -> Result<Json, String> {
+		let mut result = String::new();
+		loop {
+			match self.chars.next() {
+				None => return Err(self.error("Unclosed string")),
+				Some((_, '"')) => return Ok(Json::String(result)),
+				Some((_, '\\')) => match self.chars.next() {
+					Some((_, '"')) => result.push('"'),
+					Some((_, '\\')) => result.push('\\'),
+					Some((_, '/')) => result.push('/'),
+					Some((_, 'b')) => result.push('\x08'),
+					Some((_, 'f')) => result.push('\x0C'),
+					Some((_, 'n')) => result.push('\n'),
+					Some((_, 'r')) => result.push('\r'),
+					Some((_, 't')) => result.push('\t'),
+					Some((_, 'u')) => {
+						let mut code = self.hex_escape()?;
+						// Characters outside of the basic multilingual plane are encoded as a surrogate pair
+						if (0xD800..0xDC00).contains(&code) {
+							if self.chars.next_if(|&(_, c)| c == '\\').is_none() || self.chars.next_if(|&(_, c)| c == 'u').is_none() {
+								return Err(self.error("Expected a low surrogate after a high surrogate"));
+							}
+							let low = self.hex_escape()?;
+							if !(0xDC00..0xE000).contains(&low) {
+								return Err(self.error("Invalid low surrogate"));
+							}
+							code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+						}
+						match char::from_u32(code) {
+							Some(c) => result.push(c),
+							None => return Err(self.error("Invalid unicode escape")),
+						}
+					}
+					_ => return Err(self.error("Invalid escape sequence")),
+				},
+				Some((_, c)) if (c as u32) < 0x20 => return Err(self.error("Control characters must be escaped")),
+				Some((_, c)) => result.push(c),
+			}
+		}
+	}
+
+	/// Parses the elements of an array after the opening `[`, which is nested `depth` arrays deep
+	fn array(&mut self, depth: usize) -> Result<Json, String> {
+		let mut items = Vec::new();
+		self.skip_whitespace();
+		if self.chars.next_if(|&(_, c)| c == ']').is_some() {
+			return Ok(Json::Array(items));
+		}
+		loop {
+			items.push(self.value(depth + 1)?);
+			self.skip_whitespace();
+			match self.chars.peek() {
+				Some((_, ',')) => self.chars.next(),
+				Some((_, ']')) => {
+					self.chars.next();
+					return Ok(Json::Array(items));
+				}
+				_ => return Err(self.error("Expected ',' or ']' after an array element")),
+			};
+		}
+	}
+
+	/// Parses a value, which is nested inside of `depth` arrays
+	fn value(&mut self, depth: usize) -> Result<Json, String> {
+		self.skip_whitespace();
+		match self.chars.peek().copied() {
+			Some((_, 'n')) => self.keyword("null", Json::Null),
+			Some((_, 't')) => self.keyword("true", Json::Bool(true)),
+			Some((_, 'f')) => self.keyword("false", Json::Bool(false)),
+			Some((_, '"')) => {
+				self.chars.next();
+				self.string()
+			}
+			Some((start, c)) if c == '-' || c.is_ascii_digit() => self.number(start),
+			Some((_, '[')) if depth >= MAX_DEPTH => Err(self.error(&format!("Arrays nested more than {MAX_DEPTH} deep are not supported"))),
+			Some((_, '[')) => {
+				self.chars.next();
+				self.array(depth)
+			}
+			Some((_, '{')) => Err(self.error("Objects are not supported until the language has maps")),
+			Some(_) => Err(self.error("Expected a value")),
+			None => Err(self.error("Expected a value but found the end")),
+		}
+	}
+}
+
+/// Parses the JSON text, which must contain exactly one value
+pub fn parse(source: &str) -> Result<Json, String> {
+	let mut parser = JsonParser {
+		source,
+		chars: source.char_indices().peekable(),
+	};
+	let value = parser.value(0)?;
+	parser.skip_whitespace();
+	if parser.chars.peek().is_some() {
+		return Err(parser.error("Unexpected text after the value"));
+	}
+	Ok(value)
+}
+
+#[test]
+fn json_numbers() {
+	for number in [0., -0.5, 0.1, 0.30000000000000004, 1e300, 123456789.123, -2.5e-310, f64::MAX] {
+		let text = stringify(&Value::number(number)).unwrap();
+		assert_eq!(parse(&text), Ok(Json::Number(number)), "{text}");
+	}
+	assert_eq!(parse(" 1.5e3 "), Ok(Json::Number(1500.)));
+	assert!(parse("01").is_err());
+	assert!(parse("1 2").is_err());
+	assert!(stringify(&Value::number(f64::NAN)).is_err());
+}
+
+#[test]
+fn json_strings() {
+	let (reference, owned) = ObjRef::new("say \"hi\"\n\\ \t\x01 é".to_string());
+	let text = stringify(&Value::obj(reference)).unwrap();
+	assert_eq!(text, r#""say \"hi\"\n\\ \t\u0001 é""#);
+	assert_eq!(parse(&text), Ok(Json::String(reference.as_ref::<String>().unwrap().clone())));
+	drop(owned);
+
+	assert_eq!(parse(r#""\u00e9\ud83c\udf0d\/""#), Ok(Json::String("é🌍/".to_string())));
+	assert_eq!(parse("{}"), Err("Invalid JSON at byte 0: Objects are not supported until the language has maps".to_string()));
+	assert_eq!(parse(r#""open"#), Err("Invalid JSON at byte 5: Unclosed string".to_string()));
+	assert!(parse(r#""\ud83c""#).is_err());
+	assert_eq!(parse("null"), Ok(Json::Null));
+	assert_eq!(parse("true"), Ok(Json::Bool(true)));
+}
+
+#[test]
+fn json_arrays() {
+	use Json::*;
+	assert_eq!(
+		parse(r#" [1, ["a", [] ], null] "#),
+		Ok(Array(vec![Number(1.), Array(vec![String("a".to_string()), Array(vec![])]), Null]))
+	);
+	assert_eq!(parse("[1,"), Err("Invalid JSON at byte 3: Expected a value but found the end".to_string()));
+	assert_eq!(parse("[1 2]"), Err("Invalid JSON at byte 3: Expected ',' or ']' after an array element".to_string()));
+	assert!(parse("[1,]").is_err());
+	assert!(parse(&"[".repeat(MAX_DEPTH)).is_err());
+	assert_eq!(
+		parse(&"[".repeat(MAX_DEPTH + 1)),
+		Err(format!("Invalid JSON at byte {MAX_DEPTH}: Arrays nested more than {MAX_DEPTH} deep are not supported"))
+	);
+
+	let (string, string_owned) = ObjRef::new("a".to_string());
+	let (inner, inner_owned) = ObjRef::new(vec![Value::obj(string), Value::number(0.5)]);
+	let (mut outer, outer_owned) = ObjRef::new(vec![Value::int(1), Value::obj(inner), Value::obj(inner), Value::NULL]);
+	// The same array can appear more than once as long as it doesn't contain itself
+	assert_eq!(stringify(&Value::obj(outer)).as_deref(), Ok(r#"[1,["a",0.5],["a",0.5],null]"#));
+	let this = Value::obj(outer);
+	outer.as_mut::<Vec<Value>>().unwrap().push(this);
+	assert_eq!(stringify(&Value::obj(outer)), Err("Cannot convert a value that contains itself to JSON".to_string()));
+	drop((string_owned, inner_owned, outer_owned));
+}
@@ -0,0 +1,8 @@
+#![allow(unused)]
+
+#[macro_use]
+extern crate log;
+
+mod bytecode;
+
+pub use bytecode::*;
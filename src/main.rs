@@ -1,17 +1,15 @@
 #![allow(unused)]
-#![feature(let_chains)]
 
 #[macro_use]
 extern crate log;
 
-mod bytecode;
 use std::path::Path;
 
-pub use bytecode::*;
+use interpreter::*;
 
 /// A simple CLI
 fn main() {
-	prelude::init_logger();
+	init_logger();
 
 	let mut args = std::env::args();
 	let mut path = args.next();
@@ -21,18 +19,24 @@ fn main() {
 		path = args.next();
 	}
 
+	// File system access must be explicitly allowed with `--allow-fs` before the path
+	let fs_access = path.as_deref() == Some("--allow-fs");
+	if fs_access {
+		path = args.next();
+	}
+
 	if let Some(path) = path {
 		// Error if the user has sent in too many arguments
 		if args.next().is_some() {
-			error!("Expected either path or nothing");
+			error!("Expected either [--allow-fs] path or nothing");
 			std::process::exit(66);
 		}
 		info!("Running file {}", path);
-		run_file(&path);
+		run_file(&path, fs_access);
 	} else {
 		// Start REPL if no arguments
 		info!("Welcome to the REPL");
-		info!("Press enter to exit");
-		repl();
+		info!("Press Ctrl+D to exit");
+		repl(fs_access);
 	}
 }